@@ -0,0 +1,93 @@
+//! Benchmark of many processes that contend for the same lock, with and
+//! without [NamedLock::set_acquire_jitter].
+//!
+//! Every waiter is a child process. Besides the wall time, the number of
+//! context switches of the children shows how often they were woken up.
+//!
+//! Run with `cargo bench --bench contention`.
+//!
+//! This is a plain program (`harness = false`) instead of a `criterion`
+//! benchmark, because it runs itself again as the children and reports the
+//! context switches next to the time.
+
+use std::env;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use named_lock::NamedLock;
+
+const PROCESSES: u32 = 8;
+const ITERATIONS: u32 = 200;
+const HOLD: Duration = Duration::from_micros(100);
+
+// Runs in every child process.
+fn contend(name: &str, jitter: Duration) {
+    let mut lock = NamedLock::create(name).unwrap();
+    lock.set_acquire_jitter(jitter);
+
+    for _ in 0..ITERATIONS {
+        let _guard = lock.lock().unwrap();
+        let start = Instant::now();
+        while start.elapsed() < HOLD {}
+    }
+}
+
+#[cfg(unix)]
+fn context_switches() -> i64 {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage) };
+    (usage.ru_nvcsw + usage.ru_nivcsw) as i64
+}
+
+#[cfg(not(unix))]
+fn context_switches() -> i64 {
+    0
+}
+
+fn bench(name: &str, jitter: Duration) {
+    let exe = env::current_exe().unwrap();
+    let switches = context_switches();
+    let start = Instant::now();
+
+    let children = (0..PROCESSES)
+        .map(|_| {
+            Command::new(&exe)
+                .env("NAMED_LOCK_BENCH_LOCK", name)
+                .env("NAMED_LOCK_BENCH_JITTER", jitter.as_micros().to_string())
+                .spawn()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    for mut child in children {
+        assert!(child.wait().unwrap().success());
+    }
+
+    let elapsed = start.elapsed();
+    let switches = context_switches() - switches;
+    let label = format!("lock with jitter {:?}", jitter);
+
+    println!(
+        "{:<45} {:>10.1} ms {:>10} context switches",
+        label,
+        elapsed.as_secs_f64() * 1000.0,
+        switches
+    );
+}
+
+fn main() {
+    if let Ok(name) = env::var("NAMED_LOCK_BENCH_LOCK") {
+        let jitter = env::var("NAMED_LOCK_BENCH_JITTER").unwrap();
+        contend(&name, Duration::from_micros(jitter.parse().unwrap()));
+        return;
+    }
+
+    let name = format!("named-lock-bench-{}", std::process::id());
+
+    for jitter in [0, 50, 200, 1000] {
+        bench(&name, Duration::from_micros(jitter));
+    }
+
+    #[cfg(unix)]
+    std::fs::remove_file(NamedLock::resolved_name(&name).unwrap()).unwrap();
+}
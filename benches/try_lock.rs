@@ -0,0 +1,92 @@
+//! Benchmark of uncontended `try_lock`.
+//!
+//! Run with `cargo bench --bench try_lock`.
+//!
+//! `acquire(NonBlocking)` is the path that `try_lock` took before it got
+//! its own fast path, so the two cases compare before and after.
+
+use std::hint::black_box;
+use std::thread;
+use std::time::Instant;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use named_lock::{AcquireMode, NamedLock};
+
+const THREADS: u32 = 4;
+
+fn uncontended(c: &mut Criterion) {
+    let name = format!("named-lock-bench-{}", std::process::id());
+    let lock = NamedLock::create(&name).unwrap();
+    let mut group = c.benchmark_group("uncontended");
+
+    group.bench_function("acquire(NonBlocking) (before)", |b| {
+        b.iter(|| {
+            drop(black_box(lock.acquire(AcquireMode::NonBlocking).unwrap()))
+        })
+    });
+
+    group.bench_function("try_lock (after)", |b| {
+        b.iter(|| drop(black_box(lock.try_lock().unwrap())))
+    });
+
+    group.finish();
+
+    let _guard = lock.try_lock().unwrap();
+
+    c.bench_function("try_lock (held by this process)", |b| {
+        b.iter(|| assert!(black_box(lock.try_lock()).is_err()))
+    });
+
+    drop(_guard);
+    remove(&name);
+}
+
+// Every thread uses its own lock, so they only share the state of the crate
+// itself. Each thread runs every iteration, so the time is per `try_lock` of
+// any thread.
+fn threads(c: &mut Criterion) {
+    let name = format!("named-lock-bench-threads-{}", std::process::id());
+    let names =
+        (0..THREADS).map(|i| format!("{}-{}", name, i)).collect::<Vec<_>>();
+    let locks = names
+        .iter()
+        .map(|name| NamedLock::create(name).unwrap())
+        .collect::<Vec<_>>();
+
+    let label = format!("try_lock (one lock per thread, {} threads)", THREADS);
+
+    c.bench_function(&label, |b| {
+        b.iter_custom(|iters| {
+            let start = Instant::now();
+
+            thread::scope(|scope| {
+                for lock in &locks {
+                    scope.spawn(move || {
+                        for _ in 0..iters {
+                            drop(black_box(lock.try_lock().unwrap()));
+                        }
+                    });
+                }
+            });
+
+            start.elapsed() / THREADS
+        })
+    });
+
+    drop(locks);
+
+    for name in &names {
+        remove(name);
+    }
+}
+
+#[cfg(unix)]
+fn remove(name: &str) {
+    std::fs::remove_file(NamedLock::resolved_name(name).unwrap()).unwrap();
+}
+
+#[cfg(not(unix))]
+fn remove(_name: &str) {}
+
+criterion_group!(benches, uncontended, threads);
+criterion_main!(benches);
@@ -0,0 +1,116 @@
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixListener};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::*;
+use crate::validate_name;
+
+// How often `lock` tries to bind, since binding never blocks.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Keeps the names of this crate apart from other abstract sockets.
+const PREFIX: &str = "named-lock/";
+
+/// Named lock that is backed by an abstract Unix domain socket.
+///
+/// Abstract sockets live in a namespace of the kernel, so this lock never
+/// touches the filesystem. Locking binds a socket to an address that is
+/// derived from the name. Only one socket can be bound to an address, so
+/// exactly one holder succeeds while everyone else gets `EADDRINUSE`. The
+/// lock is released when the socket is closed, which the kernel also does
+/// when the holder exits or crashes, so nothing is ever left behind.
+///
+/// ```rust
+/// use named_lock::AbstractSocketLock;
+/// use named_lock::Result;
+///
+/// fn main() -> Result<()> {
+///     let lock = AbstractSocketLock::create("foobar")?;
+///     let _guard = lock.lock()?;
+///
+///     // Do something...
+///
+///     Ok(())
+/// }
+/// ```
+///
+/// # Notes
+///
+/// * Abstract sockets are a Linux feature.
+/// * Abstract sockets belong to a network namespace, so processes in
+///   different network namespaces (e.g. containers) do not exclude each
+///   other.
+/// * These locks do not coordinate with [NamedLock]s of the same name.
+/// * The binding is exclusive within the process as well, so there is no
+///   difference between threads and processes.
+/// * Anyone can bind to any abstract address, so a name can be squatted by
+///   an unrelated program.
+///
+/// [NamedLock]: crate::NamedLock
+#[derive(Debug, Clone)]
+pub struct AbstractSocketLock {
+    addr: SocketAddr,
+}
+
+impl AbstractSocketLock {
+    /// Create a named lock that is backed by an abstract socket.
+    ///
+    /// Nothing is opened until the lock is locked.
+    ///
+    /// # Notes
+    ///
+    /// * `name` follows the same rules as in [NamedLock::create].
+    /// * The address is `named-lock/<name>` and it is limited to 107 bytes,
+    ///   so `name` can be up to 96 bytes long. Longer names return
+    ///   `Error::CreateFailed`.
+    ///
+    /// [NamedLock::create]: crate::NamedLock::create
+    pub fn create(name: &str) -> Result<AbstractSocketLock> {
+        validate_name(name)?;
+
+        let addr =
+            SocketAddr::from_abstract_name(format!("{}{}", PREFIX, name))
+                .map_err(Error::CreateFailed)?;
+
+        Ok(AbstractSocketLock {
+            addr,
+        })
+    }
+
+    /// Try to lock the abstract socket lock.
+    ///
+    /// If it is already locked, `Error::WouldBlock` will be returned.
+    pub fn try_lock(&self) -> Result<AbstractSocketLockGuard> {
+        match UnixListener::bind_addr(&self.addr) {
+            Ok(socket) => Ok(AbstractSocketLockGuard {
+                _socket: socket,
+            }),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                Err(Error::WouldBlock)
+            }
+            Err(e) => Err(Error::LockFailed(Some(e))),
+        }
+    }
+
+    /// Lock the abstract socket lock.
+    ///
+    /// The kernel does not notify when an address becomes free, so this
+    /// polls every 10ms.
+    pub fn lock(&self) -> Result<AbstractSocketLockGuard> {
+        loop {
+            match self.try_lock() {
+                Err(Error::WouldBlock) => thread::sleep(POLL_INTERVAL),
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Scoped guard that unlocks an [AbstractSocketLock].
+#[derive(Debug)]
+pub struct AbstractSocketLockGuard {
+    // Closing the socket releases the address.
+    _socket: UnixListener,
+}
@@ -0,0 +1,120 @@
+use std::ops::Deref;
+
+use crate::error::*;
+use crate::{encode_component, NamedLock, NamedLockGuard};
+
+/// Named lock that belongs to an application.
+///
+/// Locks of different applications never collide, even if they use the
+/// same lock name, while every instance of the same application shares
+/// them. The application is identified by a stable ID, usually in
+/// reverse-DNS form like `com.example.MyApp`.
+///
+/// `AppLock` dereferences to [NamedLock], so it is locked like one.
+///
+/// ```rust
+/// use named_lock::AppLock;
+/// use named_lock::Result;
+///
+/// fn main() -> Result<()> {
+///     let lock = AppLock::new("com.example.MyApp", "database")?;
+///     let _guard = lock.lock()?;
+///
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct AppLock {
+    lock: NamedLock,
+}
+
+impl AppLock {
+    /// Create/open the lock `lock_name` of the application `app_id`.
+    ///
+    /// The application ID becomes a namespace of
+    /// [NamedLock::create_namespaced]. Reserved characters (and `@` or `%`)
+    /// in it are percent-encoded, so it is always a single component and
+    /// can be an arbitrary string.
+    ///
+    /// # Notes
+    ///
+    /// * `lock_name` follows the same rules as in [NamedLock::create].
+    /// * `app_id` must not be empty, `.` or `..`, otherwise an error is
+    ///   returned.
+    pub fn new(app_id: &str, lock_name: &str) -> Result<AppLock> {
+        Ok(AppLock {
+            lock: NamedLock::create_namespaced(
+                &encode_component(app_id),
+                lock_name,
+            )?,
+        })
+    }
+
+    /// Returns the underlying [NamedLock].
+    pub fn into_inner(self) -> NamedLock {
+        self.lock
+    }
+}
+
+impl Deref for AppLock {
+    type Target = NamedLock;
+
+    fn deref(&self) -> &NamedLock {
+        &self.lock
+    }
+}
+
+/// Proof that this is the only running instance of an application.
+///
+/// The instance lock of the application is held for as long as the value
+/// lives, so keep it until the application exits. It owns everything it
+/// needs, so it can be stored anywhere, e.g. in a `static` or in the state
+/// of the application.
+///
+/// ```rust,no_run
+/// use named_lock::{Error, SingleInstance};
+///
+/// let _instance = match SingleInstance::acquire("com.example.MyApp") {
+///     Ok(instance) => instance,
+///     Err(Error::WouldBlock) => {
+///         eprintln!("Another instance is running");
+///         std::process::exit(1);
+///     }
+///     Err(e) => panic!("{}", e),
+/// };
+/// ```
+#[derive(Debug)]
+pub struct SingleInstance {
+    guard: NamedLockGuard,
+}
+
+impl SingleInstance {
+    /// Become the single instance of the application `app_id`, without
+    /// blocking.
+    ///
+    /// `Error::WouldBlock` is returned if another instance holds the lock.
+    ///
+    /// # Notes
+    ///
+    /// * The lock is scoped to the current user like with
+    ///   [NamedLock::create_per_user], so instances of different users run
+    ///   side by side.
+    /// * Reserved characters (and `@` or `%`) in `app_id` are
+    ///   percent-encoded like in [AppLock::new], so it can be an arbitrary
+    ///   string. It must not be empty.
+    pub fn acquire(app_id: &str) -> Result<SingleInstance> {
+        let lock = NamedLock::create_per_user(&encode_component(app_id))?;
+
+        Ok(SingleInstance {
+            guard: lock.try_lock()?,
+        })
+    }
+
+    /// Stop being the single instance, so another one can start.
+    ///
+    /// This is the same as dropping the value, except that an error of
+    /// unlocking is returned.
+    pub fn release(self) -> Result<()> {
+        self.guard.unlock()
+    }
+}
@@ -0,0 +1,53 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// How often `lock_cancellable` checks the token while it waits.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Token that cancels [NamedLock::lock_cancellable].
+///
+/// Clones share the same state, so one clone can cancel every acquisition
+/// that waits with any of the others. Unlike [LockInterrupter], no signal
+/// is involved: the waiting threads check the token between attempts, so
+/// they give up within a few milliseconds.
+///
+/// ```rust
+/// use named_lock::{CancelToken, NamedLock};
+/// use named_lock::Result;
+///
+/// fn main() -> Result<()> {
+///     let token = CancelToken::new();
+///     let lock = NamedLock::create("foobar")?;
+///     let _guard = lock.lock_cancellable(&token)?;
+///
+///     Ok(())
+/// }
+/// ```
+///
+/// [NamedLock::lock_cancellable]: crate::NamedLock::lock_cancellable
+/// [LockInterrupter]: crate::LockInterrupter
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    /// Create a token that is not cancelled.
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Cancel all acquisitions that wait with this token or its clones.
+    ///
+    /// The token stays cancelled, so acquisitions that start afterwards
+    /// fail immediately.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns `true` if [CancelToken::cancel] was called.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,141 @@
+use std::thread;
+#[cfg(unix)]
+use std::time::SystemTime;
+use std::time::{Duration, Instant};
+
+// Source of time for timeouts, polling and leases.
+//
+// The crate always uses the real clock, except that tests can replace it on
+// their own thread with a `MockClock`, so that timeouts and leases expire
+// without waiting for them.
+pub(crate) trait Clock {
+    fn now(&self) -> Instant;
+    // Only leases use the system clock, and they only exist on UNIX.
+    #[cfg(unix)]
+    fn system_now(&self) -> SystemTime;
+    fn sleep(&self, duration: Duration);
+}
+
+struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    #[cfg(unix)]
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+#[cfg(not(test))]
+fn with_clock<F, R>(f: F) -> R
+where
+    F: FnOnce(&dyn Clock) -> R,
+{
+    f(&RealClock)
+}
+
+#[cfg(test)]
+fn with_clock<F, R>(f: F) -> R
+where
+    F: FnOnce(&dyn Clock) -> R,
+{
+    match mock::current() {
+        Some(clock) => f(&*clock),
+        None => f(&RealClock),
+    }
+}
+
+pub(crate) fn now() -> Instant {
+    with_clock(|clock| clock.now())
+}
+
+#[cfg(unix)]
+pub(crate) fn system_now() -> SystemTime {
+    with_clock(|clock| clock.system_now())
+}
+
+pub(crate) fn sleep(duration: Duration) {
+    with_clock(|clock| clock.sleep(duration))
+}
+
+#[cfg(test)]
+pub(crate) use self::mock::MockClock;
+
+#[cfg(test)]
+mod mock {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    #[cfg(unix)]
+    use std::time::SystemTime;
+    use std::time::{Duration, Instant};
+
+    use super::Clock;
+
+    thread_local! {
+        static CURRENT: RefCell<Option<Rc<MockClock>>> = const {
+            RefCell::new(None)
+        };
+    }
+
+    pub(super) fn current() -> Option<Rc<MockClock>> {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+
+    // Clock that only moves when it sleeps or is advanced. It replaces the
+    // real clock on the current thread until it is uninstalled.
+    #[derive(Debug)]
+    pub(crate) struct MockClock {
+        instant: Instant,
+        #[cfg(unix)]
+        system: SystemTime,
+        elapsed: Cell<Duration>,
+    }
+
+    impl MockClock {
+        pub(crate) fn install() -> Rc<MockClock> {
+            let clock = Rc::new(MockClock {
+                instant: Instant::now(),
+                #[cfg(unix)]
+                system: SystemTime::now(),
+                elapsed: Cell::new(Duration::ZERO),
+            });
+
+            CURRENT.with(|current| *current.borrow_mut() = Some(clock.clone()));
+            clock
+        }
+
+        pub(crate) fn uninstall(&self) {
+            CURRENT.with(|current| *current.borrow_mut() = None);
+        }
+
+        pub(crate) fn advance(&self, duration: Duration) {
+            self.elapsed.set(self.elapsed.get() + duration);
+        }
+
+        pub(crate) fn elapsed(&self) -> Duration {
+            self.elapsed.get()
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.instant + self.elapsed.get()
+        }
+
+        #[cfg(unix)]
+        fn system_now(&self) -> SystemTime {
+            self.system + self.elapsed.get()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.advance(duration);
+        }
+    }
+}
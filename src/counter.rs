@@ -0,0 +1,178 @@
+#[cfg(unix)]
+use std::fs::{File, OpenOptions};
+use std::io;
+#[cfg(unix)]
+use std::io::{Read, Seek, SeekFrom};
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(windows)]
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use crate::error::*;
+#[cfg(windows)]
+use crate::windows::SharedValues;
+use crate::NamedLock;
+#[cfg(windows)]
+use crate::NAME_SEPARATOR;
+
+/// Cross-process counter that is identified by name.
+///
+/// Every operation is a read-modify-write while holding a [NamedLock], so
+/// concurrent increments from different processes are never lost.
+///
+/// # UNIX
+///
+/// The value is stored as decimal text in the lock file, so it is kept
+/// when no process has the counter open.
+///
+/// # Windows
+///
+/// The value is stored in named shared memory next to the mutex, so it
+/// starts from `0` again once no process has the counter open.
+///
+/// ```rust
+/// use named_lock::NamedCounter;
+/// use named_lock::Result;
+///
+/// fn main() -> Result<()> {
+///     let counter = NamedCounter::create("foobar-epoch")?;
+///     let epoch = counter.increment()?;
+///     assert!(counter.get()? >= epoch);
+///
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct NamedCounter {
+    lock: NamedLock,
+    value: Arc<Value>,
+}
+
+// The lock of the name may already be open with a write-only descriptor,
+// so the value is accessed through its own.
+#[cfg(unix)]
+type Value = File;
+
+#[cfg(windows)]
+type Value = SharedValues;
+
+impl NamedCounter {
+    /// Create/open a named counter.
+    ///
+    /// The lock is the same as the one of [NamedLock::create], so don't use
+    /// `name` for a plain lock that writes its own content to the lock
+    /// file.
+    pub fn create(name: &str) -> Result<NamedCounter> {
+        NamedCounter::new(NamedLock::create(name)?)
+    }
+
+    /// Create/open a named counter on specified path.
+    ///
+    /// See [NamedLock::with_path] for details.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn with_path<P>(path: P) -> Result<NamedCounter>
+    where
+        P: AsRef<Path>,
+    {
+        NamedCounter::new(NamedLock::with_path(path)?)
+    }
+
+    fn new(lock: NamedLock) -> Result<NamedCounter> {
+        let value = open_value(&lock)?;
+
+        Ok(NamedCounter {
+            lock,
+            value: Arc::new(value),
+        })
+    }
+
+    /// Returns the current value. A new counter starts from `0`.
+    pub fn get(&self) -> Result<u64> {
+        let _guard = self.lock.lock()?;
+        read_value(&self.value)
+    }
+
+    /// Increment the counter and return the new value.
+    pub fn increment(&self) -> Result<u64> {
+        let _guard = self.lock.lock()?;
+
+        let value =
+            read_value(&self.value)?.checked_add(1).ok_or_else(|| {
+                Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "counter overflow",
+                ))
+            })?;
+
+        write_value(&self.value, value)?;
+
+        Ok(value)
+    }
+}
+
+#[cfg(unix)]
+fn open_value(lock: &NamedLock) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(lock.name())
+        .map_err(Error::CreateFailed)
+}
+
+#[cfg(unix)]
+fn read_value(mut file: &File) -> Result<u64> {
+    let mut content = String::new();
+
+    file.seek(SeekFrom::Start(0)).map_err(Error::Io)?;
+    file.read_to_string(&mut content).map_err(Error::Io)?;
+
+    let content = content.trim();
+
+    if content.is_empty() {
+        return Ok(0);
+    }
+
+    content.parse().map_err(|_| {
+        Error::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid counter value",
+        ))
+    })
+}
+
+// Written before truncating, like the metadata, so that the file is never
+// left empty.
+#[cfg(unix)]
+fn write_value(file: &File, value: u64) -> Result<()> {
+    let content = value.to_string();
+
+    file.write_all_at(content.as_bytes(), 0).map_err(Error::Io)?;
+    file.set_len(content.len() as u64).map_err(Error::Io)?;
+
+    Ok(())
+}
+
+// The mutex and the shared memory can not have the same name. Plain names
+// can not contain the separator, so this never collides with another lock.
+#[cfg(windows)]
+fn open_value(lock: &NamedLock) -> Result<SharedValues> {
+    SharedValues::create(
+        &format!("{}{}counter", lock.name(), NAME_SEPARATOR),
+        1,
+    )
+}
+
+#[cfg(windows)]
+fn read_value(value: &SharedValues) -> Result<u64> {
+    Ok(value.get(0).load(Ordering::SeqCst))
+}
+
+#[cfg(windows)]
+fn write_value(value: &SharedValues, new: u64) -> Result<()> {
+    value.get(0).store(new, Ordering::SeqCst);
+    Ok(())
+}
@@ -4,7 +4,50 @@ use thiserror::Error;
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 /// Error type of this crate.
+///
+/// New variants may be added in minor releases, so a `match` on this type
+/// needs a wildcard arm:
+///
+/// ```rust
+/// use named_lock::{Error, NamedLock};
+///
+/// match NamedLock::create("foobar").and_then(|lock| lock.try_lock()) {
+///     Ok(_guard) => {}
+///     Err(Error::WouldBlock) => {}
+///     Err(e) => eprintln!("{}", e),
+/// }
+/// ```
+///
+/// Without it the `match` does not compile:
+///
+/// ```rust,compile_fail
+/// use named_lock::Error;
+///
+/// fn describe(e: Error) -> &'static str {
+///     match e {
+///         Error::InvalidCharacter => "invalid character",
+///         Error::EmptyName => "empty name",
+///         Error::CreateFailed(_) => "create failed",
+///         Error::LockFailed(_) => "lock failed",
+///         Error::UnlockFailed(_) => "unlock failed",
+///         Error::WouldBlock => "would block",
+///         Error::WrongThread => "wrong thread",
+///         Error::NotAFile => "not a file",
+///         Error::Io(_) => "I/O error",
+///         Error::Interrupted => "interrupted",
+///         Error::Abandoned => "abandoned",
+///         Error::ResourceExhausted => "resource exhausted",
+///         Error::WouldDeadlock => "would deadlock",
+///         Error::Cancelled => "cancelled",
+///         Error::AlreadyExists => "already exists",
+///         Error::TooManyOpenFiles => "too many open files",
+///         Error::NameTooLong => "name too long",
+///         Error::IncompatibleVersion(_) => "incompatible version",
+///     }
+/// }
+/// ```
 #[derive(Debug, Error)]
+#[non_exhaustive]
 pub enum Error {
     #[error("Invalid character in name")]
     InvalidCharacter,
@@ -15,12 +58,168 @@ pub enum Error {
     #[error("Failed to create named lock: {0}")]
     CreateFailed(#[source] std::io::Error),
 
+    /// Locking failed with the OS error, if there is one.
     #[error("Failed to lock named lock")]
-    LockFailed,
+    LockFailed(#[source] Option<std::io::Error>),
 
+    /// Unlocking failed with the OS error, if there is one.
     #[error("Failed to unlock named lock")]
-    UnlockFailed,
+    UnlockFailed(#[source] Option<std::io::Error>),
 
     #[error("Named lock would block")]
     WouldBlock,
+
+    #[error("Named lock must be unlocked by the thread that locked it")]
+    WrongThread,
+
+    #[error("Lock path exists but it is not a file")]
+    NotAFile,
+
+    #[error("Failed to access lock file: {0}")]
+    Io(#[source] std::io::Error),
+
+    #[error("Waiting for named lock was interrupted")]
+    Interrupted,
+
+    #[error("Named lock was abandoned by its previous owner")]
+    Abandoned,
+
+    /// The system ran out of locks (`ENOLCK`). This is usually temporary,
+    /// so the operation can be retried after a while.
+    #[error("No locks available")]
+    ResourceExhausted,
+
+    #[error("Named lock is already held by the current thread")]
+    WouldDeadlock,
+
+    #[error("Waiting for named lock was cancelled")]
+    Cancelled,
+
+    #[error("Lock file already exists")]
+    AlreadyExists,
+
+    /// The process ran out of file descriptors (`EMFILE` or `ENFILE`), or
+    /// the limit of [NamedLock::set_max_open_files] is reached and every
+    /// open lock is held.
+    ///
+    /// [NamedLock::set_max_open_files]: crate::NamedLock::set_max_open_files
+    #[error("Too many open lock files")]
+    TooManyOpenFiles,
+
+    /// The name is longer than the platform allows, see
+    /// [NamedLock::max_name_len].
+    ///
+    /// [NamedLock::max_name_len]: crate::NamedLock::max_name_len
+    #[error("Name is too long")]
+    NameTooLong,
+
+    /// The lock file records a protocol version that the caller of
+    /// [NamedLock::lock_with_version] is not compatible with.
+    ///
+    /// [NamedLock::lock_with_version]: crate::NamedLock::lock_with_version
+    #[error("Named lock uses incompatible protocol version {0}")]
+    IncompatibleVersion(u32),
+}
+
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        use std::io::ErrorKind;
+
+        // Keep this exhaustive, so new variants must be mapped explicitly.
+        match e {
+            Error::CreateFailed(e) | Error::Io(e) => e,
+            Error::LockFailed(Some(e)) | Error::UnlockFailed(Some(e)) => e,
+            Error::WouldBlock => {
+                std::io::Error::new(ErrorKind::WouldBlock, e.to_string())
+            }
+            Error::Interrupted => {
+                std::io::Error::new(ErrorKind::Interrupted, e.to_string())
+            }
+            Error::AlreadyExists => {
+                std::io::Error::new(ErrorKind::AlreadyExists, e.to_string())
+            }
+            Error::InvalidCharacter
+            | Error::EmptyName
+            | Error::NotAFile
+            | Error::NameTooLong => {
+                std::io::Error::new(ErrorKind::InvalidInput, e.to_string())
+            }
+            Error::LockFailed(_)
+            | Error::UnlockFailed(_)
+            | Error::WrongThread
+            | Error::Abandoned
+            | Error::ResourceExhausted
+            | Error::WouldDeadlock
+            | Error::Cancelled
+            | Error::TooManyOpenFiles
+            | Error::IncompatibleVersion(_) => {
+                std::io::Error::other(e.to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn into_io_error() {
+        let kind = |e: Error| io::Error::from(e).kind();
+
+        assert_eq!(kind(Error::WouldBlock), io::ErrorKind::WouldBlock);
+        assert_eq!(kind(Error::EmptyName), io::ErrorKind::InvalidInput);
+        assert_eq!(kind(Error::InvalidCharacter), io::ErrorKind::InvalidInput);
+        assert_eq!(kind(Error::NotAFile), io::ErrorKind::InvalidInput);
+        assert_eq!(kind(Error::NameTooLong), io::ErrorKind::InvalidInput);
+        assert_eq!(kind(Error::Interrupted), io::ErrorKind::Interrupted);
+        assert_eq!(kind(Error::AlreadyExists), io::ErrorKind::AlreadyExists);
+        assert_eq!(kind(Error::LockFailed(None)), io::ErrorKind::Other);
+        assert_eq!(kind(Error::UnlockFailed(None)), io::ErrorKind::Other);
+        assert_eq!(kind(Error::Abandoned), io::ErrorKind::Other);
+        assert_eq!(kind(Error::ResourceExhausted), io::ErrorKind::Other);
+        assert_eq!(kind(Error::WouldDeadlock), io::ErrorKind::Other);
+        assert_eq!(kind(Error::Cancelled), io::ErrorKind::Other);
+        assert_eq!(kind(Error::TooManyOpenFiles), io::ErrorKind::Other);
+        assert_eq!(kind(Error::IncompatibleVersion(1)), io::ErrorKind::Other);
+
+        let create_failed = Error::CreateFailed(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "denied",
+        ));
+        assert_eq!(kind(create_failed), io::ErrorKind::PermissionDenied);
+
+        let io = Error::Io(io::Error::new(io::ErrorKind::InvalidData, "bad"));
+        assert_eq!(kind(io), io::ErrorKind::InvalidData);
+
+        let e = io::Error::from(Error::LockFailed(None));
+        assert_eq!(e.to_string(), Error::LockFailed(None).to_string());
+    }
+
+    #[test]
+    fn source() {
+        use std::error::Error as _;
+
+        let os_error = || Some(io::Error::from_raw_os_error(9));
+        let source =
+            |e: &Error| e.source()?.downcast_ref::<io::Error>()?.raw_os_error();
+
+        let lock_failed = Error::LockFailed(os_error());
+        let unlock_failed = Error::UnlockFailed(os_error());
+        assert_eq!(source(&lock_failed), Some(9));
+        assert_eq!(source(&unlock_failed), Some(9));
+        assert!(Error::LockFailed(None).source().is_none());
+        assert!(Error::UnlockFailed(None).source().is_none());
+
+        // The source is not repeated in the message.
+        assert_eq!(lock_failed.to_string(), "Failed to lock named lock");
+        assert_eq!(unlock_failed.to_string(), "Failed to unlock named lock");
+
+        // The OS error is kept when converting to `io::Error`.
+        let raw = |e: Error| io::Error::from(e).raw_os_error();
+        assert_eq!(raw(lock_failed), Some(9));
+        assert_eq!(raw(unlock_failed), Some(9));
+        assert_eq!(raw(Error::LockFailed(None)), None);
+    }
 }
@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+
+use once_cell::sync::Lazy;
+
+use crate::error::*;
+use crate::sync::Mutex;
+use crate::unix::RawNamedLock;
+
+// Maximum number of lock files that are open at the same time, or 0 for no
+// limit.
+static MAX_OPEN: AtomicUsize = AtomicUsize::new(0);
+
+// Number of lock files that are open. Every open lock file holds one.
+static OPEN: AtomicUsize = AtomicUsize::new(0);
+
+// Incremented whenever a lock is used, so that the least recently used
+// lock has the smallest stamp.
+static TICK: AtomicU64 = AtomicU64::new(0);
+
+// Locks whose files can be closed to stay below the limit. This is kept
+// apart from `OPENED_RAW_LOCKS`, because files are also reserved while it
+// is held.
+static LOCKS: Lazy<Mutex<Vec<Weak<Mutex<RawNamedLock>>>>> =
+    Lazy::new(|| Mutex::new(Vec::new()));
+
+pub(crate) fn set_max_open(max: Option<usize>) {
+    MAX_OPEN.store(max.unwrap_or(0), Ordering::SeqCst);
+}
+
+#[cfg(test)]
+pub(crate) fn open_count() -> usize {
+    OPEN.load(Ordering::SeqCst)
+}
+
+pub(crate) fn tick() -> u64 {
+    TICK.fetch_add(1, Ordering::Relaxed)
+}
+
+pub(crate) fn register(lock: &Arc<Mutex<RawNamedLock>>) {
+    let mut locks = LOCKS.lock();
+
+    // Forget the dropped locks before growing, so that the list does not
+    // grow without bound.
+    if locks.len() == locks.capacity() {
+        locks.retain(|lock| lock.strong_count() > 0);
+    }
+
+    locks.push(Arc::downgrade(lock));
+}
+
+// Lock file that counts towards the limit while it is open.
+#[derive(Debug)]
+pub(crate) struct OpenFile {
+    file: File,
+}
+
+impl OpenFile {
+    // Opens a lock file with `open`, after making room for it.
+    pub(crate) fn open<F>(open: F) -> Result<OpenFile>
+    where
+        F: FnOnce() -> Result<File>,
+    {
+        reserve()?;
+
+        match open() {
+            Ok(file) => Ok(OpenFile {
+                file,
+            }),
+            Err(e) => {
+                release();
+                Err(e)
+            }
+        }
+    }
+
+    pub(crate) fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Drop for OpenFile {
+    fn drop(&mut self) {
+        release();
+    }
+}
+
+// Reserves a lock file before it is opened. If the limit is reached, the
+// file of the least recently used idle lock is closed first.
+fn reserve() -> Result<()> {
+    loop {
+        let max = MAX_OPEN.load(Ordering::SeqCst);
+        let open = OPEN.load(Ordering::SeqCst);
+
+        if max == 0 || open < max {
+            if OPEN
+                .compare_exchange(
+                    open,
+                    open + 1,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                )
+                .is_ok()
+            {
+                return Ok(());
+            }
+
+            continue;
+        }
+
+        if !evict() {
+            return Err(Error::TooManyOpenFiles);
+        }
+    }
+}
+
+fn release() {
+    OPEN.fetch_sub(1, Ordering::SeqCst);
+}
+
+// Closes the file of the least recently used lock that is open and not
+// held. Returns `false` if there is none.
+fn evict() -> bool {
+    let locks = LOCKS.lock();
+
+    loop {
+        // Locks are only tried, so that this never waits for a lock that
+        // is held or waits for the registry itself.
+        let oldest = locks
+            .iter()
+            .filter_map(|lock| lock.upgrade())
+            .filter_map(|lock| {
+                let last_used = lock.try_lock()?.last_used()?;
+                Some((last_used, lock))
+            })
+            .min_by_key(|(last_used, _)| *last_used);
+
+        let lock = match oldest {
+            Some((_, lock)) => lock,
+            None => return false,
+        };
+
+        // The lock may have been acquired since it was seen idle.
+        let closed = lock.try_lock().is_some_and(|mut raw| raw.close());
+
+        if closed {
+            return true;
+        }
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+
+use lock_api::ArcMutexGuard;
+use once_cell::sync::Lazy;
+
+use crate::error::*;
+use crate::registry_key;
+use crate::sync::{Mutex, RawMutex};
+
+// Record locks belong to the process (or the open file description), so a
+// file must be opened only once per process. Closing another descriptor of
+// the same file would release all the classic record locks of the process.
+static OPENED_GROUPS: Lazy<Mutex<HashMap<PathBuf, Weak<GroupInner>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[cfg(target_os = "linux")]
+const F_SETLK: libc::c_int = libc::F_OFD_SETLK;
+#[cfg(target_os = "linux")]
+const F_SETLKW: libc::c_int = libc::F_OFD_SETLKW;
+#[cfg(not(target_os = "linux"))]
+const F_SETLK: libc::c_int = libc::F_SETLK;
+#[cfg(not(target_os = "linux"))]
+const F_SETLKW: libc::c_int = libc::F_SETLKW;
+
+/// Group of named locks that share a single lock file.
+///
+/// Every sublock of the group locks its own byte range of the file with
+/// [`fcntl`] record locks, so thousands of logical locks need only one file
+/// and one file descriptor. Sublocks with different indexes never
+/// interfere, while sublocks with the same index exclude each other, both
+/// within a process and between processes.
+///
+/// ```rust
+/// use named_lock::NamedLockGroup;
+/// use named_lock::Result;
+///
+/// fn main() -> Result<()> {
+///     let path = std::env::temp_dir().join("foobar-group.lock");
+///     let group = NamedLockGroup::open(&path)?;
+///
+///     let _guard1 = group.sublock(1).lock()?;
+///     let _guard2 = group.sublock(2).try_lock()?;
+///
+///     Ok(())
+/// }
+/// ```
+///
+/// # Byte ranges
+///
+/// The sublock with index `i` locks the single byte at offset `i`, so
+/// indexes range from 0 to `u32::MAX`, or to `i32::MAX` on platforms where
+/// file offsets have 32 bits. Record locks can extend past the end
+/// of the file, so the file is never written and stays empty.
+///
+/// # Notes
+///
+/// * On Linux, open file description locks (`F_OFD_SETLK`) are used, which
+///   are only released when the group is closed. Elsewhere, classic record
+///   locks (`F_SETLK`) are used, which are released when the process
+///   closes *any* descriptor of the file. Do not open the file in any other
+///   way while sublocks are held.
+/// * Groups and [NamedLock]s do not coordinate: the same file must not be
+///   used for both.
+/// * Record locks are only advisory and may not work on network file
+///   systems.
+///
+/// [`fcntl`]: https://man7.org/linux/man-pages/man2/fcntl.2.html
+/// [NamedLock]: crate::NamedLock
+#[derive(Debug, Clone)]
+pub struct NamedLockGroup {
+    inner: Arc<GroupInner>,
+}
+
+#[derive(Debug)]
+struct GroupInner {
+    file: File,
+    // Record locks do not exclude within the same process (or descriptor),
+    // so every index also has a mutex.
+    sublocks: Mutex<HashMap<u32, Weak<Mutex<()>>>>,
+}
+
+impl NamedLockGroup {
+    /// Create/open a lock group on the specified path.
+    ///
+    /// # Notes
+    ///
+    /// * Parent directories must exist.
+    /// * Opening the same file again in this process returns the same
+    ///   group.
+    pub fn open<P>(path: P) -> Result<NamedLockGroup>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let key = registry_key(path);
+        let mut opened_groups = OPENED_GROUPS.lock();
+
+        if let Some(inner) = opened_groups.get(&key).and_then(Weak::upgrade) {
+            return Ok(NamedLockGroup {
+                inner,
+            });
+        }
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| match e.raw_os_error() {
+                Some(libc::EISDIR) => Error::NotAFile,
+                _ => Error::CreateFailed(e),
+            })?;
+
+        let inner = Arc::new(GroupInner {
+            file,
+            sublocks: Mutex::new(HashMap::new()),
+        });
+        opened_groups.insert(key, Arc::downgrade(&inner));
+
+        Ok(NamedLockGroup {
+            inner,
+        })
+    }
+
+    /// Returns the sublock with `index`.
+    pub fn sublock(&self, index: u32) -> SubLock {
+        let mut sublocks = self.inner.sublocks.lock();
+
+        let local = match sublocks.get(&index).and_then(Weak::upgrade) {
+            Some(local) => local,
+            None => {
+                let local = Arc::new(Mutex::new(()));
+                sublocks.insert(index, Arc::downgrade(&local));
+                local
+            }
+        };
+
+        SubLock {
+            group: self.inner.clone(),
+            index,
+            local,
+        }
+    }
+}
+
+/// One lock of a [NamedLockGroup].
+#[derive(Debug, Clone)]
+pub struct SubLock {
+    group: Arc<GroupInner>,
+    index: u32,
+    local: Arc<Mutex<()>>,
+}
+
+impl SubLock {
+    /// Returns the index of the sublock in its group.
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// Try to lock the sublock.
+    ///
+    /// If it is already locked, `Error::WouldBlock` will be returned.
+    pub fn try_lock(&self) -> Result<SubLockGuard> {
+        let local = self.local.try_lock_arc().ok_or(Error::WouldBlock)?;
+        self.set_lock(F_SETLK, libc::F_WRLCK)?;
+        Ok(self.guard(local))
+    }
+
+    /// Lock the sublock.
+    pub fn lock(&self) -> Result<SubLockGuard> {
+        let local = self.local.lock_arc();
+        self.set_lock(F_SETLKW, libc::F_WRLCK)?;
+        Ok(self.guard(local))
+    }
+
+    fn guard(&self, local: ArcMutexGuard<RawMutex, ()>) -> SubLockGuard {
+        SubLockGuard {
+            sublock: self.clone(),
+            _local: local,
+        }
+    }
+
+    // `off_t` has only 32 bits on some platforms, so the conversion of the
+    // index can fail there.
+    #[allow(clippy::unnecessary_fallible_conversions)]
+    fn set_lock(&self, cmd: libc::c_int, lock_type: libc::c_int) -> Result<()> {
+        let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+        lock.l_type = lock_type as _;
+        lock.l_whence = libc::SEEK_SET as _;
+        lock.l_start = libc::off_t::try_from(self.index)
+            .map_err(|_| Error::LockFailed(None))?;
+        lock.l_len = 1;
+
+        loop {
+            let rc =
+                unsafe { libc::fcntl(self.group.file.as_raw_fd(), cmd, &lock) };
+
+            if rc == 0 {
+                return Ok(());
+            }
+
+            let err = io::Error::last_os_error();
+
+            match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EAGAIN) | Some(libc::EACCES) => {
+                    return Err(Error::WouldBlock)
+                }
+                Some(libc::ENOLCK) => return Err(Error::ResourceExhausted),
+                _ if lock_type == libc::F_UNLCK => {
+                    return Err(Error::UnlockFailed(Some(err)))
+                }
+                _ => return Err(Error::LockFailed(Some(err))),
+            }
+        }
+    }
+}
+
+/// Scoped guard that unlocks a [SubLock].
+pub struct SubLockGuard {
+    sublock: SubLock,
+    // Released after the record lock, since fields are dropped in order
+    // after `drop`.
+    _local: ArcMutexGuard<RawMutex, ()>,
+}
+
+impl Drop for SubLockGuard {
+    fn drop(&mut self) {
+        let _ = self.sublock.set_lock(F_SETLK, libc::F_UNLCK);
+    }
+}
+
+impl fmt::Debug for SubLockGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SubLockGuard").field("sublock", &self.sublock).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn group_path() -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("{}.lock", Uuid::new_v4().as_hyphenated()))
+    }
+
+    #[test]
+    fn sublocks() -> Result<()> {
+        let path = group_path();
+        let group = NamedLockGroup::open(&path)?;
+
+        let _guard0 = group.sublock(0).try_lock()?;
+        let _guard1 = group.sublock(1).try_lock()?;
+        let _guard_max = group.sublock(u32::MAX).lock()?;
+
+        assert!(matches!(group.sublock(0).try_lock(), Err(Error::WouldBlock)));
+
+        // The same file is the same group.
+        let same = NamedLockGroup::open(&path)?;
+        assert!(Arc::ptr_eq(&group.inner, &same.inner));
+        assert!(matches!(same.sublock(1).try_lock(), Err(Error::WouldBlock)));
+
+        drop(_guard1);
+        let _guard1 = same.sublock(1).try_lock()?;
+
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        Ok(())
+    }
+
+    // Open file description locks exclude each other even within the same
+    // process, so a second descriptor behaves like another process.
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn byte_ranges() -> Result<()> {
+        let path = group_path();
+        let group = NamedLockGroup::open(&path)?;
+        let other = GroupInner {
+            file: OpenOptions::new().write(true).open(&path).unwrap(),
+            sublocks: Mutex::new(HashMap::new()),
+        };
+        let other = SubLock {
+            group: Arc::new(other),
+            index: 5,
+            local: Arc::new(Mutex::new(())),
+        };
+
+        let guard = group.sublock(5).try_lock()?;
+        assert!(matches!(other.try_lock(), Err(Error::WouldBlock)));
+        let _guard4 = group.sublock(4).try_lock()?;
+        let _guard6 = group.sublock(6).try_lock()?;
+
+        drop(guard);
+        let _guard = other.try_lock()?;
+        assert!(matches!(group.sublock(5).try_lock(), Err(Error::WouldBlock)));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::sync::Mutex;
+
+// How often `interrupt` signals the waiting threads again, in case a
+// signal arrived right before a thread started waiting.
+pub(crate) const RETRY_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Handle that interrupts [NamedLock::lock_interruptible] from another
+/// thread.
+///
+/// [LockInterrupter::interrupt] marks the handle as interrupted and sends a
+/// signal to every thread that waits on it, so the blocked `flock` call
+/// returns with `EINTR` and the waiting thread gives up with
+/// [Error::Interrupted].
+///
+/// ```rust,no_run
+/// use named_lock::{LockInterrupter, NamedLock};
+/// use std::thread;
+///
+/// // A handler without `SA_RESTART` must be installed for `SIGUSR1`.
+/// let interrupter = LockInterrupter::new(libc::SIGUSR1);
+/// let waiter = {
+///     let interrupter = interrupter.clone();
+///     thread::spawn(move || {
+///         NamedLock::create("foobar")?.lock_interruptible(&interrupter)
+///     })
+/// };
+///
+/// interrupter.interrupt();
+/// let _ = waiter.join();
+/// ```
+///
+/// # Notes
+///
+/// * The signal must have a handler that is installed without
+///   `SA_RESTART`. With the default disposition the signal terminates the
+///   process, and with `SA_RESTART` the kernel restarts `flock`, so
+///   [LockInterrupter::interrupt] never returns while the lock is held
+///   elsewhere. The handler itself can do nothing.
+/// * The signal is also delivered to the thread while it waits on the
+///   in-process layer of the lock or before it calls `flock`. Pick a signal
+///   that the rest of the program does not use for something else.
+/// * Once interrupted, every call of [NamedLock::lock_interruptible] with
+///   this handle fails until [LockInterrupter::reset] is called.
+///
+/// [NamedLock::lock_interruptible]: crate::NamedLock::lock_interruptible
+/// [Error::Interrupted]: crate::Error::Interrupted
+#[derive(Debug, Clone)]
+pub struct LockInterrupter {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    signal: c_int,
+    interrupted: AtomicBool,
+    waiters: Mutex<Vec<Waiter>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Waiter(libc::pthread_t);
+
+// `pthread_t` is a pointer on some platforms. It is only used as an
+// identifier for `pthread_kill`.
+unsafe impl Send for Waiter {}
+
+impl LockInterrupter {
+    /// Create a handle that interrupts waiting threads with `signal`.
+    pub fn new(signal: c_int) -> LockInterrupter {
+        LockInterrupter {
+            inner: Arc::new(Inner {
+                signal,
+                interrupted: AtomicBool::new(false),
+                waiters: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Interrupt all threads that wait on this handle.
+    ///
+    /// This returns after every waiting thread has given up.
+    pub fn interrupt(&self) {
+        self.inner.interrupted.store(true, Ordering::SeqCst);
+
+        loop {
+            {
+                let waiters = self.inner.waiters.lock();
+
+                if waiters.is_empty() {
+                    return;
+                }
+
+                // Waiters remove themselves while holding the mutex, so
+                // the threads are still alive.
+                for waiter in waiters.iter() {
+                    unsafe { libc::pthread_kill(waiter.0, self.inner.signal) };
+                }
+            }
+
+            thread::sleep(RETRY_INTERVAL);
+        }
+    }
+
+    /// Returns `true` if [LockInterrupter::interrupt] was called.
+    pub fn is_interrupted(&self) -> bool {
+        self.inner.interrupted.load(Ordering::SeqCst)
+    }
+
+    /// Clear the interruption, so the handle can be used again.
+    pub fn reset(&self) {
+        self.inner.interrupted.store(false, Ordering::SeqCst);
+    }
+
+    pub(crate) fn flag(&self) -> &AtomicBool {
+        &self.inner.interrupted
+    }
+
+    // Registers the current thread as a waiter until the returned value is
+    // dropped.
+    pub(crate) fn register(&self) -> Registration<'_> {
+        let waiter = Waiter(unsafe { libc::pthread_self() });
+        self.inner.waiters.lock().push(waiter);
+
+        Registration {
+            interrupter: self,
+            waiter,
+        }
+    }
+}
+
+pub(crate) struct Registration<'a> {
+    interrupter: &'a LockInterrupter,
+    waiter: Waiter,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let mut waiters = self.interrupter.inner.waiters.lock();
+
+        if let Some(pos) = waiters.iter().position(|w| *w == self.waiter) {
+            waiters.swap_remove(pos);
+        }
+    }
+}
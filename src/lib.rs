@@ -18,25 +18,125 @@
 //!     Ok(())
 //! }
 //! ```
+//!
+//! ## Features
+//!
+//! * `parking_lot` (enabled by default): use [`parking_lot`] for the mutex
+//!   that arbitrates the lock between the threads of a process. Without it,
+//!   a mutex built on `std::sync` is used and the crate does not depend on
+//!   `parking_lot` at all, e.g. to avoid conflicts with its feature flags.
+//!
+//! * `reset-registry`: enable [NamedLock::reset_registry], which makes the
+//!   next `create` of a lock open it again. It is meant for tests and
+//!   recovery, and breaks the exclusion between the threads of a process
+//!   if it is misused.
+//!
+//! * `metrics`: record acquisitions with the [`metrics`] facade. Every
+//!   acquisition increments the counter `named_lock.acquired` and records
+//!   how long it waited in the histogram `named_lock.wait_seconds`, and
+//!   every acquisition that fails because the lock is held increments
+//!   `named_lock.would_block`. All of them have a `name` label with the
+//!   resolved name of the lock. Methods that retry or poll, like
+//!   [NamedLock::try_lock_spin], record one outcome per call. Without the
+//!   feature nothing is recorded or measured.
+//!
+//! [`parking_lot`]: https://docs.rs/parking_lot
+//! [`metrics`]: https://docs.rs/metrics
+//!
+//! ## Panics
+//!
+//! Creating, locking and unlocking named locks never panics. Every failure,
+//! including invalid names, lock files that can not be created, timeouts
+//! that overflow and locks that are already held, is returned as an
+//! [Error]. The exceptions are:
+//!
+//! * [RawNamedMutex] panics on errors, since the methods of
+//!   `lock_api::RawMutex` can not return them.
+//! * Like everywhere in Rust, running out of memory aborts the process
+//!   rather than panicking.
 
 use std::collections::HashMap;
+#[cfg(unix)]
+use std::ffi::{OsStr, OsString};
 use std::fmt;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
 #[cfg(unix)]
 use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
 use std::sync::{Arc, Weak};
+use std::task::Poll;
+use std::thread::{self, ThreadId};
+use std::time::Duration;
 
-use once_cell::sync::Lazy;
-use parking_lot::lock_api::ArcMutexGuard;
-use parking_lot::{Mutex, RawMutex};
+use lock_api::ArcMutexGuard;
+use once_cell::sync::{Lazy, OnceCell};
 
+#[cfg(target_os = "linux")]
+mod abstract_socket;
+mod app;
+mod cancel;
+mod clock;
+mod counter;
 mod error;
 #[cfg(unix)]
+mod fd_limit;
+#[cfg(unix)]
+mod group;
+#[cfg(unix)]
+mod interrupt;
+mod lock_name;
+#[cfg(unix)]
+mod metadata;
+mod metric;
+mod options;
+mod plan;
+mod raw_mutex;
+#[cfg(unix)]
+mod renewal;
+mod retry;
+#[cfg(unix)]
+mod signal;
+mod sync;
+#[cfg(unix)]
 mod unix;
 #[cfg(windows)]
 mod windows;
 
+#[cfg(target_os = "linux")]
+#[cfg_attr(docsrs, doc(cfg(target_os = "linux")))]
+pub use crate::abstract_socket::{AbstractSocketLock, AbstractSocketLockGuard};
+pub use crate::app::{AppLock, SingleInstance};
+pub use crate::cancel::CancelToken;
+pub use crate::counter::NamedCounter;
 pub use crate::error::*;
 #[cfg(unix)]
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+pub use crate::group::{NamedLockGroup, SubLock, SubLockGuard};
+#[cfg(unix)]
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+pub use crate::interrupt::LockInterrupter;
+pub use crate::lock_name::LockName;
+#[cfg(unix)]
+use crate::metadata::Metadata;
+#[cfg(unix)]
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+pub use crate::options::ExistingFilePolicy;
+pub use crate::options::{LockKind, NamedLockOptions};
+pub use crate::plan::LockPlan;
+#[cfg(unix)]
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+pub use crate::plan::TempDirSource;
+pub use crate::raw_mutex::{NamedMutex, RawNamedMutex, StaticName};
+#[cfg(unix)]
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+pub use crate::renewal::RenewingGuard;
+pub use crate::retry::RetryPolicy;
+#[cfg(unix)]
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+pub use crate::signal::SignalCleanup;
+use crate::sync::{Mutex, RawMutex};
+#[cfg(unix)]
 use crate::unix::RawNamedLock;
 #[cfg(windows)]
 use crate::windows::RawNamedLock;
@@ -56,14 +156,76 @@ type NameType = String;
 // the same named lock and the same process and Windows will allow you to
 // re-lock it. To avoid this, we ensure that one `HANDLE` exists in each
 // process for each name.
-static OPENED_RAW_LOCKS: Lazy<
-    Mutex<HashMap<NameType, Weak<Mutex<RawNamedLock>>>>,
-> = Lazy::new(|| Mutex::new(HashMap::new()));
+static OPENED_RAW_LOCKS: Lazy<Mutex<HashMap<NameType, OpenedLock>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
-/// Cross-process lock that is identified by name.
+// Entry of `OPENED_RAW_LOCKS`.
+#[derive(Debug)]
+struct OpenedLock {
+    raw: Weak<Mutex<RawNamedLock>>,
+    state: Arc<LockState>,
+}
+
+// State of an opened lock that is shared by all of its handles and guards,
+// and that is read without waiting for the in-process mutex.
 #[derive(Debug)]
+struct LockState {
+    // Changed by `relocate`, so that every handle sees the new path.
+    name: Mutex<NameType>,
+    backend: Backend,
+    // Thread that holds the in-process mutex, only written while it is
+    // held. The mutex is not reentrant, so this is used to detect a thread
+    // that would wait for itself.
+    holder: Mutex<Option<ThreadId>>,
+}
+
+impl LockState {
+    fn new(name: NameType, backend: Backend) -> Arc<LockState> {
+        Arc::new(LockState {
+            name: Mutex::new(name),
+            backend,
+            holder: Mutex::new(None),
+        })
+    }
+}
+
+// Errors of unlocking when a guard is dropped, keyed by the address of the
+// raw lock. The weak reference keeps the address from being reused by
+// another lock while the error is stored.
+static UNLOCK_ERRORS: Lazy<Mutex<HashMap<usize, UnlockError>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+type UnlockError = (Weak<Mutex<RawNamedLock>>, Error);
+
+/// Characters that are not allowed in lock names.
+///
+/// [NamedLock::create] returns `Error::InvalidCharacter` if the name
+/// contains any of them. It also rejects `@`, which separates the parts of
+/// the names of [NamedLock::create_versioned] and
+/// [NamedLock::create_per_user].
+pub const RESERVED_CHARS: &[char] = &['\0', '/', '\\'];
+
+// Separates the parts of derived names, so that they never collide with
+// plain names.
+const NAME_SEPARATOR: char = '@';
+
+// Name of the environment variable that takes precedence over `TMPDIR`.
+#[cfg(unix)]
+static LOCK_DIR_VAR: Lazy<Mutex<Option<OsString>>> = Lazy::new(|| {
+    Mutex::new(option_env!("NAMED_LOCK_DIR_VAR").map(OsString::from))
+});
+
+/// Cross-process lock that is identified by name.
+///
+/// Cloning a `NamedLock` is cheap and gives another handle to the same lock.
+#[derive(Debug, Clone)]
 pub struct NamedLock {
     raw: Arc<Mutex<RawNamedLock>>,
+    state: Arc<LockState>,
+    abandoned: AbandonedPolicy,
+    grace: Duration,
+    jitter: Duration,
+    max_polls: Option<u32>,
 }
 
 impl NamedLock {
@@ -73,10 +235,14 @@ impl NamedLock {
     ///
     /// This will create/open a file and use [`flock`] on it. The path of
     /// the lock file will be `$TMPDIR/<name>.lock`, or `/tmp/<name>.lock`
-    /// if `TMPDIR` environment variable is not set.
+    /// if `TMPDIR` environment variable is not set or empty. See
+    /// [NamedLock::default_lock_dir] for the exact rules.
     ///
     /// If you want to specify the exact path, then use [NamedLock::with_path].
     ///
+    /// A child created with `fork` shares the open lock file, and with it the
+    /// lock, with its parent. See [NamedLock::reinit_after_fork].
+    ///
     /// # Windows
     ///
     /// This will create/open a [global] mutex with [`CreateMutexW`].
@@ -84,249 +250,6134 @@ impl NamedLock {
     /// # Notes
     ///
     /// * `name` must not be empty, otherwise an error is returned.
-    /// * `name` must not contain `\0`, `/`, nor `\`, otherwise an error is returned.
+    /// * `name` must not contain `\0`, `/`, `\`, nor `@`, otherwise an error is returned.
     ///
     /// [`flock`]: https://linux.die.net/man/2/flock
     /// [global]: https://docs.microsoft.com/en-us/windows/win32/termserv/kernel-object-namespaces
     /// [`CreateMutexW`]: https://docs.microsoft.com/en-us/windows/win32/api/synchapi/nf-synchapi-createmutexw
     pub fn create(name: &str) -> Result<NamedLock> {
-        if name.is_empty() {
-            return Err(Error::EmptyName);
-        }
-
-        // On UNIX we want to restrict the user on `/tmp` directory,
-        // so we block the `/` character.
-        //
-        // On Windows `\` character is invalid.
-        //
-        // Both platforms expect null-terminated strings,
-        // so we block null-bytes.
-        if name.chars().any(|c| matches!(c, '\0' | '/' | '\\')) {
-            return Err(Error::InvalidCharacter);
-        }
-
-        // If `TMPDIR` environment variable is set then use it as the
-        // temporary directory, otherwise use `/tmp`.
-        #[cfg(unix)]
-        let name = std::env::var_os("TMPDIR")
-            .map(PathBuf::from)
-            .unwrap_or_else(|| PathBuf::from("/tmp"))
-            .join(format!("{}.lock", name));
+        NamedLockOptions::new().create(name)
+    }
 
-        #[cfg(windows)]
-        let name = format!("Global\\{}", name);
+    /// Returns the path of the lock file that [NamedLock::create] uses
+    /// for `name`.
+    ///
+    /// Nothing is created. This can be used to find out if two names
+    /// refer to the same lock. Keep in mind that the result depends on
+    /// the environment, see [NamedLock::default_lock_dir].
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn resolved_name(name: &str) -> Result<PathBuf> {
+        lock_path_in(&NamedLock::default_lock_dir(), name)
+    }
 
-        NamedLock::_create(name)
+    /// Returns the mutex name that [NamedLock::create] uses for `name`.
+    ///
+    /// Nothing is created. This can be used to find out if two names
+    /// refer to the same lock.
+    #[cfg(windows)]
+    #[cfg_attr(docsrs, doc(cfg(windows)))]
+    pub fn resolved_name(name: &str) -> Result<String> {
+        validate_name(name)?;
+        Ok(format!("Global\\{}", name))
     }
 
-    /// Create/open a named lock on specified path.
+    /// Returns the longest `name` that [NamedLock::create] accepts.
+    ///
+    /// Longer names fail with `Error::NameTooLong`. This can be used to
+    /// reject or shorten names before creating anything.
+    ///
+    /// # UNIX
+    ///
+    /// The limit is the longest file name of [NamedLock::default_lock_dir]
+    /// (`NAME_MAX`, as reported by `pathconf`) minus the `.lock` suffix, or
+    /// less if the path of the lock file would exceed `PATH_MAX`. It is
+    /// counted in bytes, so a non-ASCII character takes several.
+    ///
+    /// # Windows
+    ///
+    /// The limit is the longest name of a kernel object minus the `Global\`
+    /// prefix, counted in UTF-16 code units.
     ///
     /// # Notes
     ///
-    /// * This function does not append `.lock` on the path.
-    /// * Parent directories must exist.
-    #[cfg(unix)]
-    #[cfg_attr(docsrs, doc(cfg(unix)))]
-    pub fn with_path<P>(path: P) -> Result<NamedLock>
-    where
-        P: AsRef<Path>,
-    {
-        NamedLock::_create(path.as_ref().to_owned())
+    /// * The limit depends on the lock directory, which may be changed by
+    ///   the environment in the meantime. File systems may also reject
+    ///   some names below the limit.
+    pub fn max_name_len() -> usize {
+        #[cfg(unix)]
+        return unix::max_file_name_len(&NamedLock::default_lock_dir())
+            .saturating_sub(".lock".len());
+        #[cfg(windows)]
+        return windows::MAX_NAME_LEN - "Global\\".len();
     }
 
-    fn _create(name: NameType) -> Result<NamedLock> {
-        let mut opened_locks = OPENED_RAW_LOCKS.lock();
+    /// Describe what [NamedLock::create] would do for `name`, without
+    /// opening or creating anything.
+    ///
+    /// The name is validated and resolved the same way as in `create`.
+    pub fn plan(name: &str) -> Result<LockPlan> {
+        #[cfg(unix)]
+        let dir_source = lock_dir_source();
+        #[cfg(unix)]
+        let name = lock_path_in(dir_source.path(), name)?;
+        #[cfg(windows)]
+        let name = NamedLock::resolved_name(name)?;
 
-        let lock = match opened_locks.get(&name).and_then(|x| x.upgrade()) {
-            Some(lock) => lock,
-            None => {
-                let lock = Arc::new(Mutex::new(RawNamedLock::create(&name)?));
-                opened_locks.insert(name, Arc::downgrade(&lock));
-                lock
-            }
-        };
+        let opened = OPENED_RAW_LOCKS
+            .lock()
+            .get(&registry_key(&name))
+            .is_some_and(|lock| lock.raw.strong_count() > 0);
 
-        Ok(NamedLock {
-            raw: lock,
+        Ok(LockPlan {
+            #[cfg(unix)]
+            exists: name.exists(),
+            #[cfg(unix)]
+            dir_source,
+            name,
+            opened,
         })
     }
 
-    /// Try to lock named lock.
+    /// Returns `true` if the named lock of `name` exists, without opening
+    /// or creating it.
     ///
-    /// If it is already locked, `Error::WouldBlock` will be returned.
-    pub fn try_lock(&self) -> Result<NamedLockGuard> {
-        let guard = self.raw.try_lock_arc().ok_or(Error::WouldBlock)?;
-
-        guard.try_lock()?;
-
-        Ok(NamedLockGuard {
-            raw: guard,
-        })
+    /// On UNIX this checks if the lock file exists. On Windows it checks if
+    /// the mutex exists by opening it and closing it again immediately.
+    ///
+    /// # Notes
+    ///
+    /// * `name` follows the same rules as in [NamedLock::create].
+    /// * The answer can be outdated as soon as it is returned, since other
+    ///   processes can create or remove the lock at any time. Do not use it
+    ///   to decide if the lock is free.
+    /// * On Windows the mutex is destroyed when its last handle is closed,
+    ///   so it only exists while some process has it open.
+    pub fn exists(name: &str) -> Result<bool> {
+        RawNamedLock::exists(&NamedLock::resolved_name(name)?)
     }
 
-    /// Lock named lock.
-    pub fn lock(&self) -> Result<NamedLockGuard> {
-        let guard = self.raw.lock_arc();
+    /// Create/open a named lock with the complete mutex name `full_name`.
+    ///
+    /// Unlike [NamedLock::create], the `Global\` prefix is not added and
+    /// `\` is allowed, so the caller controls the kernel object namespace,
+    /// e.g. with `Local\myapp` or `Session\2\myapp`.
+    ///
+    /// The caller is responsible for choosing a valid name and namespace:
+    /// only empty names and `\0` characters are rejected, and everything
+    /// else is passed to `CreateMutexW` as is. A name without a prefix is
+    /// created in the namespace of the current session.
+    #[cfg(windows)]
+    #[cfg_attr(docsrs, doc(cfg(windows)))]
+    pub fn create_raw_windows(full_name: &str) -> Result<NamedLock> {
+        if full_name.is_empty() {
+            return Err(Error::EmptyName);
+        }
 
-        guard.lock()?;
+        if full_name.contains('\0') {
+            return Err(Error::InvalidCharacter);
+        }
 
-        Ok(NamedLockGuard {
-            raw: guard,
-        })
+        NamedLock::_create(full_name.to_owned(), &NamedLockOptions::new())
     }
-}
-
-/// Scoped guard that unlocks NamedLock.
-pub struct NamedLockGuard {
-    raw: ArcMutexGuard<RawMutex, RawNamedLock>,
-}
 
-impl Drop for NamedLockGuard {
-    fn drop(&mut self) {
-        let _ = self.raw.unlock();
-    }
-}
+    /// Create/open a named lock that is shared between Windows sessions.
+    ///
+    /// Services run in session 0, while interactive programs run in other
+    /// sessions. Named locks are always created in the `Global\` namespace,
+    /// so both see the same mutex, but the default DACL of a mutex that is
+    /// created by a service does not let interactive programs open it.
+    ///
+    /// This creates the mutex with a DACL that allows everyone to lock and
+    /// unlock it, which is what the service-plus-tray-app pattern needs.
+    /// If the mutex already exists, it is opened with the DACL of its
+    /// creator, so both sides should use this function.
+    ///
+    /// # Errors
+    ///
+    /// Creating a mutex in the `Global\` namespace from outside session 0
+    /// requires the `SeCreateGlobalPrivilege` privilege. Without it, and if
+    /// the mutex does not exist yet, `Error::CreateFailed` is returned with
+    /// an error of kind [`std::io::ErrorKind::PermissionDenied`].
+    ///
+    /// # Notes
+    ///
+    /// * `name` follows the same rules as in [NamedLock::create].
+    #[cfg(windows)]
+    #[cfg_attr(docsrs, doc(cfg(windows)))]
+    pub fn create_cross_session(name: &str) -> Result<NamedLock> {
+        let mut options = NamedLockOptions::new();
+        options.cross_session = true;
 
-impl fmt::Debug for NamedLockGuard {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("NamedLockGuard").field("raw", &*self.raw).finish()
+        NamedLock::_create(NamedLock::resolved_name(name)?, &options)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use static_assertions::assert_impl_all;
-    use std::env;
-    use std::fmt::Debug;
-    use std::process::{Child, Command};
-    use std::thread::sleep;
-    use std::time::Duration;
-    use uuid::Uuid;
+    /// Create/open a named lock that is scoped to a generation.
+    ///
+    /// Locks created with different `generation` tokens never collide, even
+    /// if `name` is the same. This is useful when a new version of a program
+    /// must not coordinate with old instances that speak an incompatible
+    /// protocol.
+    ///
+    /// The effective name is `<name>@<generation>`, where any reserved
+    /// character (and `@` or `%`) in `generation` is percent-encoded, so
+    /// `generation` can be an arbitrary non-empty string. Plain names can
+    /// not contain `@`, so a versioned lock never collides with one.
+    ///
+    /// # Notes
+    ///
+    /// * `name` follows the same rules as in [NamedLock::create].
+    /// * `generation` must not be empty, otherwise an error is returned.
+    pub fn create_versioned(name: &str, generation: &str) -> Result<NamedLock> {
+        validate_name(name)?;
 
-    fn call_proc_num(num: u32, uuid: &str) -> Child {
-        let exe = env::current_exe().expect("no exe");
-        let mut cmd = Command::new(exe);
+        if generation.is_empty() {
+            return Err(Error::EmptyName);
+        }
 
-        cmd.env("TEST_CROSS_PROCESS_LOCK_PROC_NUM", num.to_string())
-            .env("TEST_CROSS_PROCESS_LOCK_UUID", uuid)
-            .arg("tests::cross_process_lock")
-            .spawn()
-            .unwrap()
+        NamedLock::create_derived(&format!(
+            "{}{}{}",
+            name,
+            NAME_SEPARATOR,
+            encode_component(generation)
+        ))
     }
 
-    #[test]
-    fn cross_process_lock() -> Result<()> {
-        let proc_num = env::var("TEST_CROSS_PROCESS_LOCK_PROC_NUM")
-            .ok()
-            .and_then(|v| v.parse().ok())
-            .unwrap_or(0);
-        let uuid = env::var("TEST_CROSS_PROCESS_LOCK_UUID")
-            .unwrap_or_else(|_| Uuid::new_v4().as_hyphenated().to_string());
+    /// Create/open a named lock that is scoped to the current user.
+    ///
+    /// Instances of a program that run as the same user share the lock,
+    /// while instances of different users never collide, even if `name` is
+    /// the same. This gives single-instance-per-user semantics.
+    ///
+    /// The effective name is `<name>@user-<id>`, where `<id>` identifies the
+    /// user that the process runs as:
+    ///
+    /// * On UNIX it is the real user ID. IDs are only meaningful within a
+    ///   machine (or the directory service that assigns them), and a
+    ///   container may map them to different users of the host.
+    /// * On Windows it is the SID of the process token in string form, e.g.
+    ///   `S-1-5-21-...`, which is unique across machines and domains.
+    ///
+    /// # Notes
+    ///
+    /// * `name` follows the same rules as in [NamedLock::create].
+    /// * The lock is still created in the same directory (or namespace) as
+    ///   with [NamedLock::create], so on UNIX the users need to be able to
+    ///   create files there.
+    pub fn create_per_user(name: &str) -> Result<NamedLock> {
+        validate_name(name)?;
+        NamedLock::create_derived(&per_user_name(name, &current_user_id()?))
+    }
 
-        match proc_num {
-            0 => {
-                let mut handle1 = call_proc_num(1, &uuid);
-                sleep(Duration::from_millis(100));
+    // Like `create`, for a name that is built from validated parts with
+    // `NAME_SEPARATOR`, which `create` rejects.
+    fn create_derived(name: &str) -> Result<NamedLock> {
+        #[cfg(unix)]
+        let name = NamedLock::default_lock_dir().join(format!("{}.lock", name));
+        #[cfg(windows)]
+        let name = format!("Global\\{}", name);
 
-                let mut handle2 = call_proc_num(2, &uuid);
-                sleep(Duration::from_millis(200));
+        NamedLock::_create(name, &NamedLockOptions::new())
+    }
 
-                let lock = NamedLock::create(&uuid)?;
-                assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
-                lock.lock().expect("failed to lock");
+    /// Create/open a named lock inside a namespace.
+    ///
+    /// `namespace` consists of one or more components separated by `/`,
+    /// e.g. `myapp` or `myapp/cache`. Every component follows the same rules
+    /// as `name` and it must not be `.` or `..`.
+    ///
+    /// # UNIX
+    ///
+    /// Each component is a subdirectory of [NamedLock::default_lock_dir],
+    /// so `create_namespaced("myapp/cache", "foo")` uses the lock file
+    /// `$TMPDIR/myapp/cache/foo.lock`. Missing directories are created.
+    ///
+    /// # Windows
+    ///
+    /// The namespace becomes part of the mutex name, separated with `/`,
+    /// e.g. `Global\myapp/cache/foo`. Since `/` is rejected in names, this
+    /// can not collide with a lock from [NamedLock::create].
+    ///
+    /// # Notes
+    ///
+    /// * `name` follows the same rules as in [NamedLock::create].
+    /// * Empty components return `Error::EmptyName`.
+    /// * `.` or `..` components return `Error::InvalidCharacter`.
+    pub fn create_namespaced(namespace: &str, name: &str) -> Result<NamedLock> {
+        validate_name(name)?;
 
-                assert!(handle2.wait().unwrap().success());
-                assert!(handle1.wait().unwrap().success());
-            }
-            1 => {
-                let lock =
-                    NamedLock::create(&uuid).expect("failed to create lock");
+        for component in namespace.split('/') {
+            validate_name(component)?;
 
-                let _guard = lock.lock().expect("failed to lock");
-                assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
-                sleep(Duration::from_millis(200));
+            if component == "." || component == ".." {
+                return Err(Error::InvalidCharacter);
             }
-            2 => {
-                let lock =
-                    NamedLock::create(&uuid).expect("failed to create lock");
+        }
 
-                assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
-                let _guard = lock.lock().expect("failed to lock");
-                sleep(Duration::from_millis(300));
-            }
-            _ => unreachable!(),
+        #[cfg(unix)]
+        {
+            let dir = NamedLock::default_lock_dir().join(namespace);
+            std::fs::create_dir_all(&dir).map_err(Error::CreateFailed)?;
+            NamedLock::with_path_named(dir, name)
         }
 
-        Ok(())
+        #[cfg(windows)]
+        NamedLock::_create(
+            format!("Global\\{}/{}", namespace, name),
+            &NamedLockOptions::new(),
+        )
     }
 
-    #[test]
-    fn edge_cases() -> Result<()> {
-        let uuid = Uuid::new_v4().as_hyphenated().to_string();
-        let lock1 = NamedLock::create(&uuid)?;
-        let lock2 = NamedLock::create(&uuid)?;
+    /// Create/open a named lock for operations on the file at `target`.
+    ///
+    /// The name of the lock is derived from the canonical absolute path of
+    /// `target`, so different forms of the same path (relative, with `.`
+    /// components or symlinks) get the same lock, and different files get
+    /// different locks. The lock itself is created like in
+    /// [NamedLock::create]; the file at `target` is never locked or
+    /// modified.
+    ///
+    /// The name is `path-<hash>`, where `<hash>` is a 128-bit FNV-1a hash
+    /// of the path. It is stable across processes and versions of this
+    /// crate.
+    ///
+    /// # Notes
+    ///
+    /// * `target` does not have to exist, but its parent directory does,
+    ///   otherwise `Error::Io` is returned.
+    pub fn for_path<P>(target: P) -> Result<NamedLock>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        NamedLock::create(&path_lock_name(target.as_ref())?)
+    }
 
-        {
-            let _guard1 = lock1.try_lock()?;
-            assert!(matches!(lock1.try_lock(), Err(Error::WouldBlock)));
-            assert!(matches!(lock2.try_lock(), Err(Error::WouldBlock)));
-        }
+    /// Claim `name` for leader election.
+    ///
+    /// This creates/opens the named lock and locks it without blocking.
+    /// Exactly one holder can succeed at a time, everyone else gets
+    /// `Error::WouldBlock` immediately. The lock is also exclusive between
+    /// handles and threads of the same process.
+    ///
+    /// The returned guard is the leadership: it lasts until the guard is
+    /// dropped. If the leader process exits or crashes, the OS releases
+    /// the lock, so a new leader can be claimed without any cleanup.
+    ///
+    /// # Notes
+    ///
+    /// * `name` follows the same rules as in [NamedLock::create].
+    pub fn claim(name: &str) -> Result<NamedLockGuard> {
+        NamedLock::create(name)?.try_lock()
+    }
 
-        {
-            let _guard2 = lock2.try_lock()?;
-            assert!(matches!(lock1.try_lock(), Err(Error::WouldBlock)));
-            assert!(matches!(lock2.try_lock(), Err(Error::WouldBlock)));
+    /// Lock the first name of `names` that is free, without blocking.
+    ///
+    /// Each name is tried in order with [NamedLock::try_lock], and the
+    /// first name that succeeds is returned with its guard. This is useful
+    /// for sharding work across a pool of slots, such as `worker-0` to
+    /// `worker-3`.
+    ///
+    /// # Errors
+    ///
+    /// `Error::WouldBlock` is returned if all names are held, or if `names`
+    /// is empty. Any other error stops the search and is returned as is.
+    pub fn try_lock_any(names: &[&str]) -> Result<(String, NamedLockGuard)> {
+        for name in names {
+            match NamedLock::claim(name) {
+                Ok(guard) => return Ok((name.to_string(), guard)),
+                Err(Error::WouldBlock) => {}
+                Err(e) => return Err(e),
+            }
         }
 
-        Ok(())
+        Err(Error::WouldBlock)
     }
 
-    #[test]
-    fn owned_guard() -> Result<()> {
-        let uuid = Uuid::new_v4().as_hyphenated().to_string();
-        let lock1 = NamedLock::create(&uuid)?;
-        let lock2 = NamedLock::create(&uuid)?;
+    /// Lock every name of `names` without blocking, or none of them.
+    ///
+    /// The names are sorted by their [LockName] and duplicates are removed,
+    /// then each one is tried with [NamedLock::try_lock]. If one of them is
+    /// held, the locks that were already taken are released again and
+    /// `Error::WouldBlock` is returned, so partial sets are never kept. On
+    /// success the guards are returned in the sorted order of the names.
+    ///
+    /// # Notes
+    ///
+    /// * When several processes compete for overlapping sets and retry
+    ///   right away, they can keep taking each other's locks without anyone
+    ///   getting the whole set. Sleep for a randomized, growing delay
+    ///   between retries so that one of them gets ahead.
+    /// * `names` follow the same rules as in [NamedLock::create]. Any error
+    ///   other than `Error::WouldBlock` also releases the taken locks and is
+    ///   returned as is.
+    /// * An empty `names` succeeds with no guards.
+    pub fn try_lock_all(names: &[&str]) -> Result<Vec<NamedLockGuard>> {
+        sorted_names(names)?
+            .into_iter()
+            .map(|name| NamedLock::from_lock_name(name)?.try_lock())
+            .collect()
+    }
 
-        // Lock
-        let guard1 = lock1.try_lock()?;
-        assert!(matches!(lock2.try_lock(), Err(Error::WouldBlock)));
+    /// Lock every name of `names`, blocking until all of them are held.
+    ///
+    /// The names are sorted by their [LockName] and duplicates are removed,
+    /// then each one is locked with [NamedLock::lock] in that order. Every
+    /// process that uses this function takes overlapping sets in the same
+    /// order, so they never deadlock on each other. The guards are returned
+    /// in the sorted order of the names.
+    ///
+    /// # Notes
+    ///
+    /// * All names are validated before anything is locked. Any error
+    ///   releases the locks that were already taken and is returned as is.
+    /// * Locks that are taken one by one elsewhere must follow the same
+    ///   order, otherwise they can still deadlock with this function.
+    /// * An empty `names` succeeds with no guards.
+    pub fn lock_all(names: &[&str]) -> Result<Vec<NamedLockGuard>> {
+        sorted_names(names)?
+            .into_iter()
+            .map(|name| NamedLock::from_lock_name(name)?.lock())
+            .collect()
+    }
 
-        // Dropping `lock1` should not affect the state of the lock.
-        // If `guard1` is not dropped the lock must stay locked.
-        drop(lock1);
+    fn from_lock_name(name: LockName) -> Result<NamedLock> {
+        NamedLock::_create(name.name, &NamedLockOptions::new())
+    }
+
+    /// Create/open a named lock for every name of `names`.
+    ///
+    /// This is the same as calling [NamedLock::create] for each name, but
+    /// all names are validated before anything is created, so an invalid
+    /// name fails with no lock created at all. The locks are then created
+    /// under a single hold of the registry of opened locks, and returned in
+    /// the order of `names`.
+    ///
+    /// # Notes
+    ///
+    /// * If creating one of the locks fails, e.g. with
+    ///   `Error::CreateFailed`, the error is returned and the handles that
+    ///   were already created are dropped. Their lock files are left in
+    ///   place, just like with [NamedLock::create].
+    pub fn create_many(names: &[&str]) -> Result<Vec<NamedLock>> {
+        let names = names
+            .iter()
+            .map(|name| NamedLock::resolved_name(name))
+            .collect::<Result<Vec<_>>>()?;
+
+        let options = NamedLockOptions::new();
+        let mut opened_locks = OPENED_RAW_LOCKS.lock();
+
+        names
+            .into_iter()
+            .map(|name| {
+                NamedLock::_create_in(&mut opened_locks, name, &options)
+            })
+            .collect()
+    }
+
+    /// Create a named lock that is opened when it is locked for the first
+    /// time.
+    ///
+    /// The name is validated and resolved like in [NamedLock::create], but
+    /// the lock file (or mutex) is neither created nor opened until the
+    /// first [LazyNamedLock::lock] or [LazyNamedLock::try_lock]. This saves
+    /// file descriptors (or handles) when there are many locks that are
+    /// rarely taken. Once opened, the lock stays open like a [NamedLock].
+    ///
+    /// The downside is that errors of opening are reported late: the first
+    /// lock can fail with `Error::CreateFailed`, and so can every lock after
+    /// it until opening succeeds.
+    pub fn lazy(name: &str) -> Result<LazyNamedLock> {
+        Ok(LazyNamedLock {
+            lock: OnceCell::new(),
+            name: NamedLock::resolved_name(name)?,
+        })
+    }
+
+    /// Create/open a named lock on specified path.
+    ///
+    /// # Notes
+    ///
+    /// * This function does not append `.lock` on the path.
+    /// * Parent directories must exist.
+    ///
+    /// # Interoperability
+    ///
+    /// The whole file is locked with an exclusive [`flock`], which is what
+    /// the [`fd-lock`] crate does on UNIX as well. So a lock created with
+    /// this function and an `fd-lock` `RwLock` on the same path exclude
+    /// each other: the named lock conflicts with both the read and the
+    /// write guards of `fd-lock`.
+    ///
+    /// This does not hold on Windows, where named locks are mutexes while
+    /// `fd-lock` uses `LockFileEx` on the file.
+    ///
+    /// [`flock`]: https://linux.die.net/man/2/flock
+    /// [`fd-lock`]: https://docs.rs/fd-lock
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn with_path<P>(path: P) -> Result<NamedLock>
+    where
+        P: AsRef<Path>,
+    {
+        NamedLockOptions::new().with_path(path)
+    }
+
+    /// Create/open a named lock on the directory `dir` itself.
+    ///
+    /// The directory is opened (`O_DIRECTORY | O_RDONLY`) and locked with
+    /// [`flock`], so nothing is created inside it. This is meant to
+    /// coordinate operations on the whole directory, e.g. a bulk update of
+    /// its files, without leaving a lock file behind in it.
+    ///
+    /// # Notes
+    ///
+    /// * Like on files, `flock` on a directory is advisory: it only
+    ///   excludes others that lock the same directory, and does not stop
+    ///   anyone from changing its content.
+    /// * The directory must exist, otherwise `Error::CreateFailed` is
+    ///   returned. It is never created or removed.
+    ///
+    /// [`flock`]: https://linux.die.net/man/2/flock
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn with_dir_path<P>(dir: P) -> Result<NamedLock>
+    where
+        P: AsRef<Path>,
+    {
+        let mut options = NamedLockOptions::new();
+        options.directory = true;
+
+        NamedLock::_create(dir.as_ref().to_owned(), &options)
+    }
+
+    /// Returns the directory that [NamedLock::create] places lock files in.
+    ///
+    /// The directory is resolved in the following order:
+    ///
+    /// 1. The override environment variable, if one is configured and set.
+    /// 2. `TMPDIR` environment variable, if it is set.
+    /// 3. `/tmp`.
+    ///
+    /// Variables that are set to an empty string count as unset.
+    ///
+    /// The override variable can be configured at runtime with
+    /// [NamedLock::set_lock_dir_var], or at build time by setting
+    /// `NAMED_LOCK_DIR_VAR` to its name when compiling this crate.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn default_lock_dir() -> PathBuf {
+        lock_dir_source().into_path()
+    }
+
+    /// Set the environment variable that overrides `TMPDIR` as the
+    /// lock directory, or remove the override with `None`.
+    ///
+    /// For example, after `NamedLock::set_lock_dir_var(Some("MYAPP_LOCK_DIR"))`
+    /// lock files are created in `$MYAPP_LOCK_DIR` when it is set.
+    ///
+    /// This only affects locks created afterwards.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn set_lock_dir_var(var: Option<&str>) {
+        *LOCK_DIR_VAR.lock() = var.map(OsString::from);
+    }
+
+    /// Limit the number of lock files that this process keeps open at the
+    /// same time, or remove the limit with `None`.
+    ///
+    /// Every open [NamedLock] keeps its lock file open, so a process with
+    /// many locks may run out of file descriptors. With a limit, opening a
+    /// lock file beyond it closes the file of the least recently used lock
+    /// that is not held by this process. The file is opened again the next
+    /// time that lock is acquired.
+    ///
+    /// Without a limit, running out of file descriptors fails with
+    /// `Error::TooManyOpenFiles`.
+    ///
+    /// # Notes
+    ///
+    /// * Opening a lock file fails with `Error::TooManyOpenFiles` if the
+    ///   limit is reached and every open lock is held.
+    /// * A lock that is acquired again after its file was closed may fail
+    ///   like [NamedLock::create] does, e.g. if the directory was removed.
+    /// * The descriptor of a lock whose file was closed is not inherited by
+    ///   children until the lock is used again, see
+    ///   [NamedLockOptions::inheritable].
+    /// * Lowering the limit does not close any file. Files are only closed
+    ///   when another one is opened.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn set_max_open_files(max: Option<usize>) {
+        fd_limit::set_max_open(max);
+    }
+
+    /// Returns the lock files in `dir` that are not held by anyone.
+    ///
+    /// Every regular file in `dir` whose name ends with `.lock` is locked
+    /// and unlocked again without blocking. Files that can be locked are
+    /// returned, sorted by path, as candidates for cleanup. Subdirectories
+    /// are not scanned.
+    ///
+    /// # Notes
+    ///
+    /// * The result is outdated as soon as it is returned: a file can be
+    ///   locked by someone right after it was scanned.
+    /// * Removing a lock file that another process has open, even if it is
+    ///   not locked, splits the lock: that process keeps locking the removed
+    ///   file while new processes create and lock a new one. Only remove lock
+    ///   files when no process that may use them is running.
+    /// * Files that are not named locks, but happen to end with `.lock`, are
+    ///   reported too.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn scan_stale<P>(dir: P) -> Result<Vec<PathBuf>>
+    where
+        P: AsRef<Path>,
+    {
+        let mut stale = Vec::new();
+
+        for entry in std::fs::read_dir(dir).map_err(Error::Io)? {
+            let entry = entry.map_err(Error::Io)?;
+            let path = entry.path();
+
+            if path.extension() != Some(OsStr::new("lock"))
+                || !entry.file_type().map_err(Error::Io)?.is_file()
+            {
+                continue;
+            }
+
+            // Files that were removed in the meantime are skipped.
+            if RawNamedLock::probe(&path).map_err(Error::Io)? == Some(false) {
+                stale.push(path);
+            }
+        }
+
+        stale.sort();
+
+        Ok(stale)
+    }
+
+    /// Create/open a named lock in the specified directory.
+    ///
+    /// This applies the same naming as [NamedLock::create], so the path of
+    /// the lock file will be `<dir>/<name>.lock`. This means that
+    /// `NamedLock::with_path_named("/tmp", "foo")` and
+    /// `NamedLock::create("foo")` refer to the same lock when `TMPDIR`
+    /// is not set.
+    ///
+    /// # Notes
+    ///
+    /// * `name` follows the same rules as in [NamedLock::create].
+    /// * `dir` must exist.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn with_path_named<P>(dir: P, name: &str) -> Result<NamedLock>
+    where
+        P: AsRef<Path>,
+    {
+        NamedLockOptions::new().with_path(lock_path_in(dir.as_ref(), name)?)
+    }
+
+    /// Create/open a named lock whose lock file is in memory.
+    ///
+    /// The lock file is placed at `/dev/shm/<name>.lock`, which is a tmpfs
+    /// on most Linux systems, so locking never touches a disk. If `/dev/shm`
+    /// does not exist or is not writable, the lock file is placed in
+    /// [NamedLock::default_lock_dir] like with [NamedLock::create].
+    ///
+    /// # Notes
+    ///
+    /// * tmpfs is cleared on reboot, so the lock file does not survive it.
+    ///   Only the lock itself matters for locking, but nothing else should
+    ///   be kept in the file.
+    /// * Locks of this function and of [NamedLock::create] with the same
+    ///   name are different locks, unless the fallback is used.
+    /// * `name` follows the same rules as in [NamedLock::create].
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(docsrs, doc(cfg(target_os = "linux")))]
+    pub fn create_shm(name: &str) -> Result<NamedLock> {
+        let dir = match shm_dir() {
+            Some(dir) => dir.to_owned(),
+            None => NamedLock::default_lock_dir(),
+        };
+
+        NamedLock::with_path_named(dir, name)
+    }
+
+    /// Create/open a named lock in the specified directory with a name that
+    /// may not be valid UTF-8.
+    ///
+    /// This is [NamedLock::with_path_named] for names that come from the
+    /// filesystem, so the path of the lock file will be `<dir>/<name>.lock`.
+    ///
+    /// # Notes
+    ///
+    /// * `name` must not be empty, otherwise `Error::EmptyName` is returned.
+    /// * `name` must not contain any of the bytes of [RESERVED_CHARS],
+    ///   otherwise `Error::InvalidCharacter` is returned. Any other bytes
+    ///   are allowed.
+    /// * `dir` must exist.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn create_os_in<P>(dir: P, name: &OsStr) -> Result<NamedLock>
+    where
+        P: AsRef<Path>,
+    {
+        NamedLockOptions::new().with_path(lock_path_in_os(dir.as_ref(), name)?)
+    }
+
+    /// Create/open a named lock in the directory that `dirfd` refers to.
+    ///
+    /// The lock file `<name>.lock` is opened with [`openat`] relative to
+    /// `dirfd`, so the path of the directory is never resolved again. This
+    /// protects daemons that hold a trusted directory descriptor against
+    /// the directory being swapped by someone else.
+    ///
+    /// # Notes
+    ///
+    /// * `name` follows the same rules as in [NamedLock::create].
+    /// * `dirfd` must refer to a directory, otherwise `Error::CreateFailed`
+    ///   is returned.
+    /// * `dirfd` must stay open while the lock is used, because the lock
+    ///   file is also accessed through `/proc/self/fd/<dirfd>`, e.g. by
+    ///   [NamedLock::try_lock_or_holder].
+    ///
+    /// [`openat`]: https://man7.org/linux/man-pages/man2/openat.2.html
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(docsrs, doc(cfg(target_os = "linux")))]
+    pub fn with_dir_fd(
+        dirfd: std::os::unix::io::RawFd,
+        name: &str,
+    ) -> Result<NamedLock> {
+        let dir = PathBuf::from(format!("/proc/self/fd/{}", dirfd));
+        let mut options = NamedLockOptions::new();
+        options.dir_fd = Some(dirfd);
+
+        NamedLock::_create(lock_path_in(&dir, name)?, &options)
+    }
+
+    fn _create(
+        name: NameType,
+        options: &NamedLockOptions,
+    ) -> Result<NamedLock> {
+        NamedLock::_create_in(&mut OPENED_RAW_LOCKS.lock(), name, options)
+    }
+
+    // Like `_create`, for callers that already hold `OPENED_RAW_LOCKS`.
+    fn _create_in(
+        opened_locks: &mut HashMap<NameType, OpenedLock>,
+        name: NameType,
+        options: &NamedLockOptions,
+    ) -> Result<NamedLock> {
+        let key = registry_key(&name);
+
+        let (lock, state) = match opened_lock(opened_locks, &key) {
+            Some(opened) => opened,
+            None => {
+                let raw = RawNamedLock::create(&name, options)?;
+                let state = LockState::new(name, raw.backend());
+                let lock = Arc::new(Mutex::new(raw));
+                opened_locks.insert(
+                    key,
+                    OpenedLock {
+                        raw: Arc::downgrade(&lock),
+                        state: state.clone(),
+                    },
+                );
+                #[cfg(unix)]
+                fd_limit::register(&lock);
+                (lock, state)
+            }
+        };
+
+        Ok(NamedLock {
+            raw: lock,
+            state,
+            abandoned: AbandonedPolicy::Accept,
+            grace: Duration::ZERO,
+            jitter: Duration::ZERO,
+            max_polls: None,
+        })
+    }
+
+    fn _create_locked(
+        name: NameType,
+        options: &NamedLockOptions,
+    ) -> Result<(NamedLock, NamedLockGuard)> {
+        let key = registry_key(&name);
+        let mut opened_locks = OPENED_RAW_LOCKS.lock();
+
+        // The OS lock is already open in this process, so it can only be
+        // locked as usual.
+        if let Some((lock, state)) = opened_lock(&opened_locks, &key) {
+            drop(opened_locks);
+
+            let lock = NamedLock {
+                raw: lock,
+                state,
+                abandoned: AbandonedPolicy::Accept,
+                grace: Duration::ZERO,
+                jitter: Duration::ZERO,
+                max_polls: None,
+            };
+            let guard = lock.try_lock()?;
+
+            return Ok((lock, guard));
+        }
+
+        let (raw, owned) = RawNamedLock::create_owned(&name, options)?;
+        let state = LockState::new(name, raw.backend());
+        let lock = Arc::new(Mutex::new(raw));
+        opened_locks.insert(
+            key,
+            OpenedLock {
+                raw: Arc::downgrade(&lock),
+                state: state.clone(),
+            },
+        );
+        drop(opened_locks);
+        #[cfg(unix)]
+        fd_limit::register(&lock);
+
+        // Nobody else has seen the new handle, so this never blocks.
+        let guard = lock.lock_arc();
+
+        if !owned {
+            guard.try_lock()?;
+        }
+
+        let guard = NamedLockGuard::new(guard, &state);
+        let lock = NamedLock {
+            raw: lock,
+            state,
+            abandoned: AbandonedPolicy::Accept,
+            grace: Duration::ZERO,
+            jitter: Duration::ZERO,
+            max_polls: None,
+        };
+
+        Ok((lock, guard))
+    }
+
+    /// Returns the [LockName] of this lock, which orders it like
+    /// [NamedLock::lock_all] does.
+    pub fn lock_name(&self) -> LockName {
+        LockName {
+            name: self.name(),
+        }
+    }
+
+    // Current name of the lock, which `relocate` changes.
+    fn name(&self) -> NameType {
+        self.state.name.lock().clone()
+    }
+
+    /// Returns the number of handles in this process that refer to the
+    /// same underlying OS lock.
+    ///
+    /// Every [NamedLock] and [NamedLockGuard] for the same name counts as
+    /// a handle, so a count that does not drop as expected points to a
+    /// forgotten clone or guard. Handles of other processes are not counted.
+    pub fn handle_count(&self) -> usize {
+        Arc::strong_count(&self.raw)
+    }
+
+    /// Clear the registry of opened locks, so that the next
+    /// [NamedLock::create] of a name opens the OS lock again.
+    ///
+    /// Locks of a process share one open lock file or `HANDLE` per name,
+    /// which is looked up in a process-wide registry. This removes the
+    /// entries of locks that are already closed. With `detach_live`, the
+    /// entries of locks that are still open are removed as well, e.g. to
+    /// pick up a lock file that was replaced on disk.
+    ///
+    /// This is only available with the `reset-registry` feature.
+    ///
+    /// # Notes
+    ///
+    /// * Detached locks keep working, but they are no longer shared with
+    ///   the locks created afterwards. The in-process layer does not
+    ///   arbitrate between them anymore, so the old and the new lock of the
+    ///   same name exclude each other only as far as the OS does.
+    /// * On UNIX both open their own descriptor, so locking one while the
+    ///   same thread holds the other blocks forever, instead of returning
+    ///   `Error::WouldDeadlock`.
+    /// * On Windows the same thread can acquire a mutex again through
+    ///   another `HANDLE`, so the old and the new lock do not exclude each
+    ///   other within a thread at all.
+    #[cfg(any(test, feature = "reset-registry"))]
+    #[cfg_attr(docsrs, doc(cfg(feature = "reset-registry")))]
+    pub fn reset_registry(detach_live: bool) {
+        let mut opened_locks = OPENED_RAW_LOCKS.lock();
+
+        if detach_live {
+            opened_locks.clear();
+        } else {
+            opened_locks.retain(|_, lock| lock.raw.strong_count() > 0);
+        }
+    }
+
+    /// Open the lock file again in a child that was created with `fork`.
+    ///
+    /// After `fork` the child shares the open file description of the lock
+    /// file with its parent, and [`flock`] locks belong to the open file
+    /// description. So the child holds whatever the parent holds, it can
+    /// lock while the parent holds the lock, and unlocking in one of them
+    /// releases the lock of the other as well.
+    ///
+    /// Calling this in the child gives it its own open file description, so
+    /// that parent and child exclude each other like unrelated processes.
+    /// It detects the child by a change of the process ID, so calling it in
+    /// the process that opened the lock does nothing. It affects every
+    /// handle of the lock in the process.
+    ///
+    /// # Notes
+    ///
+    /// * The lock must not be held in the child, otherwise this returns
+    ///   `Error::WouldBlock`. A guard that existed when the process forked
+    ///   exists in the child as well, and dropping it in the child releases
+    ///   the lock of the parent.
+    /// * Lock files are opened with `O_CLOEXEC` (unless
+    ///   [NamedLockOptions::inheritable] is set), so a child that calls
+    ///   `exec` does not keep them anyway.
+    ///
+    /// [`flock`]: https://linux.die.net/man/2/flock
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn reinit_after_fork(&self) -> Result<()> {
+        let mut raw = self.raw.try_lock().ok_or(Error::WouldBlock)?;
+        raw.reinit_after_fork()
+    }
+
+    /// Remove the lock file when the process is asked to terminate by a
+    /// signal.
+    ///
+    /// This is meant for single-instance tools that want no lock file left
+    /// behind after `SIGTERM`, `SIGINT` (Ctrl-C) or `SIGHUP`. The kernel
+    /// releases the lock itself when the process exits, so only the file
+    /// needs to be removed.
+    ///
+    /// The first call installs a handler for these signals that stays
+    /// installed for the lifetime of the process. When a signal arrives,
+    /// the handler removes every registered lock file, then calls the
+    /// handler that was installed before, or terminates the process like
+    /// the signal would have. Dropping the returned [SignalCleanup]
+    /// unregisters the lock file.
+    ///
+    /// ```rust,no_run
+    /// use named_lock::NamedLock;
+    /// use named_lock::Result;
+    ///
+    /// fn main() -> Result<()> {
+    ///     let lock = NamedLock::create("foobar")?;
+    ///     let _guard = lock.try_lock()?;
+    ///     // Dropped before the guard.
+    ///     let _cleanup = lock.on_signal_cleanup()?;
+    ///
+    ///     // Run until terminated...
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    ///
+    /// # Notes
+    ///
+    /// * Signal handling is global to the process. Register only while the
+    ///   lock is held, and drop the [SignalCleanup] before the guard, so the
+    ///   file of a lock that another process holds is never removed.
+    /// * Removing a lock file splits the lock for processes that wait on it:
+    ///   they keep locking the removed file, while new processes create a
+    ///   new one. Only use this when nobody waits for the lock, e.g. with
+    ///   [NamedLock::try_lock].
+    /// * The handler only calls async-signal-safe functions (`unlink`,
+    ///   `signal` and `raise`). The paths are prepared when they are
+    ///   registered.
+    /// * A previous handler is called without `siginfo_t`. It should
+    ///   terminate the process, since the lock file is already removed.
+    ///   Signals that were ignored when the handler was installed (e.g.
+    ///   `SIGHUP` under `nohup`) are left alone.
+    /// * A handler installed later by someone else replaces this one.
+    /// * At most 32 lock files can be registered at the same time, after
+    ///   that `Error::Io` is returned.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn on_signal_cleanup(&self) -> Result<SignalCleanup> {
+        SignalCleanup::register(&self.name())
+    }
+
+    /// Move the lock file to `new_path` without ever leaving it unlocked.
+    ///
+    /// This locks the lock, renames the lock file to `new_path`, and
+    /// unlocks it again. Processes that already have the lock file open
+    /// keep excluding each other, since the file itself stays the same. If
+    /// `new_path` is on another file system, where the file can not be
+    /// moved, a new lock file is created and locked there before the old
+    /// one is removed and released.
+    ///
+    /// Afterwards every handle of the lock in this process uses the new
+    /// path, and [NamedLock::with_path] with `new_path` gives the same lock.
+    ///
+    /// # Notes
+    ///
+    /// * This is advanced and racy. A process that opens the old path
+    ///   after the relocation creates a new, unrelated lock file, and so
+    ///   does a process on another file system that still has the old file
+    ///   open. All participants must agree on the new path before it is
+    ///   used.
+    /// * If `new_path` already exists, it is replaced.
+    /// * Like [NamedLock::lock], this blocks while the lock is held, and it
+    ///   returns `Error::WouldDeadlock` if the current thread holds it.
+    /// * If another lock of this process is opened at `new_path`, an
+    ///   `Error::Io` with `ErrorKind::AlreadyExists` is returned.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn relocate<P>(&self, new_path: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let new_path = new_path.as_ref();
+        let mut guard = self.lock()?;
+
+        let old_key = registry_key(&self.name());
+        let new_key = registry_key(new_path);
+        let is_self = |opened: &OpenedLock| {
+            std::ptr::eq(opened.raw.as_ptr(), Arc::as_ptr(&self.raw))
+        };
+
+        // The new path is registered before the file is moved, so that no
+        // other lock of this process is opened there meanwhile. Moving the
+        // file can wait for another process, so the registry is only held
+        // to update the entries.
+        {
+            let mut opened_locks = OPENED_RAW_LOCKS.lock();
+
+            if new_key != old_key
+                && opened_lock(&opened_locks, &new_key).is_some()
+            {
+                return Err(Error::Io(
+                    std::io::ErrorKind::AlreadyExists.into(),
+                ));
+            }
+
+            opened_locks.insert(
+                new_key.clone(),
+                OpenedLock {
+                    raw: Arc::downgrade(&self.raw),
+                    state: self.state.clone(),
+                },
+            );
+        }
+
+        let res = guard.raw.relocate(new_path);
+        let mut opened_locks = OPENED_RAW_LOCKS.lock();
+
+        if let Err(e) = res {
+            if new_key != old_key
+                && opened_locks.get(&new_key).is_some_and(is_self)
+            {
+                opened_locks.remove(&new_key);
+            }
+
+            return Err(e);
+        }
+
+        if new_key != old_key && opened_locks.get(&old_key).is_some_and(is_self)
+        {
+            opened_locks.remove(&old_key);
+        }
+
+        drop(opened_locks);
+        *self.state.name.lock() = new_path.to_owned();
+
+        guard.unlock()
+    }
+
+    /// Create a [WeakNamedLock] that refers to this lock without keeping
+    /// it open.
+    pub fn downgrade(&self) -> WeakNamedLock {
+        WeakNamedLock {
+            raw: Arc::downgrade(&self.raw),
+            state: self.state.clone(),
+            abandoned: self.abandoned.clone(),
+            grace: self.grace,
+            jitter: self.jitter,
+            max_polls: self.max_polls,
+        }
+    }
+
+    /// Create a [LockHandle] that keeps this lock open and gives it back
+    /// with [LockHandle::to_lock].
+    ///
+    /// Unlike a [WeakNamedLock], the handle keeps the underlying OS lock
+    /// open, so getting the lock back always succeeds.
+    pub fn downgrade_handle(&self) -> LockHandle {
+        LockHandle {
+            raw: self.raw.clone(),
+            state: self.state.clone(),
+            abandoned: self.abandoned.clone(),
+            grace: self.grace,
+            jitter: self.jitter,
+            max_polls: self.max_polls,
+        }
+    }
+
+    /// Set what happens when this handle acquires a lock that was
+    /// abandoned by its previous owner.
+    ///
+    /// The policy applies to every locking method of this handle and its
+    /// clones made afterwards. The default is [AbandonedPolicy::Accept].
+    ///
+    /// Only Windows reports abandoned locks, so on UNIX the policy never
+    /// runs.
+    pub fn set_abandoned_policy(&mut self, policy: AbandonedPolicy) {
+        self.abandoned = policy;
+    }
+
+    /// Set how long [NamedLock::try_lock] waits for the lock before it
+    /// returns `Error::WouldBlock`.
+    ///
+    /// The default is zero, so `try_lock` does not wait at all. A grace
+    /// period of a few milliseconds lets `try_lock` succeed for locks that
+    /// are released almost immediately, which avoids storms of retries.
+    /// With a grace period, `try_lock` is the same as [NamedLock::acquire]
+    /// with [AcquireMode::Timeout].
+    ///
+    /// The grace period applies to this handle and its clones made
+    /// afterwards. [NamedLock::acquire] with [AcquireMode::NonBlocking] still
+    /// never waits.
+    pub fn set_try_lock_grace(&mut self, grace: Duration) {
+        self.grace = grace;
+    }
+
+    /// Set the upper limit of a random delay before [NamedLock::lock]
+    /// blocks on a contended lock.
+    ///
+    /// The default is zero, so `lock` blocks right away. When many
+    /// processes wait for the same lock they all wake up and retry at the
+    /// same time. A jitter of a few milliseconds spreads them out, so fewer
+    /// of them compete at once. This is only a performance tuning knob, it
+    /// does not change what `lock` returns.
+    ///
+    /// # Notes
+    ///
+    /// * The delay is only added when the lock is held by another process
+    ///   (or another handle), and the lock is tried again right after it.
+    /// * The jitter applies to [AcquireMode::Blocking], not to timeouts,
+    ///   and to this handle and its clones made afterwards.
+    pub fn set_acquire_jitter(&mut self, max: Duration) {
+        self.jitter = max;
+    }
+
+    /// Set the maximum number of polls of the methods that wait for the
+    /// lock by polling it.
+    ///
+    /// [NamedLock::lock_with_progress], [NamedLock::lock_cancellable] and
+    /// [NamedLock::lock_interruptible] wait in short, timed attempts. The
+    /// default is `None`, so they keep trying until the lock is acquired or
+    /// the wait is cancelled. With `Some(max)` they give up with
+    /// `Error::WouldBlock` after `max` attempts, which bounds how long they
+    /// can wait for a lock that is never released.
+    ///
+    /// # Notes
+    ///
+    /// * An attempt of `lock_with_progress` lasts its `interval`, and one of
+    ///   `lock_cancellable` or `lock_interruptible` a few milliseconds.
+    /// * With a maximum, `lock_interruptible` polls the lock of the other
+    ///   processes as well, instead of blocking in `flock` until a signal
+    ///   arrives.
+    /// * A maximum of 0 counts as 1. It applies to this handle and its
+    ///   clones made afterwards.
+    pub fn set_max_poll_attempts(&mut self, max: Option<u32>) {
+        self.max_polls = max;
+    }
+
+    // Counts an attempt of a polling method. Returns `false` if it exceeds
+    // the maximum of `set_max_poll_attempts`.
+    fn poll_attempt(&self, attempts: &mut u32) -> bool {
+        *attempts = attempts.saturating_add(1);
+        self.max_polls.is_none_or(|max| *attempts <= max.max(1))
+    }
+
+    /// Take the error of the last unlock that failed when a guard of this
+    /// lock was dropped.
+    ///
+    /// Dropping a [NamedLockGuard] can not report errors, so they are kept
+    /// here instead of being lost. Only the last error is kept, for this
+    /// lock and all of its handles, until it is taken. Use
+    /// [NamedLockGuard::unlock] to get the error right away.
+    ///
+    /// # Windows
+    ///
+    /// Dropping a guard on another thread than the one that locked it fails
+    /// with `Error::WrongThread`, and the mutex stays owned by the locking
+    /// thread. This makes that mistake visible.
+    pub fn take_unlock_error(&self) -> Option<Error> {
+        UNLOCK_ERRORS.lock().remove(&holder_key(&self.raw)).map(|(_, e)| e)
+    }
+
+    fn is_held_by_current_thread(&self) -> bool {
+        *self.state.holder.lock() == Some(thread::current().id())
+    }
+
+    // Applies the abandoned policy to a guard that was just acquired.
+    fn check_abandoned(
+        &self,
+        guard: NamedLockGuard,
+        abandoned: bool,
+    ) -> Result<NamedLockGuard> {
+        if abandoned {
+            match &self.abandoned {
+                AbandonedPolicy::Accept => {}
+                // Dropping the guard releases the lock, and it is not
+                // reported as abandoned again.
+                AbandonedPolicy::Error => return Err(Error::Abandoned),
+                AbandonedPolicy::Recover(recover) => recover(&guard)?,
+            }
+        }
+
+        Ok(guard)
+    }
+
+    /// Try to lock named lock.
+    ///
+    /// If it is already locked, `Error::WouldBlock` will be returned. With
+    /// [NamedLock::set_try_lock_grace], it waits for the grace period first.
+    pub fn try_lock(&self) -> Result<NamedLockGuard> {
+        if !self.grace.is_zero() {
+            return self
+                .acquire(AcquireMode::Timeout(self.grace))
+                .map(Acquired::into_guard);
+        }
+
+        self.try_lock_now()
+    }
+
+    // `try_lock` without the grace period.
+    fn try_lock_now(&self) -> Result<NamedLockGuard> {
+        let res = self.try_lock_once();
+        metric::record(&self.state, &res, || Duration::ZERO);
+        res
+    }
+
+    // `try_lock_now` without recording metrics, for retrying.
+    fn try_lock_once(&self) -> Result<NamedLockGuard> {
+        // This is the fast path of `acquire`, without keeping the time.
+        // If the lock is held by this process we return before doing
+        // any syscall.
+        let guard = self.raw.try_lock_arc().ok_or(Error::WouldBlock)?;
+
+        let abandoned = guard.try_lock()?;
+
+        self.check_abandoned(NamedLockGuard::new(guard, &self.state), abandoned)
+    }
+
+    /// Try to lock named lock, for driving the acquisition from an event
+    /// loop.
+    ///
+    /// This is [NamedLock::try_lock] in the shape of [`Poll`]:
+    /// `Error::WouldBlock` becomes [`Poll::Pending`] and everything else is
+    /// [`Poll::Ready`]. Nothing is registered to wake the caller up, so the
+    /// caller decides when to poll again. This never waits, even with
+    /// [NamedLock::set_try_lock_grace].
+    pub fn poll_lock(&self) -> Poll<Result<NamedLockGuard>> {
+        match self.try_lock_now() {
+            Err(Error::WouldBlock) => Poll::Pending,
+            res => Poll::Ready(res),
+        }
+    }
+
+    /// Try to lock named lock up to `attempts` times, spinning in between.
+    ///
+    /// Between attempts this busy-waits with [`std::hint::spin_loop`],
+    /// doubling the number of spins each time up to a small limit, and it
+    /// never sleeps. This suits very short critical sections, where the lock
+    /// is expected to be released within microseconds. For longer waits use
+    /// [AcquireMode::Timeout], which does not keep a CPU busy.
+    ///
+    /// Returns `Error::WouldBlock` if all attempts fail. An `attempts` of 0
+    /// behaves like 1. The attempts ignore [NamedLock::set_try_lock_grace].
+    pub fn try_lock_spin(&self, attempts: u32) -> Result<NamedLockGuard> {
+        const MAX_SPINS: u32 = 64;
+
+        let mut spins = 1;
+
+        for _ in 1..attempts {
+            match self.try_lock_once() {
+                Err(Error::WouldBlock) => {}
+                res => {
+                    metric::record(&self.state, &res, || Duration::ZERO);
+                    return res;
+                }
+            }
+
+            for _ in 0..spins {
+                std::hint::spin_loop();
+            }
+
+            spins = (spins * 2).min(MAX_SPINS);
+        }
+
+        self.try_lock_now()
+    }
+
+    /// Try to lock named lock and report who holds it on contention.
+    ///
+    /// This works like [NamedLock::try_lock], but it tells apart a lock
+    /// that is held by this process (another thread or guard) from a lock
+    /// that is held by another process. An error of the abandoned policy
+    /// (see [NamedLock::set_abandoned_policy]) is reported as
+    /// [Contention::Failed].
+    ///
+    /// The result is best-effort: the OS does not tell who holds a
+    /// [`flock`], so this process holding the same file through an
+    /// inherited descriptor or a hard link is reported as
+    /// [Contention::OtherProcess].
+    ///
+    /// [`flock`]: https://linux.die.net/man/2/flock
+    pub fn try_lock_diagnostic(&self) -> Result<NamedLockGuard, Contention> {
+        let guard = match self.raw.try_lock_arc() {
+            Some(guard) => guard,
+            None => {
+                metric::would_block(&self.state);
+                return Err(Contention::SameProcess);
+            }
+        };
+
+        match guard.try_lock() {
+            Ok(abandoned) => {
+                let guard = self
+                    .check_abandoned(
+                        NamedLockGuard::new(guard, &self.state),
+                        abandoned,
+                    )
+                    .map_err(Contention::Failed)?;
+                metric::acquired(&self.state, Duration::ZERO);
+                Ok(guard)
+            }
+            Err(Error::WouldBlock) => {
+                metric::would_block(&self.state);
+                Err(Contention::OtherProcess)
+            }
+            Err(e) => Err(Contention::Failed(e)),
+        }
+    }
+
+    /// Try to lock named lock and report who holds it on contention.
+    ///
+    /// On UNIX the holder is known only if it records itself in the lock
+    /// file with [NamedLockOptions::record_holder]. On Windows the holder
+    /// is always [LockHolder::Unknown].
+    ///
+    /// The holder information is best-effort and it may be stale, e.g. if
+    /// the holder has just released the lock.
+    pub fn try_lock_or_holder(&self) -> Result<NamedLockGuard, LockHolder> {
+        match self.try_lock() {
+            Ok(guard) => Ok(guard),
+            Err(Error::WouldBlock) => Err(self.holder()),
+            Err(e) => Err(LockHolder::Failed(e)),
+        }
+    }
+
+    #[cfg(unix)]
+    fn holder(&self) -> LockHolder {
+        Metadata::read(&self.name())
+            .ok()
+            .and_then(|meta| meta.get(metadata::PID)?.parse().ok())
+            .map_or(LockHolder::Unknown, LockHolder::Pid)
+    }
+
+    #[cfg(windows)]
+    fn holder(&self) -> LockHolder {
+        LockHolder::Unknown
+    }
+
+    /// Returns `true` if the holder of the lock has exceeded its lease.
+    ///
+    /// The lease is recorded by a holder that opened the lock with
+    /// [NamedLockOptions::ttl]. If the lock is not held, or if the holder
+    /// did not record a lease, this returns `false`.
+    ///
+    /// This is advisory: an expired lease does not release the lock, it only
+    /// tells that the holder may be stuck.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn is_expired(&self) -> Result<bool> {
+        let meta = Metadata::read(&self.name()).map_err(Error::Io)?;
+        Ok(meta.is_lease_expired(clock::system_now()))
+    }
+
+    /// Returns `true` if the lock file records a holder that is no longer
+    /// running.
+    ///
+    /// A holder that opened the lock with [NamedLockOptions::record_holder]
+    /// removes itself from the lock file when it unlocks, but a holder that
+    /// crashed leaves its process ID behind. The lock itself is released by
+    /// the OS when the holder exits, so this only tells that the recorded
+    /// holder is outdated, e.g. to clean up after it.
+    ///
+    /// If no holder is recorded, this returns `false`.
+    ///
+    /// # Notes
+    ///
+    /// * On Linux the recorded start time of the holder is compared with
+    ///   the one of the running process in `/proc/<pid>/stat`, so a process
+    ///   that reuses the ID of a holder that exited is not mistaken for it.
+    /// * Elsewhere, or if the start time was not recorded, only the process
+    ///   ID is checked, so a reused ID counts as a live holder.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn is_stale(&self) -> Result<bool> {
+        let meta = Metadata::read(&self.name()).map_err(Error::Io)?;
+
+        let pid = match meta.get(metadata::PID).and_then(|v| v.parse().ok()) {
+            Some(pid) => pid,
+            None => return Ok(false),
+        };
+
+        if !metadata::process_exists(pid) {
+            return Ok(true);
+        }
+
+        let recorded =
+            meta.get(metadata::START).and_then(|v| v.parse::<u64>().ok());
+
+        Ok(match (recorded, metadata::process_start_time(pid)) {
+            (Some(recorded), Some(start)) => recorded != start,
+            _ => false,
+        })
+    }
+
+    /// Returns `true` if the lock excludes other processes, not only other
+    /// threads of the current process.
+    ///
+    /// This is [Backend::is_cross_process] of [NamedLock::backend]. Code
+    /// that relies on cross-process exclusion can check it to refuse
+    /// running with a backend that only locks within the process.
+    pub fn is_cross_process(&self) -> bool {
+        self.backend().is_cross_process()
+    }
+
+    /// Returns the OS mechanism that this lock uses.
+    ///
+    /// Locks of different mechanisms do not exclude each other, e.g. a
+    /// `flock` lock does not conflict with an `fcntl` lock on the same
+    /// file. Logging the backend helps to track down such mismatches when
+    /// a program coordinates with another implementation.
+    pub fn backend(&self) -> Backend {
+        self.state.backend
+    }
+
+    /// Returns a summary of the state of the lock.
+    ///
+    /// This collects what [NamedLock::exists], [NamedLock::try_lock_or_holder]
+    /// and [NamedLock::is_expired] report into one [LockStatus], which can
+    /// be printed with `Display`, e.g. for a `status` command.
+    ///
+    /// # Notes
+    ///
+    /// * The status is a racy snapshot: the lock can be locked or unlocked
+    ///   right after it was read, and the fields are read one after the
+    ///   other, so they may not even agree with each other.
+    /// * To find out if another process holds the lock, it is briefly locked
+    ///   and unlocked. Someone that tries to lock it at the same time may see
+    ///   it as held.
+    /// * On Windows, this consumes the abandonment of a mutex whose previous
+    ///   owner exited without unlocking it.
+    pub fn status(&self) -> Result<LockStatus> {
+        #[cfg(unix)]
+        let exists = RawNamedLock::exists(&self.name())?;
+        // The mutex exists for as long as this handle is open.
+        #[cfg(windows)]
+        let exists = true;
+
+        let held = exists && self.is_held()?;
+        let holder = match self.holder() {
+            LockHolder::Pid(pid) if held => Some(pid),
+            _ => None,
+        };
+
+        #[cfg(unix)]
+        let expired = held && self.is_expired()?;
+        #[cfg(windows)]
+        let expired = false;
+
+        Ok(LockStatus {
+            name: self.name(),
+            exists,
+            held,
+            holder,
+            expired,
+        })
+    }
+
+    #[cfg(unix)]
+    fn is_held(&self) -> Result<bool> {
+        if self.raw.is_locked() {
+            return Ok(true);
+        }
+
+        // A new descriptor conflicts with the one of this handle, so this
+        // works even if this process locks it meanwhile.
+        let held = RawNamedLock::probe(&self.name()).map_err(Error::Io)?;
+        Ok(held.unwrap_or(false))
+    }
+
+    #[cfg(windows)]
+    fn is_held(&self) -> Result<bool> {
+        let guard = match self.raw.try_lock() {
+            Some(guard) => guard,
+            None => return Ok(true),
+        };
+
+        match guard.try_lock() {
+            Ok(_) => guard.unlock().map(|_| false),
+            Err(Error::WouldBlock) => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Lock named lock.
+    ///
+    /// The lock is not reentrant. If the current thread already holds it,
+    /// `Error::WouldDeadlock` is returned instead of waiting forever. A
+    /// guard that was sent to another thread still counts as held by the
+    /// thread that locked it.
+    pub fn lock(&self) -> Result<NamedLockGuard> {
+        self.acquire(AcquireMode::Blocking).map(Acquired::into_guard)
+    }
+
+    /// Lock named lock and keep renewing a lease of `ttl` until the guard
+    /// is dropped.
+    ///
+    /// This works like [NamedLock::lock], but a lease of `ttl` is written
+    /// to the lock file like with [NamedLockOptions::ttl], and a background
+    /// thread renews it every `ttl / 2`. Watchers that check
+    /// [NamedLock::is_expired] do not see the lease expire during a long
+    /// operation, as long as the holder is alive.
+    ///
+    /// See [RenewingGuard] for the lifecycle of the renewal thread.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn lock_with_renewal(&self, ttl: Duration) -> Result<RenewingGuard> {
+        RenewingGuard::new(self.lock()?, ttl)
+    }
+
+    /// Lock named lock and retry transient failures according to `policy`.
+    ///
+    /// This works like [NamedLock::lock], but `Error::LockFailed` and
+    /// `Error::ResourceExhausted` are retried with backoff until the retries
+    /// of `policy` are used up. Every other error is returned immediately.
+    pub fn lock_with_retry(
+        &self,
+        policy: &RetryPolicy,
+    ) -> Result<NamedLockGuard> {
+        policy.run(|| self.lock())
+    }
+
+    /// Lock named lock within `timeout` and report how much of it is left.
+    ///
+    /// This waits like [AcquireMode::Timeout] and returns the guard along
+    /// with the part of `timeout` that was not spent waiting. A caller with
+    /// a total budget for several locks can pass the remaining time on to
+    /// the next acquisition. If the lock is not acquired in time,
+    /// `Error::WouldBlock` is returned.
+    ///
+    /// ```rust
+    /// use named_lock::NamedLock;
+    /// use named_lock::Result;
+    /// use std::time::Duration;
+    ///
+    /// fn main() -> Result<()> {
+    ///     let budget = Duration::from_secs(1);
+    ///
+    ///     let lock1 = NamedLock::create("foo")?;
+    ///     let (_guard1, budget) = lock1.lock_with_timeout_reporting(budget)?;
+    ///     let lock2 = NamedLock::create("bar")?;
+    ///     let (_guard2, _) = lock2.lock_with_timeout_reporting(budget)?;
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn lock_with_timeout_reporting(
+        &self,
+        timeout: Duration,
+    ) -> Result<(NamedLockGuard, Duration)> {
+        let acquired = self.acquire(AcquireMode::Timeout(timeout))?;
+        let remaining = timeout.saturating_sub(acquired.wait_duration());
+
+        Ok((acquired.into_guard(), remaining))
+    }
+
+    /// Lock named lock, unless its protocol version is incompatible.
+    ///
+    /// This is meant for rolling upgrades, where two versions of a program
+    /// may use the lock (and what it protects) one after the other. The
+    /// lock is acquired like with [NamedLock::lock], then the version that
+    /// the lock file records is passed to `compatible`:
+    ///
+    /// * If it returns `true`, or no version is recorded yet, `version` is
+    ///   recorded in its place and the guard is returned.
+    /// * Otherwise the lock is released again and
+    ///   `Error::IncompatibleVersion` with the recorded version is returned,
+    ///   so the caller can refuse to run.
+    ///
+    /// ```rust
+    /// use named_lock::NamedLock;
+    /// use named_lock::Result;
+    ///
+    /// fn main() -> Result<()> {
+    ///     let lock = NamedLock::create("foobar")?;
+    ///
+    ///     // Version 3 can take over from versions 2 and 3.
+    ///     let _guard = lock.lock_with_version(3, |v| v == 2 || v == 3)?;
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    ///
+    /// # Notes
+    ///
+    /// * The version is only read and written while the lock is held, and
+    ///   it is written before the guard is returned. So every holder sees
+    ///   the version of the previous holder that used this function.
+    /// * The version stays in the lock file after unlocking, until the next
+    ///   compatible holder replaces it. A version that can not be parsed
+    ///   counts as not recorded.
+    /// * Callers that lock without this function neither check nor change
+    ///   the version. [NamedLockOptions::record_holder] and
+    ///   [NamedLockOptions::ttl] keep it.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn lock_with_version<F>(
+        &self,
+        version: u32,
+        compatible: F,
+    ) -> Result<NamedLockGuard>
+    where
+        F: FnOnce(u32) -> bool,
+    {
+        let guard = self.lock()?;
+        let mut meta = Metadata::read(guard.path()).map_err(Error::Io)?;
+        let recorded = meta.get(metadata::VERSION).and_then(|v| v.parse().ok());
+
+        match recorded {
+            Some(recorded) if !compatible(recorded) => {
+                guard.unlock()?;
+                return Err(Error::IncompatibleVersion(recorded));
+            }
+            Some(recorded) if recorded == version => return Ok(guard),
+            _ => {}
+        }
+
+        meta.set(metadata::VERSION, version);
+        meta.write(guard.file()).map_err(Error::Io)?;
+
+        Ok(guard)
+    }
+
+    /// Run `init` once across all processes that share the lock.
+    ///
+    /// The lock is acquired like with [NamedLock::lock], then a marker in
+    /// the lock file is checked. If it is not set, `init` is called and the
+    /// marker is set once it succeeds. The lock is released before this
+    /// returns, so `init` never runs in two processes at the same time.
+    ///
+    /// Returns `true` if `init` ran and `false` if the lock file was
+    /// already marked as initialized.
+    ///
+    /// ```rust
+    /// use named_lock::NamedLock;
+    /// use named_lock::Result;
+    ///
+    /// fn main() -> Result<()> {
+    ///     let lock = NamedLock::create("foobar")?;
+    ///     lock.lock_and_init(|| {
+    ///         // Set up the shared resource...
+    ///         Ok(())
+    ///     })?;
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    ///
+    /// # Notes
+    ///
+    /// * If `init` fails, or the process dies while it runs, the marker is
+    ///   not set and the next call runs `init` again. `init` should cope
+    ///   with a partial initialization of an earlier attempt.
+    /// * An error of `init` is returned as is.
+    /// * The marker lives as long as the lock file. Removing the file, or
+    ///   opening it with [ExistingFilePolicy::Truncate], makes the next call
+    ///   initialize again. [NamedLockOptions::record_holder] and
+    ///   [NamedLockOptions::ttl] keep it.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn lock_and_init<F>(&self, init: F) -> Result<bool>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        let guard = self.lock()?;
+        let mut meta = Metadata::read(guard.path()).map_err(Error::Io)?;
+
+        if meta.get(metadata::INITIALIZED).is_some() {
+            guard.unlock()?;
+            return Ok(false);
+        }
+
+        init()?;
+
+        meta.set(metadata::INITIALIZED, 1);
+        meta.write(guard.file()).map_err(Error::Io)?;
+        guard.unlock()?;
+
+        Ok(true)
+    }
+
+    /// Block until the lock is free, without keeping it.
+    ///
+    /// This waits like [NamedLock::lock] and unlocks again right away, so
+    /// it can be used to wait for someone else's critical section to finish
+    /// before doing unrelated work.
+    ///
+    /// # Notes
+    ///
+    /// * When this returns, someone else may already hold the lock again.
+    ///   Use [NamedLock::lock] if the work needs the lock to stay free.
+    /// * The lock is briefly held, so anything that [NamedLockOptions]
+    ///   write on locking, like the holder, is written and removed again.
+    pub fn wait_until_free(&self) -> Result<()> {
+        self.lock()?.unlock()
+    }
+
+    /// Run `cmd` while holding the lock, like `flock(1)`.
+    ///
+    /// The lock is acquired like with [NamedLock::lock], then `cmd` is
+    /// spawned and the lock is released after it exits. The child does not
+    /// get the lock file, so the lock is only held while this process waits
+    /// for it: if this process dies, the lock is released even though the
+    /// command may still run. See [NamedLock::run_command_inherited] for a
+    /// lock that the child keeps.
+    ///
+    /// Errors of spawning or waiting for the command are returned as
+    /// `Error::Io`.
+    pub fn run_command(&self, cmd: &mut Command) -> Result<ExitStatus> {
+        let guard = self.lock()?;
+        let status = cmd.status().map_err(Error::Io)?;
+        guard.unlock()?;
+
+        Ok(status)
+    }
+
+    /// Run `cmd` while holding the lock and let the command inherit it.
+    ///
+    /// This works like [NamedLock::run_command], but the descriptor of the
+    /// lock file is inherited by the child (its `O_CLOEXEC` is cleared in
+    /// the child only). Since [`flock`] locks belong to the open file, the
+    /// command keeps the lock until it exits, even if this process dies
+    /// while it waits. Processes that the command spawns inherit the lock
+    /// too, unless it closes the descriptor.
+    ///
+    /// # Notes
+    ///
+    /// * When the command exits, this process releases the lock, which also
+    ///   releases it for processes that the command left running.
+    /// * The inheritance is set up with [`CommandExt::pre_exec`], which stays
+    ///   on `cmd`. Don't spawn `cmd` again once this returns.
+    ///
+    /// [`flock`]: https://linux.die.net/man/2/flock
+    /// [`CommandExt::pre_exec`]: std::os::unix::process::CommandExt::pre_exec
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn run_command_inherited(
+        &self,
+        cmd: &mut Command,
+    ) -> Result<ExitStatus> {
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::process::CommandExt;
+
+        let guard = self.lock()?;
+        let fd = guard.file().as_raw_fd();
+
+        // SAFETY: `set_cloexec` only calls `fcntl`, which is
+        // async-signal-safe.
+        unsafe { cmd.pre_exec(move || unix::set_cloexec(fd, false)) };
+
+        let status = cmd.status().map_err(Error::Io)?;
+        guard.unlock()?;
+
+        Ok(status)
+    }
+
+    /// Lock named lock and report the progress of a long wait.
+    ///
+    /// This works like [NamedLock::lock], but `on_wait` is called with the
+    /// time waited so far every `interval` until the lock is acquired, e.g.
+    /// to log "still waiting for lock". It is not called if the lock is
+    /// acquired within the first `interval`.
+    ///
+    /// ```rust
+    /// use named_lock::NamedLock;
+    /// use named_lock::Result;
+    /// use std::time::Duration;
+    ///
+    /// fn main() -> Result<()> {
+    ///     let lock = NamedLock::create("foobar")?;
+    ///     let _guard = lock.lock_with_progress(Duration::from_secs(5), |waited| {
+    ///         eprintln!("still waiting for lock, waited {:?}", waited);
+    ///     })?;
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    ///
+    /// # Notes
+    ///
+    /// * The wait is split into [AcquireMode::Timeout] waits of `interval`,
+    ///   so on UNIX the lock is polled like with a timeout, and on Windows
+    ///   each wait is a timed `WaitForSingleObject`.
+    /// * `on_wait` runs on the waiting thread, so a slow callback delays
+    ///   the acquisition.
+    /// * An `interval` below one millisecond counts as one millisecond.
+    /// * After the attempts of [NamedLock::set_max_poll_attempts] this
+    ///   returns `Error::WouldBlock`.
+    pub fn lock_with_progress<F>(
+        &self,
+        interval: Duration,
+        mut on_wait: F,
+    ) -> Result<NamedLockGuard>
+    where
+        F: FnMut(Duration),
+    {
+        if self.is_held_by_current_thread() {
+            return Err(Error::WouldDeadlock);
+        }
+
+        let interval = interval.max(Duration::from_millis(1));
+        let start = clock::now();
+        let mut attempts = 0;
+
+        let res = loop {
+            if !self.poll_attempt(&mut attempts) {
+                break Err(Error::WouldBlock);
+            }
+
+            match self.acquire_once(AcquireMode::Timeout(interval)) {
+                Err(Error::WouldBlock) => on_wait(clock::now() - start),
+                res => break res.map(Acquired::into_guard),
+            }
+        };
+
+        metric::record(&self.state, &res, || clock::now() - start);
+        res
+    }
+
+    /// Lock named lock, unless `token` is cancelled.
+    ///
+    /// This works like [NamedLock::lock], but returns [Error::Cancelled]
+    /// once [CancelToken::cancel] is called on `token` or one of its clones.
+    ///
+    /// # Notes
+    ///
+    /// * The lock is polled, so the wait ends a few milliseconds after the
+    ///   token is cancelled.
+    /// * If the token is already cancelled, this fails without trying the
+    ///   lock.
+    /// * After the attempts of [NamedLock::set_max_poll_attempts] this
+    ///   returns `Error::WouldBlock`.
+    /// * Like [NamedLock::lock], this returns `Error::WouldDeadlock` if the
+    ///   current thread holds the lock.
+    pub fn lock_cancellable(
+        &self,
+        token: &CancelToken,
+    ) -> Result<NamedLockGuard> {
+        if self.is_held_by_current_thread() {
+            return Err(Error::WouldDeadlock);
+        }
+
+        let start = clock::now();
+        let mut attempts = 0;
+
+        let res = loop {
+            if token.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+
+            if !self.poll_attempt(&mut attempts) {
+                break Err(Error::WouldBlock);
+            }
+
+            let mode = AcquireMode::Timeout(cancel::POLL_INTERVAL);
+
+            match self.acquire_once(mode) {
+                Err(Error::WouldBlock) => continue,
+                res => break res.map(Acquired::into_guard),
+            }
+        };
+
+        metric::record(&self.state, &res, || clock::now() - start);
+        res
+    }
+
+    /// Lock named lock, unless it is interrupted by `interrupter`.
+    ///
+    /// This works like [NamedLock::lock], but another thread can make it
+    /// return [Error::Interrupted] with [LockInterrupter::interrupt]. See
+    /// [LockInterrupter] for the signal handling that this requires.
+    ///
+    /// Like [NamedLock::lock], this returns `Error::WouldDeadlock` if the
+    /// current thread holds the lock. After the attempts of
+    /// [NamedLock::set_max_poll_attempts] it returns `Error::WouldBlock`.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn lock_interruptible(
+        &self,
+        interrupter: &LockInterrupter,
+    ) -> Result<NamedLockGuard> {
+        if self.is_held_by_current_thread() {
+            return Err(Error::WouldDeadlock);
+        }
+
+        let _registration = interrupter.register();
+        let start = clock::now();
+        let mut attempts = 0;
+
+        // The in-process layer can not be interrupted by a signal, so it
+        // is polled instead.
+        let guard = loop {
+            if interrupter.is_interrupted() {
+                return Err(Error::Interrupted);
+            }
+
+            if !self.poll_attempt(&mut attempts) {
+                metric::would_block(&self.state);
+                return Err(Error::WouldBlock);
+            }
+
+            if let Some(guard) =
+                self.raw.try_lock_arc_for(interrupt::RETRY_INTERVAL)
+            {
+                break guard;
+            }
+        };
+
+        if self.max_polls.is_none() {
+            guard.lock_interruptible(interrupter.flag())?;
+        } else {
+            // The blocking `flock` could wait forever, so it is tried
+            // instead, as often as the attempts allow.
+            loop {
+                if interrupter.is_interrupted() {
+                    return Err(Error::Interrupted);
+                }
+
+                match guard.try_lock() {
+                    Err(Error::WouldBlock) => {}
+                    res => break res.map(drop)?,
+                }
+
+                if !self.poll_attempt(&mut attempts) {
+                    metric::would_block(&self.state);
+                    return Err(Error::WouldBlock);
+                }
+
+                clock::sleep(interrupt::RETRY_INTERVAL);
+            }
+        }
+
+        metric::acquired(&self.state, clock::now() - start);
+
+        Ok(NamedLockGuard::new(guard, &self.state))
+    }
+
+    /// Acquire named lock and report how the acquisition went.
+    ///
+    /// [NamedLock::lock] and [NamedLock::try_lock] are shortcuts for
+    /// [AcquireMode::Blocking] and [AcquireMode::NonBlocking] respectively.
+    ///
+    /// # Notes
+    ///
+    /// * On UNIX there is no native timeout for [`flock`], so
+    ///   [AcquireMode::Timeout] polls the lock until it is acquired or the
+    ///   timeout expires.
+    ///
+    /// [`flock`]: https://linux.die.net/man/2/flock
+    pub fn acquire(&self, mode: AcquireMode) -> Result<Acquired> {
+        let res = self.acquire_once(mode);
+        metric::record(&self.state, &res, || {
+            res.as_ref().map_or(Duration::ZERO, Acquired::wait_duration)
+        });
+        res
+    }
+
+    // `acquire` without recording metrics, for polling in a loop.
+    fn acquire_once(&self, mode: AcquireMode) -> Result<Acquired> {
+        let start = clock::now();
+
+        // Both layers are tried without blocking first, so we can tell if
+        // the lock was contended.
+        let (guard, mut contended) = match self.raw.try_lock_arc() {
+            Some(guard) => (guard, false),
+            None => match mode {
+                AcquireMode::Blocking => {
+                    if self.is_held_by_current_thread() {
+                        return Err(Error::WouldDeadlock);
+                    }
+
+                    (self.raw.lock_arc(), true)
+                }
+                AcquireMode::NonBlocking => return Err(Error::WouldBlock),
+                AcquireMode::Timeout(timeout) => {
+                    let guard = self
+                        .raw
+                        .try_lock_arc_for(timeout)
+                        .ok_or(Error::WouldBlock)?;
+                    (guard, true)
+                }
+            },
+        };
+
+        let abandoned = match guard.try_lock() {
+            Err(Error::WouldBlock) => {
+                contended = true;
+
+                match mode {
+                    AcquireMode::Blocking if !self.jitter.is_zero() => {
+                        clock::sleep(retry::jitter(self.jitter));
+
+                        match guard.try_lock() {
+                            Err(Error::WouldBlock) => guard.lock()?,
+                            res => res?,
+                        }
+                    }
+                    AcquireMode::Blocking => guard.lock()?,
+                    AcquireMode::NonBlocking => return Err(Error::WouldBlock),
+                    AcquireMode::Timeout(timeout) => guard.try_lock_for(
+                        timeout.saturating_sub(clock::now() - start),
+                    )?,
+                }
+            }
+            res => res?,
+        };
+
+        let guard = NamedLockGuard::new(guard, &self.state);
+
+        Ok(Acquired {
+            guard: self.check_abandoned(guard, abandoned)?,
+            contended,
+            wait: clock::now() - start,
+            abandoned,
+        })
+    }
+}
+
+/// Weak reference to a [NamedLock].
+///
+/// This works like [`std::sync::Weak`]: it does not keep the underlying OS
+/// lock open. When all [NamedLock]s and guards of this process are dropped,
+/// the lock is closed and [WeakNamedLock::upgrade] returns `None`.
+#[derive(Debug, Clone)]
+pub struct WeakNamedLock {
+    raw: Weak<Mutex<RawNamedLock>>,
+    state: Arc<LockState>,
+    abandoned: AbandonedPolicy,
+    grace: Duration,
+    jitter: Duration,
+    max_polls: Option<u32>,
+}
+
+impl WeakNamedLock {
+    /// Try to get a [NamedLock] back, if the lock is still open.
+    pub fn upgrade(&self) -> Option<NamedLock> {
+        self.raw.upgrade().map(|raw| NamedLock {
+            raw,
+            state: self.state.clone(),
+            abandoned: self.abandoned.clone(),
+            grace: self.grace,
+            jitter: self.jitter,
+            max_polls: self.max_polls,
+        })
+    }
+}
+
+/// Named lock that is opened on first use.
+///
+/// Created by [NamedLock::lazy].
+#[derive(Debug, Clone)]
+pub struct LazyNamedLock {
+    lock: OnceCell<NamedLock>,
+    name: NameType,
+}
+
+impl LazyNamedLock {
+    /// Returns the lock if it is already opened.
+    pub fn get(&self) -> Option<&NamedLock> {
+        self.lock.get()
+    }
+
+    /// Open the lock if it is not opened yet, and return it.
+    pub fn open(&self) -> Result<&NamedLock> {
+        self.lock.get_or_try_init(|| {
+            NamedLock::_create(self.name.clone(), &NamedLockOptions::new())
+        })
+    }
+
+    /// Try to lock named lock, opening it first if needed.
+    ///
+    /// See [NamedLock::try_lock].
+    pub fn try_lock(&self) -> Result<NamedLockGuard> {
+        self.open()?.try_lock()
+    }
+
+    /// Lock named lock, opening it first if needed.
+    ///
+    /// See [NamedLock::lock].
+    pub fn lock(&self) -> Result<NamedLockGuard> {
+        self.open()?.lock()
+    }
+}
+
+/// Token that keeps a [NamedLock] open, for passing it to tasks.
+///
+/// Created by [NamedLock::downgrade_handle]. It is cheap to clone and it
+/// can be sent to other threads, where [LockHandle::to_lock] gives back
+/// the lock with the same settings.
+#[derive(Debug, Clone)]
+pub struct LockHandle {
+    raw: Arc<Mutex<RawNamedLock>>,
+    state: Arc<LockState>,
+    abandoned: AbandonedPolicy,
+    grace: Duration,
+    jitter: Duration,
+    max_polls: Option<u32>,
+}
+
+impl LockHandle {
+    /// Get the [NamedLock] back.
+    pub fn to_lock(&self) -> NamedLock {
+        NamedLock {
+            raw: self.raw.clone(),
+            state: self.state.clone(),
+            abandoned: self.abandoned.clone(),
+            grace: self.grace,
+            jitter: self.jitter,
+            max_polls: self.max_polls,
+        }
+    }
+}
+
+/// Determines what happens when a lock that was abandoned is acquired.
+///
+/// On Windows a mutex is abandoned when the thread that owns it exits
+/// without releasing it, e.g. because the process crashed. The next owner
+/// gets the mutex, but the state it protects may be inconsistent.
+///
+/// Set with [NamedLock::set_abandoned_policy].
+#[derive(Clone, Default)]
+pub enum AbandonedPolicy {
+    /// Acquire the lock as if nothing happened.
+    ///
+    /// [Acquired::abandoned] still tells that the lock was abandoned.
+    #[default]
+    Accept,
+    /// Release the lock again and return `Error::Abandoned`.
+    ///
+    /// The lock is not reported as abandoned anymore, so the next attempt
+    /// succeeds.
+    Error,
+    /// Call the function with the guard before returning it, for example
+    /// to reset the protected state.
+    ///
+    /// If the function returns an error, the lock is released and the
+    /// error is returned.
+    Recover(Arc<RecoverFn>),
+}
+
+type RecoverFn = dyn Fn(&NamedLockGuard) -> Result<()> + Send + Sync;
+
+impl fmt::Debug for AbandonedPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AbandonedPolicy::Accept => f.write_str("Accept"),
+            AbandonedPolicy::Error => f.write_str("Error"),
+            AbandonedPolicy::Recover(_) => f.write_str("Recover(..)"),
+        }
+    }
+}
+
+/// Determines how [NamedLock::acquire] waits for the lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcquireMode {
+    /// Block until the lock is acquired.
+    Blocking,
+    /// Return `Error::WouldBlock` if the lock is already locked.
+    NonBlocking,
+    /// Block for at most the given duration, then return `Error::WouldBlock`.
+    Timeout(Duration),
+}
+
+/// OS mechanism of a [NamedLock], returned by [NamedLock::backend].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Backend {
+    /// [`flock`] on a lock file, used on UNIX.
+    ///
+    /// [`flock`]: https://linux.die.net/man/2/flock
+    Flock,
+    /// A named mutex from [`CreateMutexW`], used on Windows.
+    ///
+    /// [`CreateMutexW`]: https://learn.microsoft.com/en-us/windows/win32/api/synchapi/nf-synchapi-createmutexw
+    NamedMutex,
+    /// A ticket lock in front of a named mutex, used on Windows with
+    /// `NamedLockOptions::ticket_lock`.
+    TicketLock,
+}
+
+impl Backend {
+    /// Returns `true` if locks of this backend exclude other processes,
+    /// not only other threads of the current process.
+    ///
+    /// Every backend of this crate is cross-process for now.
+    pub fn is_cross_process(self) -> bool {
+        match self {
+            Backend::Flock | Backend::NamedMutex | Backend::TicketLock => true,
+        }
+    }
+}
+
+/// Reason of a failed [NamedLock::try_lock_diagnostic].
+#[derive(Debug)]
+pub enum Contention {
+    /// The lock is held by this process.
+    SameProcess,
+    /// The lock is held by another process.
+    OtherProcess,
+    /// Locking failed for another reason.
+    Failed(Error),
+}
+
+/// Holder of a lock, as reported by [NamedLock::try_lock_or_holder].
+#[derive(Debug)]
+pub enum LockHolder {
+    /// Process ID of the holder.
+    Pid(u32),
+    /// The lock is held, but the holder is not known.
+    Unknown,
+    /// Locking failed for another reason.
+    Failed(Error),
+}
+
+/// Snapshot of the state of a lock, as reported by [NamedLock::status].
+///
+/// The `Display` implementation gives a readable report with one field per
+/// line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockStatus {
+    name: NameType,
+    exists: bool,
+    held: bool,
+    holder: Option<u32>,
+    expired: bool,
+}
+
+impl LockStatus {
+    /// Path of the lock file.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn path(&self) -> &Path {
+        &self.name
+    }
+
+    /// Name of the mutex.
+    #[cfg(windows)]
+    #[cfg_attr(docsrs, doc(cfg(windows)))]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns `true` if the lock file (or mutex) exists.
+    pub fn exists(&self) -> bool {
+        self.exists
+    }
+
+    /// Returns `true` if the lock is held, by this or another process.
+    pub fn is_held(&self) -> bool {
+        self.held
+    }
+
+    /// Process ID of the holder, if the lock is held and the holder
+    /// recorded itself with [NamedLockOptions::record_holder].
+    pub fn holder_pid(&self) -> Option<u32> {
+        self.holder
+    }
+
+    /// Returns `true` if the lock is held and the holder has exceeded its
+    /// lease, so it may be stuck. See [NamedLock::is_expired].
+    pub fn is_expired(&self) -> bool {
+        self.expired
+    }
+}
+
+impl fmt::Display for LockStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let yes_no = |b| {
+            if b {
+                "yes"
+            } else {
+                "no"
+            }
+        };
+
+        #[cfg(unix)]
+        writeln!(f, "path: {}", self.name.display())?;
+        #[cfg(windows)]
+        writeln!(f, "name: {}", self.name)?;
+        writeln!(f, "exists: {}", yes_no(self.exists))?;
+        writeln!(f, "held: {}", yes_no(self.held))?;
+
+        match self.holder {
+            Some(pid) => writeln!(f, "holder: {}", pid)?,
+            None => writeln!(f, "holder: unknown")?,
+        }
+
+        write!(f, "expired: {}", yes_no(self.expired))
+    }
+}
+
+/// Outcome of a successful [NamedLock::acquire].
+#[derive(Debug)]
+pub struct Acquired {
+    guard: NamedLockGuard,
+    contended: bool,
+    wait: Duration,
+    abandoned: bool,
+}
+
+impl Acquired {
+    /// Guard of the acquired lock.
+    pub fn guard(&self) -> &NamedLockGuard {
+        &self.guard
+    }
+
+    /// Consume the outcome and return the guard.
+    pub fn into_guard(self) -> NamedLockGuard {
+        self.guard
+    }
+
+    /// Returns `true` if the lock was held by someone else when the
+    /// acquisition started.
+    pub fn contended(&self) -> bool {
+        self.contended
+    }
+
+    /// Time spent waiting for the lock.
+    pub fn wait_duration(&self) -> Duration {
+        self.wait
+    }
+
+    /// Returns `true` if the previous owner exited without unlocking.
+    ///
+    /// This can only happen on Windows. On UNIX the lock is released
+    /// by the OS when the owner exits, so this is always `false`.
+    pub fn abandoned(&self) -> bool {
+        self.abandoned
+    }
+}
+
+#[cfg(unix)]
+fn lock_dir_source() -> TempDirSource {
+    let override_var = LOCK_DIR_VAR.lock().clone();
+    resolve_lock_dir(override_var.as_deref(), |var| std::env::var_os(var))
+}
+
+#[cfg(unix)]
+fn resolve_lock_dir<F>(
+    override_var: Option<&OsStr>,
+    get_var: F,
+) -> TempDirSource
+where
+    F: Fn(&OsStr) -> Option<OsString>,
+{
+    // An empty variable would resolve lock files relative to the current
+    // directory, so it counts as unset.
+    let get_var = |var: &OsStr| get_var(var).filter(|dir| !dir.is_empty());
+
+    if let Some(dir) = override_var.and_then(get_var) {
+        TempDirSource::Override(dir.into())
+    } else if let Some(dir) = get_var(OsStr::new("TMPDIR")) {
+        TempDirSource::TmpDirEnv(dir.into())
+    } else {
+        TempDirSource::FallbackTmp
+    }
+}
+
+// Resolves `names`, then sorts them and removes duplicates.
+fn sorted_names(names: &[&str]) -> Result<Vec<LockName>> {
+    let mut names = names
+        .iter()
+        .map(|name| LockName::new(name))
+        .collect::<Result<Vec<_>>>()?;
+    names.sort_unstable();
+    names.dedup();
+
+    Ok(names)
+}
+
+// Returns the lock and the state of the entry of `key` in `OPENED_RAW_LOCKS`,
+// if the lock is still open.
+fn opened_lock(
+    opened_locks: &HashMap<NameType, OpenedLock>,
+    key: &NameType,
+) -> Option<(Arc<Mutex<RawNamedLock>>, Arc<LockState>)> {
+    let opened = opened_locks.get(key)?;
+    Some((opened.raw.upgrade()?, opened.state.clone()))
+}
+
+// Returns the key of `name` in `OPENED_RAW_LOCKS`.
+//
+// On UNIX different paths can refer to the same file (`.` or `..`
+// components, symlinks, etc). They must share the same entry, otherwise
+// the edge cases described above are not handled, so we canonicalize the
+// path. If the lock file does not exist yet, its parent is canonicalized
+// instead.
+#[cfg(unix)]
+fn registry_key(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+
+    let parent = match path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+        Some(parent) => parent,
+        None => return path.to_owned(),
+    };
+
+    match (parent.canonicalize(), path.file_name()) {
+        (Ok(parent), Some(file_name)) => parent.join(file_name),
+        _ => path.to_owned(),
+    }
+}
+
+#[cfg(windows)]
+fn registry_key(name: &str) -> String {
+    name.to_owned()
+}
+
+// Returns `/dev/shm` if lock files can be created in it.
+#[cfg(target_os = "linux")]
+fn shm_dir() -> Option<&'static Path> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = Path::new("/dev/shm");
+    let c_dir = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+
+    let writable = unsafe { libc::access(c_dir.as_ptr(), libc::W_OK) } == 0;
+    (dir.is_dir() && writable).then_some(dir)
+}
+
+#[cfg(unix)]
+fn lock_path_in(dir: &Path, name: &str) -> Result<PathBuf> {
+    validate_name(name)?;
+    Ok(dir.join(format!("{}.lock", name)))
+}
+
+#[cfg(unix)]
+fn lock_path_in_os(dir: &Path, name: &OsStr) -> Result<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let bytes = name.as_bytes();
+
+    if bytes.is_empty() {
+        return Err(Error::EmptyName);
+    }
+
+    // All reserved characters are ASCII, so they are single bytes.
+    if bytes.iter().any(|&b| RESERVED_CHARS.contains(&char::from(b))) {
+        return Err(Error::InvalidCharacter);
+    }
+
+    let mut file_name = name.to_owned();
+    file_name.push(".lock");
+
+    Ok(dir.join(file_name))
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() {
+        return Err(Error::EmptyName);
+    }
+
+    // On UNIX we want to restrict the user on `/tmp` directory,
+    // so we block the `/` character.
+    //
+    // On Windows `\` character is invalid.
+    //
+    // Both platforms expect null-terminated strings,
+    // so we block null-bytes.
+    if name.contains(RESERVED_CHARS) || name.contains(NAME_SEPARATOR) {
+        return Err(Error::InvalidCharacter);
+    }
+
+    Ok(())
+}
+
+fn resolved_name_of_kind(name: &str, kind: LockKind) -> Result<NameType> {
+    match kind {
+        LockKind::Machine => NamedLock::resolved_name(name),
+        #[cfg(unix)]
+        LockKind::Session => {
+            validate_name(name)?;
+            let dir = session_lock_dir(|var| std::env::var_os(var))?;
+            lock_path_in(&dir, name)
+        }
+        #[cfg(windows)]
+        LockKind::Session => {
+            validate_name(name)?;
+            Ok(format!("Local\\{}", name))
+        }
+    }
+}
+
+#[cfg(unix)]
+fn session_lock_dir<F>(get_var: F) -> Result<PathBuf>
+where
+    F: Fn(&OsStr) -> Option<OsString>,
+{
+    get_var(OsStr::new("XDG_RUNTIME_DIR"))
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .ok_or_else(|| {
+            Error::CreateFailed(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "XDG_RUNTIME_DIR is not set",
+            ))
+        })
+}
+
+// Name of the lock that `NamedLock::for_path` uses for `target`.
+fn path_lock_name(target: &std::path::Path) -> Result<String> {
+    let path = match target.canonicalize() {
+        Ok(path) => path,
+        Err(e) => {
+            let parent = match target.parent() {
+                Some(parent) if parent.as_os_str().is_empty() => ".".as_ref(),
+                Some(parent) => parent,
+                None => return Err(Error::Io(e)),
+            };
+            let file_name = target.file_name().ok_or(Error::Io(e))?;
+
+            parent.canonicalize().map_err(Error::Io)?.join(file_name)
+        }
+    };
+
+    #[cfg(unix)]
+    let hash = {
+        use std::os::unix::ffi::OsStrExt;
+        fnv1a_128(path.as_os_str().as_bytes().iter().copied())
+    };
+    #[cfg(windows)]
+    let hash = {
+        use std::os::windows::ffi::OsStrExt;
+        fnv1a_128(path.as_os_str().encode_wide().flat_map(u16::to_le_bytes))
+    };
+
+    Ok(format!("path-{:032x}", hash))
+}
+
+fn fnv1a_128<I>(bytes: I) -> u128
+where
+    I: IntoIterator<Item = u8>,
+{
+    const OFFSET_BASIS: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013b;
+
+    bytes.into_iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u128::from(byte)).wrapping_mul(PRIME)
+    })
+}
+
+// Name of the lock that `NamedLock::create_per_user` uses for `user`.
+fn per_user_name(name: &str, user: &str) -> String {
+    format!("{}{}user-{}", name, NAME_SEPARATOR, encode_component(user))
+}
+
+#[cfg(unix)]
+fn current_user_id() -> Result<String> {
+    Ok(unsafe { libc::getuid() }.to_string())
+}
+
+#[cfg(windows)]
+fn current_user_id() -> Result<String> {
+    crate::windows::current_user_sid().map_err(Error::Io)
+}
+
+// Percent-encode a component so it can be safely embedded in a lock name.
+//
+// Besides the reserved characters we also encode `%` and `@`, which makes
+// the encoding reversible and lets us use `@` as a separator.
+fn encode_component(component: &str) -> String {
+    let mut encoded = String::with_capacity(component.len());
+
+    for c in component.chars() {
+        if RESERVED_CHARS.contains(&c) || matches!(c, '%' | '@') {
+            encoded.push_str(&format!("%{:02X}", c as u32));
+        } else {
+            encoded.push(c);
+        }
+    }
+
+    encoded
+}
+
+fn holder_key(raw: &Mutex<RawNamedLock>) -> usize {
+    raw.data_ptr() as usize
+}
+
+/// Scoped guard that unlocks NamedLock.
+pub struct NamedLockGuard {
+    // Released in `release_mutex`, fairly or not.
+    raw: ManuallyDrop<ArcMutexGuard<RawMutex, RawNamedLock>>,
+    state: Arc<LockState>,
+    fair: bool,
+}
+
+impl NamedLockGuard {
+    fn new(
+        raw: ArcMutexGuard<RawMutex, RawNamedLock>,
+        state: &Arc<LockState>,
+    ) -> NamedLockGuard {
+        *state.holder.lock() = Some(thread::current().id());
+
+        NamedLockGuard {
+            raw: ManuallyDrop::new(raw),
+            state: state.clone(),
+            fair: false,
+        }
+    }
+
+    // Called before the in-process mutex is released.
+    fn released(&self) {
+        *self.state.holder.lock() = None;
+    }
+
+    /// Unlock the named lock and report any error.
+    ///
+    /// Dropping the guard also unlocks, but errors are ignored.
+    ///
+    /// # Windows
+    ///
+    /// A mutex can only be released by the thread that locked it, so
+    /// unlocking a guard that was sent to another thread returns
+    /// `Error::WrongThread`. In that case the mutex stays owned by the
+    /// locking thread until it exits, after which the next owner sees it
+    /// as abandoned.
+    pub fn unlock(self) -> Result<()> {
+        let mut this = ManuallyDrop::new(self);
+        let res = this.raw.unlock();
+        this.released();
+
+        // SAFETY: `this` is never used or dropped again, so the mutex is
+        // released exactly once.
+        unsafe { this.release_mutex() };
+
+        res
+    }
+
+    /// Hand the lock over fairly to the threads of this process that wait
+    /// for it, when this guard is dropped or unlocked.
+    ///
+    /// By default a thread that unlocks can lock again right away, even if
+    /// other threads are waiting, which is faster but can starve them. With
+    /// a fair unlock the in-process mutex is handed to the next waiting
+    /// thread instead (see [`parking_lot::Mutex`]).
+    ///
+    /// # Notes
+    ///
+    /// * This only affects the order of threads within this process. Other
+    ///   processes compete for the OS lock as usual.
+    /// * Without the `parking_lot` feature the in-process mutex has no fair
+    ///   unlock, so this has no effect.
+    ///
+    /// [`parking_lot::Mutex`]: https://docs.rs/parking_lot/latest/parking_lot/type.Mutex.html
+    pub fn set_unlock_fair(&mut self, fair: bool) {
+        self.fair = fair;
+    }
+
+    // Releases the in-process mutex.
+    //
+    // SAFETY: Must be called exactly once, after which `raw` must not be
+    // used anymore.
+    unsafe fn release_mutex(&mut self) {
+        let raw = ManuallyDrop::take(&mut self.raw);
+
+        if self.fair {
+            ArcMutexGuard::unlock_fair(raw);
+        } else {
+            drop(raw);
+        }
+    }
+
+    /// Returns the path of the lock file that this guard holds.
+    ///
+    /// This is the path that the lock was created with, e.g. the result of
+    /// [NamedLock::resolved_name] for [NamedLock::create].
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn path(&self) -> &Path {
+        self.raw.path()
+    }
+
+    /// Returns the mutex name of the lock that this guard holds.
+    ///
+    /// This is the name that the mutex was created with, e.g. the result of
+    /// [NamedLock::resolved_name] for [NamedLock::create].
+    #[cfg(windows)]
+    #[cfg_attr(docsrs, doc(cfg(windows)))]
+    pub fn name(&self) -> &str {
+        self.raw.name()
+    }
+
+    /// Returns the lock file.
+    ///
+    /// This can be used to read or write content that is protected by the
+    /// lock, without opening the file again. The file is opened only for
+    /// writing unless [NamedLockOptions::read] is set.
+    ///
+    /// Only UNIX has this method, on Windows the lock is a mutex and there
+    /// is no file.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn file(&self) -> &std::fs::File {
+        self.raw.file()
+    }
+
+    /// Bundle the guard with `data` that it protects.
+    ///
+    /// The returned [GuardWith] dereferences to `data` and unlocks the
+    /// named lock when it is dropped. `data` is dropped before the lock is
+    /// released.
+    pub fn with_data<T>(self, data: T) -> GuardWith<T> {
+        GuardWith {
+            data,
+            guard: self,
+        }
+    }
+}
+
+impl Drop for NamedLockGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.raw.unlock() {
+            let raw = ArcMutexGuard::mutex(&self.raw);
+            UNLOCK_ERRORS
+                .lock()
+                .insert(holder_key(raw), (Arc::downgrade(raw), e));
+        }
+
+        self.released();
+
+        // SAFETY: The guard is being dropped, so `raw` is never used again.
+        unsafe { self.release_mutex() };
+    }
+}
+
+impl fmt::Debug for NamedLockGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NamedLockGuard").field("raw", &**self.raw).finish()
+    }
+}
+
+/// [NamedLockGuard] bundled with data that it protects.
+///
+/// Created by [NamedLockGuard::with_data].
+#[derive(Debug)]
+pub struct GuardWith<T> {
+    // Fields are dropped in order, so `data` is dropped while the lock is
+    // still held.
+    data: T,
+    guard: NamedLockGuard,
+}
+
+impl<T> GuardWith<T> {
+    /// Returns the guard.
+    pub fn guard(&self) -> &NamedLockGuard {
+        &self.guard
+    }
+
+    /// Split into the guard and the data.
+    pub fn into_parts(self) -> (NamedLockGuard, T) {
+        (self.guard, self.data)
+    }
+}
+
+impl<T> Deref for GuardWith<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.data
+    }
+}
+
+impl<T> DerefMut for GuardWith<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use static_assertions::assert_impl_all;
+    use std::env;
+    use std::fmt::{Debug, Display};
+    use std::process::{Child, Command};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+    use uuid::Uuid;
+
+    fn call_proc(test: &str, num: u32, uuid: &str) -> Child {
+        let exe = env::current_exe().expect("no exe");
+        let mut cmd = Command::new(exe);
+
+        cmd.env("TEST_CROSS_PROCESS_LOCK_PROC_NUM", num.to_string())
+            .env("TEST_CROSS_PROCESS_LOCK_UUID", uuid)
+            .arg(test)
+            .spawn()
+            .unwrap()
+    }
+
+    fn call_proc_num(num: u32, uuid: &str) -> Child {
+        call_proc("tests::cross_process_lock", num, uuid)
+    }
+
+    fn proc_num_and_uuid() -> (u32, String) {
+        let proc_num = env::var("TEST_CROSS_PROCESS_LOCK_PROC_NUM")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        let uuid = env::var("TEST_CROSS_PROCESS_LOCK_UUID")
+            .unwrap_or_else(|_| Uuid::new_v4().as_hyphenated().to_string());
+
+        (proc_num, uuid)
+    }
+
+    #[test]
+    fn cross_process_lock() -> Result<()> {
+        let (proc_num, uuid) = proc_num_and_uuid();
+
+        match proc_num {
+            0 => {
+                let mut handle1 = call_proc_num(1, &uuid);
+                sleep(Duration::from_millis(100));
+
+                let mut handle2 = call_proc_num(2, &uuid);
+                sleep(Duration::from_millis(200));
+
+                let lock =
+                    NamedLock::create(&uuid).expect("failed to create lock");
+                assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+                lock.lock().expect("failed to lock");
+
+                assert!(handle2.wait().unwrap().success());
+                assert!(handle1.wait().unwrap().success());
+            }
+            1 => {
+                let lock =
+                    NamedLock::create(&uuid).expect("failed to create lock");
+
+                let _guard = lock.lock().expect("failed to lock");
+                assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+                sleep(Duration::from_millis(200));
+            }
+            2 => {
+                let lock =
+                    NamedLock::create(&uuid).expect("failed to create lock");
+
+                assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+                let _guard = lock.lock().expect("failed to lock");
+                sleep(Duration::from_millis(300));
+            }
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn claim_election() {
+        const CONTENDERS: u32 = 4;
+        const NOT_LEADER: i32 = 2;
+
+        let (proc_num, uuid) = proc_num_and_uuid();
+        let start_name = format!("{}-start", uuid);
+
+        if proc_num == 0 {
+            // Hold the start lock so all contenders race at the same time.
+            let start = NamedLock::create(&start_name).unwrap();
+            let start_guard = start.lock().unwrap();
+
+            let handles = (1..=CONTENDERS)
+                .map(|num| call_proc("tests::claim_election", num, &uuid))
+                .collect::<Vec<_>>();
+
+            sleep(Duration::from_millis(200));
+            drop(start_guard);
+
+            let leaders = handles
+                .into_iter()
+                .map(|mut handle| handle.wait().unwrap())
+                .filter(|status| {
+                    assert!(
+                        status.success() || status.code() == Some(NOT_LEADER)
+                    );
+                    status.success()
+                })
+                .count();
+
+            assert_eq!(leaders, 1);
+        } else {
+            drop(NamedLock::create(&start_name).unwrap().lock().unwrap());
+
+            match NamedLock::claim(&uuid) {
+                Ok(_leader) => sleep(Duration::from_millis(1000)),
+                Err(Error::WouldBlock) => std::process::exit(NOT_LEADER),
+                Err(e) => panic!("failed to claim: {}", e),
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn lock_and_init() {
+        const CONTENDERS: u32 = 4;
+        const NOT_INITIALIZED: i32 = 2;
+
+        let (proc_num, uuid) = proc_num_and_uuid();
+        let start_name = format!("{}-start", uuid);
+
+        if proc_num == 0 {
+            // An initialization that fails is tried again.
+            let lock = NamedLock::create(&uuid).unwrap();
+            assert!(matches!(
+                lock.lock_and_init(|| Err(Error::Cancelled)),
+                Err(Error::Cancelled)
+            ));
+
+            // Hold the start lock so all contenders race at the same time.
+            let start = NamedLock::create(&start_name).unwrap();
+            let start_guard = start.lock().unwrap();
+
+            let handles = (1..=CONTENDERS)
+                .map(|num| call_proc("tests::lock_and_init", num, &uuid))
+                .collect::<Vec<_>>();
+
+            sleep(Duration::from_millis(200));
+            drop(start_guard);
+
+            let initialized = handles
+                .into_iter()
+                .map(|mut handle| handle.wait().unwrap())
+                .filter(|status| {
+                    assert!(
+                        status.success()
+                            || status.code() == Some(NOT_INITIALIZED)
+                    );
+                    status.success()
+                })
+                .count();
+
+            assert_eq!(initialized, 1);
+            assert!(!lock.lock_and_init(|| unreachable!()).unwrap());
+        } else {
+            drop(NamedLock::create(&start_name).unwrap().lock().unwrap());
+
+            let lock = NamedLockOptions::new()
+                .record_holder(true)
+                .create(&uuid)
+                .unwrap();
+            let ran = lock
+                .lock_and_init(|| {
+                    sleep(Duration::from_millis(100));
+                    Ok(())
+                })
+                .unwrap();
+
+            if !ran {
+                std::process::exit(NOT_INITIALIZED);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn lock_with_version() -> Result<()> {
+        let (proc_num, uuid) = proc_num_and_uuid();
+        let lock = NamedLock::create(&uuid)?;
+        let strict = |version| move |v| v == version;
+
+        match proc_num {
+            0 => {
+                // The first version records itself.
+                let mut handle =
+                    call_proc("tests::lock_with_version", 1, &uuid);
+                assert!(handle.wait().unwrap().success());
+
+                // The second version is rejected by the first one, unless
+                // it can take over from it.
+                assert!(matches!(
+                    lock.lock_with_version(2, strict(2)),
+                    Err(Error::IncompatibleVersion(1))
+                ));
+                drop(lock.lock_with_version(2, |v| v <= 2)?);
+
+                // Now the first version is rejected by the second one.
+                let mut handle =
+                    call_proc("tests::lock_with_version", 2, &uuid);
+                assert!(handle.wait().unwrap().success());
+
+                drop(lock.lock_with_version(2, strict(2))?);
+                let _guard = lock.try_lock()?;
+            }
+            1 => drop(lock.lock_with_version(1, strict(1))?),
+            2 => assert!(matches!(
+                lock.lock_with_version(1, strict(1)),
+                Err(Error::IncompatibleVersion(2))
+            )),
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn reset_registry() -> Result<()> {
+        let (proc_num, uuid) = proc_num_and_uuid();
+
+        // Resetting affects the locks of every test, so it is done in a
+        // separate process, where no other test runs.
+        if proc_num == 0 {
+            let mut handle = call_proc("tests::reset_registry", 1, &uuid);
+            assert!(handle.wait().unwrap().success());
+            return Ok(());
+        }
+
+        let registered = |name: &Path| {
+            OPENED_RAW_LOCKS.lock().contains_key(&registry_key(name))
+        };
+
+        // Closed locks are removed, open ones are kept.
+        let closed = NamedLock::create(&format!("{}-closed", uuid))?;
+        let closed_name = closed.name();
+        drop(closed);
+        let lock1 = NamedLock::create(&uuid)?;
+        NamedLock::reset_registry(false);
+        assert!(!registered(&closed_name));
+        assert!(registered(&lock1.name()));
+        assert_eq!(NamedLock::create(&uuid)?.handle_count(), 2);
+
+        // Replace the lock file while it is held.
+        let _guard = lock1.lock()?;
+        std::fs::remove_file(lock1.name()).unwrap();
+        assert!(matches!(
+            NamedLock::create(&uuid)?.try_lock(),
+            Err(Error::WouldBlock)
+        ));
+
+        // After detaching, the new file is opened.
+        NamedLock::reset_registry(true);
+        assert!(!registered(&lock1.name()));
+        let lock2 = NamedLock::create(&uuid)?;
+        assert_eq!(lock2.handle_count(), 1);
+        lock2.try_lock()?;
+        assert!(lock1.name().exists());
+
+        std::fs::remove_file(lock1.name()).unwrap();
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn max_open_files() -> Result<()> {
+        let (proc_num, uuid) = proc_num_and_uuid();
+
+        // The limits are per process, so they are changed in a separate
+        // process, where no other test runs.
+        if proc_num == 0 {
+            let mut handle = call_proc("tests::max_open_files", 1, &uuid);
+            assert!(handle.wait().unwrap().success());
+            return Ok(());
+        }
+
+        // Leave room for 8 more descriptors.
+        let open_fds = std::fs::read_dir("/dev/fd").unwrap().count() as u64;
+        let limit = libc::rlimit {
+            rlim_cur: open_fds + 8,
+            rlim_max: open_fds + 8,
+        };
+        assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) }, 0);
+
+        let create = |i| NamedLock::create(&format!("{}-{}", uuid, i));
+
+        // Without a limit, the descriptors run out.
+        let locks = (0..16).map(create).collect::<Vec<_>>();
+        assert!(locks
+            .iter()
+            .any(|lock| matches!(lock, Err(Error::TooManyOpenFiles))));
+        drop(locks);
+
+        NamedLock::set_max_open_files(Some(4));
+        let locks = (0..32).map(create).collect::<Result<Vec<_>>>()?;
+        assert_eq!(fd_limit::open_count(), 4);
+
+        // Closed files are opened again, closing the least recently used.
+        for lock in locks.iter().chain(&locks) {
+            let _guard = lock.try_lock()?;
+            assert!(fd_limit::open_count() <= 4);
+        }
+
+        // Held locks are not closed.
+        let guards = locks[..4]
+            .iter()
+            .map(|lock| lock.try_lock())
+            .collect::<Result<Vec<_>>>()?;
+        assert!(matches!(locks[4].try_lock(), Err(Error::TooManyOpenFiles)));
+        drop(guards);
+        locks[4].try_lock()?;
+
+        for i in 0..32 {
+            let _ = std::fs::remove_file(NamedLock::resolved_name(&format!(
+                "{}-{}",
+                uuid, i
+            ))?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn reinit_after_fork() -> Result<()> {
+        let (proc_num, uuid) = proc_num_and_uuid();
+
+        // Forking is done in a separate process, where no other test runs.
+        if proc_num == 0 {
+            let mut handle = call_proc("tests::reinit_after_fork", 1, &uuid);
+            assert!(handle.wait().unwrap().success());
+            return Ok(());
+        }
+
+        let lock = NamedLock::create(&uuid)?;
+        let fork = |child: &dyn Fn() -> Result<()>| unsafe {
+            let pid = libc::fork();
+            assert!(pid >= 0);
+
+            if pid == 0 {
+                libc::_exit(if child().is_ok() {
+                    0
+                } else {
+                    1
+                });
+            }
+
+            pid
+        };
+        let wait = |pid| {
+            let mut status = 0;
+            assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+            assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+        };
+        let held = || RawNamedLock::probe(&lock.name()).unwrap();
+
+        // Without reopening, the child shares the lock of the parent and can
+        // release it.
+        let guard = lock.lock()?;
+        wait(fork(&|| {
+            if !matches!(lock.reinit_after_fork(), Err(Error::WouldBlock)) {
+                return Err(Error::LockFailed(None));
+            }
+
+            // This is a copy of the guard of the parent.
+            let guard = unsafe { std::ptr::read(&guard) };
+            guard.unlock()
+        }));
+        assert_eq!(held(), Some(false));
+        drop(guard);
+
+        // After reopening, the child has its own lock.
+        let pid = fork(&|| {
+            lock.reinit_after_fork()?;
+            let _guard = lock.lock()?;
+            sleep(Duration::from_millis(300));
+            Ok(())
+        });
+        sleep(Duration::from_millis(100));
+        assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+        wait(pid);
+        lock.try_lock()?;
+
+        // Nothing changes in the process that opened the lock.
+        lock.reinit_after_fork()?;
+        let _guard = lock.try_lock()?;
+        assert_eq!(held(), Some(true));
+
+        Ok(())
+    }
+
+    // Every error path must return an error, never panic.
+    #[test]
+    fn errors_do_not_panic() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let check = |f: &dyn Fn() -> Result<()>| {
+            let res = catch_unwind(AssertUnwindSafe(f));
+            assert!(matches!(res, Ok(Err(_))));
+        };
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create(&uuid).unwrap();
+        let _guard = lock.lock().unwrap();
+
+        check(&|| NamedLock::create("").map(drop));
+        check(&|| NamedLock::create("a/b").map(drop));
+        check(&|| NamedLock::create("a\0b").map(drop));
+        check(&|| lock.try_lock().map(drop));
+        check(&|| lock.lock().map(drop));
+        check(&|| lock.try_lock_spin(0).map(drop));
+        check(&|| lock.acquire(AcquireMode::NonBlocking).map(drop));
+        check(&|| lock.acquire(AcquireMode::Timeout(Duration::ZERO)).map(drop));
+        check(&|| NamedLock::create(&uuid)?.try_lock().map(drop));
+
+        #[cfg(unix)]
+        {
+            let dir = env::temp_dir();
+            check(&|| NamedLock::with_path(&dir).map(drop));
+            check(&|| {
+                NamedLock::with_path(dir.join(&uuid).join("x")).map(drop)
+            });
+            check(&|| NamedLock::with_path_named(&dir, "").map(drop));
+        }
+
+        // Timeouts that overflow are not errors, but they must not panic
+        // either.
+        let other = NamedLock::create(&format!("{}-other", uuid)).unwrap();
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            other.acquire(AcquireMode::Timeout(Duration::MAX)).map(drop)
+        }));
+        assert!(matches!(res, Ok(Ok(()))));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn create_os_in() -> Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let dir = env::temp_dir();
+
+        let mut bytes = uuid.clone().into_bytes();
+        bytes.extend_from_slice(b"-\xff\xfe");
+        let name = OsStr::from_bytes(&bytes);
+        assert!(name.to_str().is_none());
+
+        let lock = NamedLock::create_os_in(&dir, name)?;
+        let mut file_name = bytes.clone();
+        file_name.extend_from_slice(b".lock");
+        assert_eq!(lock.name(), dir.join(OsStr::from_bytes(&file_name)));
+
+        let _guard = lock.try_lock()?;
+        assert!(matches!(
+            NamedLock::with_path(lock.name())?.try_lock(),
+            Err(Error::WouldBlock)
+        ));
+
+        // Valid UTF-8 names give the same lock as `with_path_named`.
+        let utf8 = NamedLock::create_os_in(&dir, OsStr::new(&uuid))?;
+        assert_eq!(
+            utf8.name(),
+            NamedLock::with_path_named(&dir, &uuid)?.name()
+        );
+
+        assert!(matches!(
+            NamedLock::create_os_in(&dir, OsStr::new("")),
+            Err(Error::EmptyName)
+        ));
+
+        for bad in [&b"a/\xff"[..], b"\xff\0", b"a\\b"] {
+            assert!(matches!(
+                NamedLock::create_os_in(&dir, OsStr::from_bytes(bad)),
+                Err(Error::InvalidCharacter)
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn mock_clock() -> Result<()> {
+        let clock = clock::MockClock::install();
+        let start = Instant::now();
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLockOptions::new()
+            .ttl(Duration::from_secs(3600))
+            .create(&uuid)?;
+
+        // Another open file description excludes this one like another
+        // process would.
+        let other =
+            RawNamedLock::create(&lock.name(), &NamedLockOptions::new())?;
+        other.lock()?;
+
+        let timeout = Duration::from_secs(60);
+        assert!(matches!(
+            lock.acquire(AcquireMode::Timeout(timeout)),
+            Err(Error::WouldBlock)
+        ));
+        assert!(clock.elapsed() >= timeout);
+
+        other.unlock()?;
+        let acquired = lock.acquire(AcquireMode::Timeout(timeout))?;
+        assert!(!lock.is_expired()?);
+        clock.advance(Duration::from_secs(3600));
+        assert!(lock.is_expired()?);
+        assert_eq!(acquired.wait_duration(), Duration::ZERO);
+
+        assert!(start.elapsed() < Duration::from_secs(10));
+        clock.uninstall();
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn lock_with_timeout_reporting() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create(&uuid)?;
+        let timeout = Duration::from_secs(10);
+        let hold = Duration::from_millis(300);
+
+        // Another open file description excludes this one like another
+        // process would.
+        let other =
+            RawNamedLock::create(&lock.name(), &NamedLockOptions::new())?;
+        other.lock()?;
+        let start = Instant::now();
+        let holder = thread::spawn(move || {
+            sleep(hold);
+            other.unlock().unwrap();
+        });
+
+        let (guard, remaining) = lock.lock_with_timeout_reporting(timeout)?;
+        let elapsed = start.elapsed();
+        holder.join().unwrap();
+
+        // The holder may start sleeping before the wait starts.
+        assert!(remaining <= timeout - hold / 2);
+        assert!(remaining >= timeout - elapsed);
+        drop(guard);
+
+        // Without contention nothing is spent.
+        let clock = clock::MockClock::install();
+        let (guard, remaining) = lock.lock_with_timeout_reporting(timeout)?;
+        assert_eq!(remaining, timeout);
+        drop(guard);
+
+        let other =
+            RawNamedLock::create(&lock.name(), &NamedLockOptions::new())?;
+        other.lock()?;
+        assert!(matches!(
+            lock.lock_with_timeout_reporting(timeout),
+            Err(Error::WouldBlock)
+        ));
+        assert!(clock.elapsed() >= timeout);
+        other.unlock()?;
+        clock.uninstall();
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn lock_with_progress() -> Result<()> {
+        let clock = clock::MockClock::install();
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create(&uuid)?;
+        let interval = Duration::from_secs(5);
+
+        // Another open file description excludes this one like another
+        // process would. It is released after the third report.
+        let other =
+            RawNamedLock::create(&lock.name(), &NamedLockOptions::new())?;
+        other.lock()?;
+
+        let mut reports = Vec::new();
+        let guard = lock.lock_with_progress(interval, |waited| {
+            reports.push(waited);
+
+            if reports.len() == 3 {
+                other.unlock().unwrap();
+            }
+        })?;
+        assert_eq!(reports, [interval, interval * 2, interval * 3]);
+
+        assert!(matches!(
+            lock.lock_with_progress(interval, |_| unreachable!()),
+            Err(Error::WouldDeadlock)
+        ));
+        drop(guard);
+
+        drop(lock.lock_with_progress(interval, |_| unreachable!())?);
+        clock.uninstall();
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn max_poll_attempts() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let mut lock = NamedLock::create(&uuid)?;
+        let interrupter = LockInterrupter::new(libc::SIGUSR2);
+        let interval = Duration::from_millis(1);
+        lock.set_max_poll_attempts(Some(3));
+
+        // Held by another open file description for the whole test.
+        let other =
+            RawNamedLock::create(&lock.name(), &NamedLockOptions::new())?;
+        other.lock()?;
+
+        let mut reports = 0;
+        assert!(matches!(
+            lock.lock_with_progress(interval, |_| reports += 1),
+            Err(Error::WouldBlock)
+        ));
+        assert_eq!(reports, 3);
+        assert!(matches!(
+            lock.lock_cancellable(&CancelToken::new()),
+            Err(Error::WouldBlock)
+        ));
+        assert!(matches!(
+            lock.lock_interruptible(&interrupter),
+            Err(Error::WouldBlock)
+        ));
+
+        // Held by this process, so the waiters poll the in-process layer.
+        other.unlock()?;
+        let guard = lock.lock()?;
+        let waiter = std::thread::spawn({
+            let lock = lock.clone();
+            move || {
+                let cancellable = lock.lock_cancellable(&CancelToken::new());
+                let interruptible = lock.lock_interruptible(&interrupter);
+                assert!(matches!(cancellable, Err(Error::WouldBlock)));
+                assert!(matches!(interruptible, Err(Error::WouldBlock)));
+            }
+        });
+        waiter.join().unwrap();
+        drop(guard);
+
+        // A maximum of 0 still tries once.
+        lock.set_max_poll_attempts(Some(0));
+        drop(lock.lock_with_progress(interval, |_| unreachable!())?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn lazy() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lazy = NamedLock::lazy(&uuid)?;
+
+        assert!(lazy.get().is_none());
+        assert!(!NamedLock::exists(&uuid)?);
+
+        let guard = lazy.try_lock()?;
+        assert!(lazy.get().is_some());
+        assert!(NamedLock::exists(&uuid)?);
+
+        // It is the same lock as the one of `create`.
+        let lock = NamedLock::create(&uuid)?;
+        assert!(Arc::ptr_eq(&lazy.get().unwrap().raw, &lock.raw));
+        assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+        drop(guard);
+        let _guard = lazy.lock()?;
+
+        assert!(matches!(NamedLock::lazy(""), Err(Error::EmptyName)));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn relocate() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let dir = env::temp_dir();
+        let old_path = dir.join(format!("{}-old.lock", uuid));
+        let new_path = dir.join(format!("{}-new.lock", uuid));
+
+        let lock =
+            NamedLockOptions::new().record_holder(true).with_path(&old_path)?;
+        let clone = lock.clone();
+        // Stands for another process that has the old file open.
+        let other = RawNamedLock::create(&old_path, &NamedLockOptions::new())?;
+
+        lock.relocate(&new_path)?;
+        assert!(!old_path.exists());
+        assert_eq!(lock.name(), new_path);
+        // Every handle sees the new path.
+        assert_eq!(clone.status()?.path(), new_path);
+        assert!(clone.status()?.exists());
+
+        let guard = lock.lock()?;
+        assert!(matches!(other.try_lock(), Err(Error::WouldBlock)));
+        assert_eq!(guard.path(), new_path);
+
+        let same = NamedLock::with_path(&new_path)?;
+        assert!(Arc::ptr_eq(&lock.raw, &same.raw));
+        assert!(matches!(same.try_lock(), Err(Error::WouldBlock)));
+        assert_eq!(
+            Metadata::read(&new_path).unwrap().get(metadata::PID),
+            Some(std::process::id().to_string().as_str())
+        );
+
+        // Relocating locks, so it can not be done while holding the lock.
+        assert!(matches!(lock.relocate(&old_path), Err(Error::WouldDeadlock)));
+        drop(guard);
+
+        // The path of another open lock is not taken over.
+        let taken = NamedLock::with_path(&old_path)?;
+        assert!(!Arc::ptr_eq(&taken.raw, &lock.raw));
+        assert!(matches!(
+            lock.relocate(&old_path),
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists
+        ));
+        drop(taken);
+
+        other.try_lock()?;
+        other.unlock()?;
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn acquire_jitter() -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let mut lock = NamedLock::create(&uuid)?;
+        lock.set_acquire_jitter(Duration::from_millis(50));
+
+        let acquired = lock.acquire(AcquireMode::Blocking)?;
+        assert!(!acquired.contended());
+        drop(acquired);
+
+        // Held by another file descriptor until the holder releases it.
+        let other =
+            std::fs::File::open(NamedLock::resolved_name(&uuid)?).unwrap();
+        assert_eq!(unsafe { libc::flock(other.as_raw_fd(), libc::LOCK_EX) }, 0);
+        let holder = std::thread::spawn(move || {
+            sleep(Duration::from_millis(100));
+            drop(other);
+        });
+
+        let acquired = lock.clone().acquire(AcquireMode::Blocking)?;
+        assert!(acquired.contended());
+        assert!(acquired.wait_duration() >= Duration::from_millis(100));
+        holder.join().unwrap();
+
+        Ok(())
+    }
+
+    #[test]
+    fn try_lock_grace() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let mut lock = NamedLock::create(&uuid)?;
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+
+        // The holder releases the lock shortly after it was locked.
+        let holder = {
+            let lock = lock.clone();
+            thread::spawn(move || -> Result<()> {
+                let guard = lock.lock()?;
+                locked_tx.send(()).unwrap();
+                sleep(Duration::from_millis(50));
+                guard.unlock()
+            })
+        };
+        locked_rx.recv().unwrap();
+
+        assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+
+        lock.set_try_lock_grace(Duration::from_secs(5));
+        let start = Instant::now();
+        let guard = lock.try_lock()?;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        holder.join().unwrap()?;
+
+        // Clones keep the grace period, and it still gives up in the end.
+        let clone = lock.clone();
+        let mut short = clone.clone();
+        short.set_try_lock_grace(Duration::from_millis(20));
+        assert!(matches!(short.try_lock(), Err(Error::WouldBlock)));
+        assert!(matches!(
+            clone.acquire(AcquireMode::NonBlocking),
+            Err(Error::WouldBlock)
+        ));
+        drop(guard);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn signal_cleanup() -> Result<()> {
+        use std::os::unix::process::ExitStatusExt;
+
+        let (proc_num, uuid) = proc_num_and_uuid();
+        let lock = NamedLock::create(&uuid)?;
+
+        if proc_num == 1 {
+            let _guard = lock.lock()?;
+            let _cleanup = lock.on_signal_cleanup()?;
+            unsafe { libc::raise(libc::SIGTERM) };
+            unreachable!("SIGTERM did not terminate the process");
+        }
+
+        // Registering and unregistering leaves the file alone.
+        drop(lock.on_signal_cleanup()?);
+        assert!(lock.name().exists());
+
+        let mut handle = call_proc("tests::signal_cleanup", 1, &uuid);
+        let status = handle.wait().unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+        assert!(!lock.name().exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn lock_handle() -> Result<()> {
+        use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create(&uuid)?;
+        let handle = lock.downgrade_handle();
+        drop(lock);
+
+        let inside = Arc::new(AtomicBool::new(false));
+        let count = Arc::new(AtomicU32::new(0));
+
+        let threads = (0..8)
+            .map(|_| {
+                let handle = handle.clone();
+                let inside = inside.clone();
+                let count = count.clone();
+
+                thread::spawn(move || -> Result<()> {
+                    let lock = handle.to_lock();
+
+                    for _ in 0..10 {
+                        let _guard = lock.lock()?;
+                        assert!(!inside.swap(true, Ordering::SeqCst));
+                        count.fetch_add(1, Ordering::SeqCst);
+                        inside.store(false, Ordering::SeqCst);
+                    }
+
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap()?;
+        }
+
+        assert_eq!(count.load(Ordering::SeqCst), 80);
+
+        // The handle kept the lock open.
+        let lock = handle.to_lock();
+        assert_eq!(lock.handle_count(), 2);
+        assert!(Arc::ptr_eq(&NamedLock::create(&uuid)?.raw, &lock.raw));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn existing_file_policy() -> Result<()> {
+        let dir = env::temp_dir();
+        let create = |policy| {
+            let path = dir.join(format!("{}.lock", Uuid::new_v4()));
+            std::fs::write(&path, "hello").unwrap();
+
+            let res =
+                NamedLockOptions::new().existing_file(policy).with_path(&path);
+            (path, res)
+        };
+
+        let (path, lock) = create(ExistingFilePolicy::Reuse);
+        let _guard = lock?.try_lock()?;
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hello");
+
+        let (path, lock) = create(ExistingFilePolicy::Truncate);
+        let _guard = lock?.try_lock()?;
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+
+        let (path, lock) = create(ExistingFilePolicy::Error);
+        assert!(matches!(lock, Err(Error::AlreadyExists)));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hello");
+
+        // A new file is fine with every policy.
+        for policy in [
+            ExistingFilePolicy::Reuse,
+            ExistingFilePolicy::Error,
+            ExistingFilePolicy::Truncate,
+        ] {
+            let path = dir.join(format!("{}.lock", Uuid::new_v4()));
+            let lock = NamedLockOptions::new()
+                .existing_file(policy)
+                .with_path(&path)?;
+            let _guard = lock.try_lock()?;
+            assert!(path.is_file());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn extension() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let name = format!("{}.tar", uuid);
+        let dir = NamedLock::default_lock_dir();
+
+        let lock =
+            NamedLockOptions::new().extension(Some("pid")).create(&name)?;
+        let guard = lock.lock()?;
+        assert_eq!(guard.path(), dir.join(format!("{}.tar.pid", uuid)));
+        assert!(guard.path().is_file());
+        drop(guard);
+
+        let lock =
+            NamedLockOptions::new().extension(Some(".lck")).create(&name)?;
+        assert_eq!(lock.lock()?.path(), dir.join(format!("{}.tar.lck", uuid)));
+
+        let lock = NamedLockOptions::new().extension(None).create(&name)?;
+        assert_eq!(lock.lock()?.path(), dir.join(&name));
+
+        let lock = NamedLockOptions::new().extension(Some("")).create(&name)?;
+        assert_eq!(lock.lock()?.path(), dir.join(&name));
+
+        assert!(matches!(
+            NamedLockOptions::new().extension(Some("a/b")).create(&name),
+            Err(Error::InvalidCharacter)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn is_cross_process() -> Result<()> {
+        let (proc_num, uuid) = proc_num_and_uuid();
+        let lock = NamedLock::create(&uuid)?;
+
+        if proc_num == 1 {
+            assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+            return Ok(());
+        }
+
+        assert_eq!(lock.is_cross_process(), lock.backend().is_cross_process());
+        assert!(lock.is_cross_process());
+
+        // The lock that this process holds excludes another process.
+        let guard = lock.lock()?;
+        let mut handle = call_proc("tests::is_cross_process", 1, &uuid);
+        assert!(handle.wait().unwrap().success());
+        drop(guard);
+
+        #[cfg(unix)]
+        {
+            assert_eq!(lock.backend(), Backend::Flock);
+
+            let options =
+                NamedLockOptions::new().kind(LockKind::Session).clone();
+            if let Ok(session) = options.create(&uuid) {
+                assert_eq!(session.backend(), Backend::Flock);
+            }
+        }
+        #[cfg(windows)]
+        assert_eq!(lock.backend(), Backend::NamedMutex);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn lock_with_renewal() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create(&uuid)?;
+        let path = NamedLock::resolved_name(&uuid)?;
+        let ttl = Duration::from_millis(200);
+
+        let guard = lock.lock_with_renewal(ttl)?;
+        let acquired = || {
+            Metadata::read(&path)
+                .unwrap()
+                .get(metadata::ACQUIRED)
+                .map(str::to_owned)
+        };
+        let first = acquired().unwrap();
+
+        // Held for three times the lease.
+        for _ in 0..6 {
+            sleep(Duration::from_millis(100));
+            assert!(!lock.is_expired()?);
+        }
+        assert_ne!(acquired().unwrap(), first);
+        assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+
+        drop(guard);
+        assert_eq!(acquired(), None);
+
+        // The renewal thread is gone, so the lease is not written again.
+        let _guard = lock.try_lock()?;
+        sleep(Duration::from_millis(300));
+        assert_eq!(acquired(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn status() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        #[cfg(unix)]
+        let lock = NamedLockOptions::new().record_holder(true).create(&uuid)?;
+        #[cfg(windows)]
+        let lock = NamedLock::create(&uuid)?;
+
+        let status = lock.status()?;
+        assert!(status.exists());
+        assert!(!status.is_held());
+        assert_eq!(status.holder_pid(), None);
+        assert!(!status.is_expired());
+
+        let guard = lock.lock()?;
+        let status = lock.status()?;
+        assert!(status.exists());
+        assert!(status.is_held());
+        #[cfg(unix)]
+        {
+            assert_eq!(status.path(), NamedLock::resolved_name(&uuid)?);
+            assert_eq!(status.holder_pid(), Some(std::process::id()));
+            assert!(status
+                .to_string()
+                .contains(&format!("holder: {}", std::process::id())));
+
+            // Other handles see the same state.
+            let other = NamedLock::with_path(lock.name())?;
+            assert!(other.status()?.is_held());
+        }
+        #[cfg(windows)]
+        assert_eq!(status.name(), NamedLock::resolved_name(&uuid)?);
+        assert!(status.to_string().contains("held: yes"));
+
+        drop(guard);
+        let status = lock.status()?;
+        assert!(!status.is_held());
+        assert!(status.to_string().contains("held: no"));
+        lock.try_lock()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn would_deadlock() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create(&uuid)?;
+
+        let guard = lock.lock()?;
+        assert!(matches!(lock.lock(), Err(Error::WouldDeadlock)));
+        assert!(matches!(
+            NamedLock::create(&uuid)?.lock(),
+            Err(Error::WouldDeadlock)
+        ));
+        assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+
+        // Another thread waits as usual.
+        let handle = {
+            let lock = lock.clone();
+            thread::spawn(move || lock.lock().map(drop))
+        };
+        sleep(Duration::from_millis(100));
+        drop(guard);
+        handle.join().unwrap()?;
+
+        let guard = lock.lock()?;
+        guard.unlock()?;
+        let _guard = lock.lock()?;
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn abstract_socket_lock() -> Result<()> {
+        let (proc_num, uuid) = proc_num_and_uuid();
+        let lock = AbstractSocketLock::create(&uuid)?;
+
+        if proc_num == 1 {
+            let _guard = lock.lock()?;
+            sleep(Duration::from_millis(500));
+            return Ok(());
+        }
+
+        let mut handle = call_proc("tests::abstract_socket_lock", 1, &uuid);
+        sleep(Duration::from_millis(200));
+
+        assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+
+        let guard = lock.lock().unwrap();
+        assert!(handle.wait().unwrap().success());
+        assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+
+        drop(guard);
+        lock.try_lock()?;
+
+        assert!(AbstractSocketLock::create(&"a".repeat(96)).is_ok());
+        assert!(matches!(
+            AbstractSocketLock::create(&"a".repeat(97)),
+            Err(Error::CreateFailed(_))
+        ));
+        assert!(matches!(
+            AbstractSocketLock::create("a/b"),
+            Err(Error::InvalidCharacter)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn cross_process_counter() -> Result<()> {
+        const PROCESSES: u32 = 4;
+        const INCREMENTS: u64 = 50;
+
+        let (proc_num, uuid) = proc_num_and_uuid();
+        let counter = NamedCounter::create(&uuid)?;
+
+        if proc_num == 0 {
+            assert_eq!(counter.get()?, 0);
+
+            let handles = (1..=PROCESSES)
+                .map(|num| {
+                    call_proc("tests::cross_process_counter", num, &uuid)
+                })
+                .collect::<Vec<_>>();
+
+            for mut handle in handles {
+                assert!(handle.wait().unwrap().success());
+            }
+
+            assert_eq!(counter.get()?, u64::from(PROCESSES) * INCREMENTS);
+            assert_eq!(
+                counter.increment()?,
+                u64::from(PROCESSES) * INCREMENTS + 1
+            );
+        } else {
+            for _ in 0..INCREMENTS {
+                counter.increment()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn counter_of_open_lock() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+
+        // The lock file is opened write-only by the plain lock first.
+        let lock = NamedLock::create(&uuid)?;
+        let counter = NamedCounter::create(&uuid)?;
+        assert_eq!(lock.handle_count(), 2);
+
+        assert_eq!(counter.get()?, 0);
+        assert_eq!(counter.increment()?, 1);
+        assert_eq!(counter.increment()?, 2);
+        assert_eq!(NamedCounter::create(&uuid)?.get()?, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn contention_diagnostic() -> Result<()> {
+        let (proc_num, uuid) = proc_num_and_uuid();
+        let lock = NamedLock::create(&uuid)?;
+
+        if proc_num == 0 {
+            {
+                let _guard = lock.try_lock_diagnostic().unwrap();
+                let lock2 = NamedLock::create(&uuid)?;
+                assert!(matches!(
+                    lock2.try_lock_diagnostic(),
+                    Err(Contention::SameProcess)
+                ));
+            }
+
+            let mut handle =
+                call_proc("tests::contention_diagnostic", 1, &uuid);
+            sleep(Duration::from_millis(200));
+
+            assert!(matches!(
+                lock.try_lock_diagnostic(),
+                Err(Contention::OtherProcess)
+            ));
+
+            assert!(handle.wait().unwrap().success());
+            assert!(lock.try_lock_diagnostic().is_ok());
+        } else {
+            let _guard = lock.lock()?;
+            sleep(Duration::from_millis(400));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn lock_holder() -> Result<()> {
+        let (proc_num, uuid) = proc_num_and_uuid();
+
+        if proc_num == 0 {
+            let lock = NamedLock::create(&uuid)?;
+            let mut handle = call_proc("tests::lock_holder", 1, &uuid);
+            sleep(Duration::from_millis(200));
+
+            let holder = lock.try_lock_or_holder();
+
+            #[cfg(unix)]
+            assert!(
+                matches!(holder, Err(LockHolder::Pid(pid)) if pid == handle.id())
+            );
+            #[cfg(windows)]
+            assert!(matches!(holder, Err(LockHolder::Unknown)));
+
+            assert!(handle.wait().unwrap().success());
+
+            // The holder is removed on unlock.
+            let _guard = lock.try_lock_or_holder().unwrap();
+            #[cfg(unix)]
+            assert!(matches!(lock.holder(), LockHolder::Unknown));
+        } else {
+            #[cfg(unix)]
+            let lock =
+                NamedLockOptions::new().record_holder(true).create(&uuid)?;
+            #[cfg(windows)]
+            let lock = NamedLock::create(&uuid)?;
+            let _guard = lock.lock()?;
+            sleep(Duration::from_millis(400));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_name_len() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let max = NamedLock::max_name_len();
+        assert!(max > uuid.len());
+
+        let name = |len| format!("{:a<1$}", uuid, len);
+
+        let lock = NamedLock::create(&name(max))?;
+        drop(lock.try_lock()?);
+        assert!(matches!(
+            NamedLock::create(&name(max + 1)),
+            Err(Error::NameTooLong)
+        ));
+
+        #[cfg(unix)]
+        std::fs::remove_file(lock.name()).unwrap();
+
+        Ok(())
+    }
+
+    #[test]
+    fn single_instance() -> Result<()> {
+        let (proc_num, uuid) = proc_num_and_uuid();
+        let app_id = format!("com.example/{}", uuid);
+
+        if proc_num == 0 {
+            let mut handle = call_proc("tests::single_instance", 1, &uuid);
+            sleep(Duration::from_millis(200));
+
+            // The child is the running instance.
+            assert!(matches!(
+                SingleInstance::acquire(&app_id),
+                Err(Error::WouldBlock)
+            ));
+            assert!(handle.wait().unwrap().success());
+
+            let instance = SingleInstance::acquire(&app_id)?;
+            assert!(matches!(
+                SingleInstance::acquire(&app_id),
+                Err(Error::WouldBlock)
+            ));
+            instance.release()?;
+            drop(SingleInstance::acquire(&app_id)?);
+
+            assert!(matches!(
+                SingleInstance::acquire(""),
+                Err(Error::EmptyName)
+            ));
+        } else {
+            let _instance = SingleInstance::acquire(&app_id)?;
+            sleep(Duration::from_millis(400));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn create_shm() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create_shm(&uuid)?;
+
+        let dir = match shm_dir() {
+            Some(dir) => dir.to_owned(),
+            None => NamedLock::default_lock_dir(),
+        };
+        assert_eq!(lock.name(), dir.join(format!("{}.lock", uuid)));
+        assert!(lock.name().exists());
+        drop(lock.try_lock()?);
+
+        assert!(matches!(NamedLock::create_shm(""), Err(Error::EmptyName)));
+        std::fs::remove_file(lock.name()).unwrap();
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn with_dir_path() -> Result<()> {
+        let (proc_num, uuid) = proc_num_and_uuid();
+        let dir = env::temp_dir().join(&uuid);
+
+        if proc_num == 0 {
+            std::fs::create_dir(&dir).unwrap();
+            let lock = NamedLock::with_dir_path(&dir)?;
+            let mut handle = call_proc("tests::with_dir_path", 1, &uuid);
+            sleep(Duration::from_millis(200));
+
+            // The child holds the directory.
+            assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+            assert!(handle.wait().unwrap().success());
+            drop(lock.try_lock()?);
+
+            // Nothing was created in the directory.
+            assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+            drop(lock);
+
+            let file = dir.join("file");
+            std::fs::write(&file, "").unwrap();
+            assert!(matches!(
+                NamedLock::with_dir_path(&file),
+                Err(Error::CreateFailed(_))
+            ));
+            std::fs::remove_dir_all(&dir).unwrap();
+            assert!(matches!(
+                NamedLock::with_dir_path(&dir),
+                Err(Error::CreateFailed(_))
+            ));
+        } else {
+            let lock = NamedLock::with_dir_path(&dir)?;
+            let _guard = lock.lock()?;
+            sleep(Duration::from_millis(400));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn is_stale() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLockOptions::new().record_holder(true).create(&uuid)?;
+        let guard = lock.lock()?;
+        let record = |meta: &Metadata| meta.write(guard.file()).unwrap();
+        let mut meta = Metadata::read(&lock.name()).unwrap();
+
+        // The current process is the holder.
+        assert!(!lock.is_stale()?);
+        #[cfg(target_os = "linux")]
+        assert!(meta.get(metadata::START).is_some());
+
+        // A holder that exited.
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        meta.set(metadata::PID, child.id());
+        record(&meta);
+        assert!(lock.is_stale()?);
+
+        // The ID of the holder was reused by the current process, which
+        // started at another time.
+        meta.set(metadata::PID, std::process::id());
+        meta.set(metadata::START, 1);
+        record(&meta);
+        #[cfg(target_os = "linux")]
+        assert!(lock.is_stale()?);
+        #[cfg(not(target_os = "linux"))]
+        assert!(!lock.is_stale()?);
+
+        // Without a start time only the ID is checked.
+        meta.remove(metadata::START);
+        record(&meta);
+        assert!(!lock.is_stale()?);
+
+        drop(guard);
+        assert!(!lock.is_stale()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn edge_cases() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock1 = NamedLock::create(&uuid)?;
+        let lock2 = NamedLock::create(&uuid)?;
+
+        {
+            let _guard1 = lock1.try_lock()?;
+            assert!(matches!(lock1.try_lock(), Err(Error::WouldBlock)));
+            assert!(matches!(lock2.try_lock(), Err(Error::WouldBlock)));
+        }
+
+        {
+            let _guard2 = lock2.try_lock()?;
+            assert!(matches!(lock1.try_lock(), Err(Error::WouldBlock)));
+            assert!(matches!(lock2.try_lock(), Err(Error::WouldBlock)));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn owned_guard() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock1 = NamedLock::create(&uuid)?;
+        let lock2 = NamedLock::create(&uuid)?;
+
+        // Lock
+        let guard1 = lock1.try_lock()?;
+        assert!(matches!(lock2.try_lock(), Err(Error::WouldBlock)));
+
+        // Dropping `lock1` should not affect the state of the lock.
+        // If `guard1` is not dropped the lock must stay locked.
+        drop(lock1);
+        assert!(matches!(lock2.try_lock(), Err(Error::WouldBlock)));
+
+        // Unlock by dropping the `guard1`
+        drop(guard1);
+        let _guard2 = lock2.try_lock()?;
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn equivalent_paths() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let dir = NamedLock::default_lock_dir();
+
+        let lock1 = NamedLock::create(&uuid)?;
+        let lock2 =
+            NamedLock::with_path(dir.join(".").join(format!("{}.lock", uuid)))?;
+        assert!(Arc::ptr_eq(&lock1.raw, &lock2.raw));
+
+        let link = dir.join(format!("{}-link.lock", uuid));
+        std::os::unix::fs::symlink(NamedLock::resolved_name(&uuid)?, &link)
+            .unwrap();
+        let lock3 = NamedLock::with_path(&link)?;
+        std::fs::remove_file(&link).unwrap();
+        assert!(Arc::ptr_eq(&lock1.raw, &lock3.raw));
+
+        let _guard = lock1.try_lock()?;
+        assert!(matches!(lock2.try_lock(), Err(Error::WouldBlock)));
+        assert!(matches!(lock3.try_lock(), Err(Error::WouldBlock)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn handle_count() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock1 = NamedLock::create(&uuid)?;
+        assert_eq!(lock1.handle_count(), 1);
+
+        let lock2 = lock1.clone();
+        let lock3 = NamedLock::create(&uuid)?;
+        assert_eq!(lock1.handle_count(), 3);
+
+        let guard = lock2.try_lock()?;
+        assert_eq!(lock1.handle_count(), 4);
+        assert!(matches!(lock3.try_lock(), Err(Error::WouldBlock)));
+
+        drop(guard);
+        drop(lock2);
+        drop(lock3);
+        assert_eq!(lock1.handle_count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn lock_dir_precedence() {
+        let vars = |set: &'static [(&'static str, &'static str)]| {
+            move |var: &OsStr| {
+                set.iter()
+                    .find(|(k, _)| OsStr::new(k) == var)
+                    .map(|(_, v)| OsString::from(v))
+            }
+        };
+
+        assert_eq!(
+            resolve_lock_dir(None, vars(&[])),
+            TempDirSource::FallbackTmp
+        );
+        assert_eq!(TempDirSource::FallbackTmp.path(), Path::new("/tmp"));
+
+        assert_eq!(
+            resolve_lock_dir(None, vars(&[("TMPDIR", "/tmpdir")])),
+            TempDirSource::TmpDirEnv("/tmpdir".into())
+        );
+
+        let custom = Some(OsStr::new("MYAPP_LOCK_DIR"));
+        assert_eq!(
+            resolve_lock_dir(custom, vars(&[("TMPDIR", "/tmpdir")])),
+            TempDirSource::TmpDirEnv("/tmpdir".into())
+        );
+
+        assert_eq!(
+            resolve_lock_dir(
+                custom,
+                vars(&[("TMPDIR", "/tmpdir"), ("MYAPP_LOCK_DIR", "/myapp")])
+            ),
+            TempDirSource::Override("/myapp".into())
+        );
+
+        assert_eq!(
+            resolve_lock_dir(custom, vars(&[("MYAPP_LOCK_DIR", "/myapp")])),
+            TempDirSource::Override("/myapp".into())
+        );
+
+        // Empty variables count as unset.
+        assert_eq!(
+            resolve_lock_dir(None, vars(&[("TMPDIR", "")])),
+            TempDirSource::FallbackTmp
+        );
+        assert_eq!(
+            resolve_lock_dir(
+                custom,
+                vars(&[("TMPDIR", "/tmpdir"), ("MYAPP_LOCK_DIR", "")])
+            ),
+            TempDirSource::TmpDirEnv("/tmpdir".into())
+        );
+
+        let plan = NamedLock::plan("foo").unwrap();
+        assert_eq!(plan.dir_source().path(), plan.path().parent().unwrap());
+        let expected = match env::var_os("TMPDIR") {
+            Some(dir) if !dir.is_empty() => {
+                TempDirSource::TmpDirEnv(dir.into())
+            }
+            _ => TempDirSource::FallbackTmp,
+        };
+        assert_eq!(plan.dir_source(), &expected);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn empty_tmpdir() -> Result<()> {
+        let (proc_num, uuid) = proc_num_and_uuid();
+
+        // The environment is changed only in a child process.
+        if proc_num == 0 {
+            let status = Command::new(env::current_exe().unwrap())
+                .env("TEST_CROSS_PROCESS_LOCK_PROC_NUM", "1")
+                .env("TEST_CROSS_PROCESS_LOCK_UUID", &uuid)
+                .env("TMPDIR", "")
+                .arg("tests::empty_tmpdir")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return Ok(());
+        }
+
+        assert_eq!(env::var_os("TMPDIR"), Some(OsString::new()));
+        assert_eq!(NamedLock::default_lock_dir(), Path::new("/tmp"));
+        assert_eq!(
+            NamedLock::resolved_name(&uuid)?,
+            Path::new("/tmp").join(format!("{}.lock", uuid))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn weak_named_lock() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create(&uuid)?;
+        let weak = lock.downgrade();
+        assert_eq!(lock.handle_count(), 1);
+
+        let upgraded = weak.upgrade().expect("lock is alive");
+        assert!(Arc::ptr_eq(&lock.raw, &upgraded.raw));
+        drop(upgraded);
+
+        // A guard keeps the lock alive.
+        let guard = lock.try_lock()?;
+        drop(lock);
+        let upgraded = weak.upgrade().expect("guard is alive");
+        assert!(matches!(upgraded.try_lock(), Err(Error::WouldBlock)));
+        drop(upgraded);
+
+        drop(guard);
+        assert!(weak.upgrade().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn explicit_unlock() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create(&uuid)?;
+
+        let guard = lock.try_lock()?;
+        assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+        guard.unlock()?;
+
+        let _guard = lock.try_lock()?;
+        assert_eq!(lock.handle_count(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn create_locked() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+
+        let (lock, guard) = NamedLockOptions::new().create_locked(&uuid)?;
+        assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+        assert!(matches!(
+            NamedLockOptions::new().create_locked(&uuid),
+            Err(Error::WouldBlock)
+        ));
+
+        // On Windows this fails if the mutex is not owned by this thread.
+        guard.unlock()?;
+
+        let (_lock, _guard) = NamedLockOptions::new().create_locked(&uuid)?;
+        assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn named_mutex() -> Result<()> {
+        struct Counter;
+
+        impl StaticName for Counter {
+            const NAME: &'static str = "named-lock-test-named-mutex";
+        }
+
+        static COUNTER: NamedMutex<Counter, u32> = NamedMutex::new(0);
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    for _ in 0..10 {
+                        *COUNTER.lock() += 1;
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let guard = COUNTER.lock();
+        assert_eq!(*guard, 40);
+        assert!(COUNTER.try_lock().is_none());
+
+        let lock = NamedLock::create(Counter::NAME)?;
+        assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+
+        drop(guard);
+        let lock_guard = lock.try_lock()?;
+        assert!(COUNTER.try_lock().is_none());
+
+        drop(lock_guard);
+        assert_eq!(COUNTER.try_lock().map(|x| *x), Some(40));
+
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic(expected = "named mutex failed")]
+    fn named_mutex_invalid_name() {
+        struct Invalid;
+
+        impl StaticName for Invalid {
+            const NAME: &'static str = "";
+        }
+
+        let mutex: NamedMutex<Invalid, ()> = NamedMutex::new(());
+        drop(mutex.lock());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn lease() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let expiring = NamedLockOptions::new()
+            .ttl(Duration::from_millis(100))
+            .create(&format!("{}-expiring", uuid))?;
+        let lasting = NamedLockOptions::new()
+            .ttl(Duration::from_secs(3600))
+            .create(&format!("{}-lasting", uuid))?;
+
+        assert!(!expiring.is_expired()?);
+        assert!(!lasting.is_expired()?);
+
+        let _expiring_guard = expiring.lock()?;
+        let _lasting_guard = lasting.lock()?;
+        assert!(!expiring.is_expired()?);
+        sleep(Duration::from_millis(150));
+        assert!(expiring.is_expired()?);
+        assert!(!lasting.is_expired()?);
+
+        // Other handles see the same lease.
+        let other = NamedLock::with_path(expiring.name())?;
+        assert!(other.is_expired()?);
+
+        drop(_expiring_guard);
+        assert!(!expiring.is_expired()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn abandoned_policy() -> Result<()> {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let (proc_num, uuid) = proc_num_and_uuid();
+
+        // Locks and exits without unlocking, so on Windows the mutex is
+        // abandoned.
+        if proc_num == 1 {
+            std::mem::forget(NamedLock::create(&uuid)?.lock()?);
+            std::process::exit(0);
+        }
+
+        let abandoned_lock = |policy| -> Result<NamedLock> {
+            let uuid = Uuid::new_v4().as_hyphenated().to_string();
+            let mut lock = NamedLock::create(&uuid)?;
+            lock.set_abandoned_policy(policy);
+
+            let status =
+                call_proc("tests::abandoned_policy", 1, &uuid).wait().unwrap();
+            assert!(status.success());
+
+            Ok(lock)
+        };
+
+        let lock = abandoned_lock(AbandonedPolicy::Accept)?;
+        assert_eq!(
+            lock.acquire(AcquireMode::Blocking)?.abandoned(),
+            cfg!(windows)
+        );
+
+        let lock = abandoned_lock(AbandonedPolicy::Error)?;
+        #[cfg(windows)]
+        assert!(matches!(lock.try_lock(), Err(Error::Abandoned)));
+        assert!(!lock.acquire(AcquireMode::NonBlocking)?.abandoned());
+
+        let lock = abandoned_lock(AbandonedPolicy::Error)?;
+        #[cfg(windows)]
+        assert!(matches!(
+            lock.try_lock_diagnostic(),
+            Err(Contention::Failed(Error::Abandoned))
+        ));
+        assert!(lock.try_lock_diagnostic().is_ok());
+
+        let recovered = Arc::new(AtomicBool::new(false));
+        let lock = abandoned_lock(AbandonedPolicy::Recover(Arc::new({
+            let recovered = recovered.clone();
+            move |_| {
+                recovered.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+        })))?;
+        let _guard = lock.lock()?;
+        assert_eq!(recovered.load(Ordering::SeqCst), cfg!(windows));
+
+        let lock = abandoned_lock(AbandonedPolicy::Recover(Arc::new(|_| {
+            Err(Error::LockFailed(None))
+        })))?;
+        #[cfg(windows)]
+        assert!(matches!(lock.lock(), Err(Error::LockFailed(None))));
+        lock.try_lock()?;
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn acquisition_metrics() -> Result<()> {
+        use ::metrics::{
+            Counter, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata,
+            Recorder, SharedString, Unit,
+        };
+        use std::collections::HashMap;
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        type Id = (String, String);
+
+        // Keeps the counters and histograms by name and `name` label.
+        #[derive(Default)]
+        struct TestRecorder {
+            counters: Mutex<HashMap<Id, Arc<AtomicU64>>>,
+            histograms: Mutex<HashMap<Id, Arc<Samples>>>,
+        }
+
+        #[derive(Default)]
+        struct Samples(Mutex<Vec<f64>>);
+
+        impl HistogramFn for Samples {
+            fn record(&self, value: f64) {
+                self.0.lock().push(value);
+            }
+        }
+
+        fn id(key: &Key) -> Id {
+            let name = key
+                .labels()
+                .find(|label| label.key() == "name")
+                .map(|label| label.value().to_owned())
+                .unwrap_or_default();
+
+            (key.name().to_owned(), name)
+        }
+
+        impl Recorder for TestRecorder {
+            fn describe_counter(
+                &self,
+                _: KeyName,
+                _: Option<Unit>,
+                _: SharedString,
+            ) {
+            }
+            fn describe_gauge(
+                &self,
+                _: KeyName,
+                _: Option<Unit>,
+                _: SharedString,
+            ) {
+            }
+            fn describe_histogram(
+                &self,
+                _: KeyName,
+                _: Option<Unit>,
+                _: SharedString,
+            ) {
+            }
+
+            fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+                let mut counters = self.counters.lock();
+                Counter::from_arc(counters.entry(id(key)).or_default().clone())
+            }
+
+            fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+                Gauge::noop()
+            }
+
+            fn register_histogram(
+                &self,
+                key: &Key,
+                _: &Metadata<'_>,
+            ) -> Histogram {
+                let mut histograms = self.histograms.lock();
+                Histogram::from_arc(
+                    histograms.entry(id(key)).or_default().clone(),
+                )
+            }
+        }
+
+        impl TestRecorder {
+            fn counter(&self, name: &str, label: &str) -> u64 {
+                let id = (name.to_owned(), label.to_owned());
+                self.counters
+                    .lock()
+                    .get(&id)
+                    .map_or(0, |counter| counter.load(Ordering::SeqCst))
+            }
+
+            fn samples(&self, name: &str, label: &str) -> Vec<f64> {
+                let id = (name.to_owned(), label.to_owned());
+                self.histograms
+                    .lock()
+                    .get(&id)
+                    .map_or(Vec::new(), |samples| samples.0.lock().clone())
+            }
+        }
+
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create(&uuid)?;
+        #[cfg(unix)]
+        let label = lock.name().to_string_lossy().into_owned();
+        #[cfg(windows)]
+        let label = lock.name();
+
+        let recorder = TestRecorder::default();
+        let acquired = || recorder.counter("named_lock.acquired", &label);
+        let would_block = || recorder.counter("named_lock.would_block", &label);
+
+        ::metrics::with_local_recorder(&recorder, || -> Result<()> {
+            let guard = lock.lock()?;
+            assert_eq!(acquired(), 1);
+
+            assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+            assert!(matches!(
+                lock.acquire(AcquireMode::Timeout(Duration::from_millis(10))),
+                Err(Error::WouldBlock)
+            ));
+            assert_eq!(would_block(), 2);
+
+            // Retries in one call count once.
+            assert!(matches!(lock.try_lock_spin(3), Err(Error::WouldBlock)));
+            assert_eq!(would_block(), 3);
+
+            drop(guard);
+            lock.try_lock()?;
+            assert_eq!(acquired(), 2);
+
+            Ok(())
+        })?;
+
+        let samples = recorder.samples("named_lock.wait_seconds", &label);
+        assert_eq!(samples.len(), 2);
+        assert!(samples.iter().all(|&wait| wait >= 0.0));
+
+        // Nothing is recorded without a recorder.
+        lock.try_lock()?;
+        assert_eq!(acquired(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn poll_lock() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create(&uuid)?;
+
+        let mut guard = Some(lock.lock()?);
+        assert!(lock.poll_lock().is_pending());
+
+        let mut polls = 0;
+        let _guard = loop {
+            match lock.poll_lock() {
+                Poll::Ready(guard) => break guard?,
+                Poll::Pending => polls += 1,
+            }
+
+            // Other work of the event loop, which eventually frees the lock.
+            if polls == 3 {
+                guard.take();
+            }
+        };
+
+        assert_eq!(polls, 3);
+        assert!(lock.poll_lock().is_pending());
+
+        Ok(())
+    }
+
+    #[test]
+    fn try_lock_spin() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create(&uuid)?;
+
+        lock.try_lock_spin(0)?;
+
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (unlock_tx, unlock_rx) = std::sync::mpsc::channel();
+        let holder = std::thread::spawn({
+            let lock = lock.clone();
+            move || {
+                let _guard = lock.try_lock().unwrap();
+                locked_tx.send(()).unwrap();
+                unlock_rx.recv().unwrap();
+            }
+        });
+
+        locked_rx.recv().unwrap();
+        assert!(matches!(lock.try_lock_spin(3), Err(Error::WouldBlock)));
+
+        unlock_tx.send(()).unwrap();
+        let _guard = lock.try_lock_spin(u32::MAX)?;
+        holder.join().unwrap();
+
+        Ok(())
+    }
+
+    #[test]
+    fn try_lock_any() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let slots: Vec<String> =
+            (0..4).map(|i| format!("{}-worker-{}", uuid, i)).collect();
+        let slots: Vec<&str> = slots.iter().map(String::as_str).collect();
+
+        let _held0 = NamedLock::claim(slots[0])?;
+        let _held2 = NamedLock::claim(slots[2])?;
+
+        let (name, _guard1) = NamedLock::try_lock_any(&slots)?;
+        assert_eq!(name, slots[1]);
+
+        let (name, _guard3) = NamedLock::try_lock_any(&slots)?;
+        assert_eq!(name, slots[3]);
+
+        assert!(matches!(
+            NamedLock::try_lock_any(&slots),
+            Err(Error::WouldBlock)
+        ));
+        assert!(matches!(NamedLock::try_lock_any(&[]), Err(Error::WouldBlock)));
+        assert!(matches!(
+            NamedLock::try_lock_any(&[slots[0], ""]),
+            Err(Error::EmptyName)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn create_many() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let names: Vec<String> =
+            (0..3).map(|i| format!("{}-{}", uuid, i)).collect();
+        let mut names: Vec<&str> = names.iter().map(String::as_str).collect();
+
+        names.insert(2, "a/b");
+        assert!(matches!(
+            NamedLock::create_many(&names),
+            Err(Error::InvalidCharacter)
+        ));
+        #[cfg(unix)]
+        for name in [names[0], names[1], names[3]] {
+            assert!(!NamedLock::resolved_name(name)?.exists());
+        }
+
+        names.remove(2);
+        let locks = NamedLock::create_many(&names)?;
+        assert_eq!(locks.len(), 3);
+
+        for (lock, name) in locks.iter().zip(&names) {
+            let _guard = lock.try_lock()?;
+            let same = NamedLock::create(name)?;
+            assert!(matches!(same.try_lock(), Err(Error::WouldBlock)));
+        }
+
+        assert!(NamedLock::create_many(&[])?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn try_lock_all() -> Result<()> {
+        let (proc_num, uuid) = proc_num_and_uuid();
+        let names: Vec<String> =
+            (0..3).map(|i| format!("{}-resource-{}", uuid, i)).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+
+        match proc_num {
+            0 => {
+                let mut handle = call_proc("tests::try_lock_all", 1, &uuid);
+                sleep(Duration::from_millis(200));
+
+                // The child holds the middle one, so nothing is kept.
+                assert!(matches!(
+                    NamedLock::try_lock_all(&names),
+                    Err(Error::WouldBlock)
+                ));
+                drop(NamedLock::claim(names[0]).unwrap());
+                drop(NamedLock::claim(names[2]).unwrap());
+
+                assert!(handle.wait().unwrap().success());
+
+                // Duplicates are locked once.
+                let guards = NamedLock::try_lock_all(&[
+                    names[2], names[0], names[1], names[0],
+                ])?;
+                assert_eq!(guards.len(), 3);
+                assert!(matches!(
+                    NamedLock::claim(names[1]),
+                    Err(Error::WouldBlock)
+                ));
+                drop(guards);
+
+                assert!(NamedLock::try_lock_all(&[])?.is_empty());
+                assert!(matches!(
+                    NamedLock::try_lock_all(&[names[0], ""]),
+                    Err(Error::EmptyName)
+                ));
+                drop(NamedLock::claim(names[0])?);
+            }
+            1 => {
+                let _guard = NamedLock::claim(names[1])?;
+                sleep(Duration::from_millis(500));
+            }
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn lock_name_order() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let names = ["b", "a-b", "a", "a.b", "A"]
+            .iter()
+            .map(|suffix| format!("{}-{}", uuid, suffix))
+            .collect::<Vec<_>>();
+
+        let mut lock_names = names
+            .iter()
+            .map(|name| LockName::new(name))
+            .collect::<Result<Vec<_>>>()?;
+        lock_names.sort();
+
+        // The order is the one of the bytes of the resolved names, which
+        // is not the order of the names themselves.
+        let mut resolved = names
+            .iter()
+            .map(|name| NamedLock::resolved_name(name))
+            .collect::<Result<Vec<_>>>()?;
+        #[cfg(unix)]
+        resolved.sort_by(|a, b| {
+            use std::os::unix::ffi::OsStrExt;
+            a.as_os_str().as_bytes().cmp(b.as_os_str().as_bytes())
+        });
+        #[cfg(windows)]
+        resolved.sort();
+
+        #[cfg(unix)]
+        let sorted = lock_names.iter().map(|name| name.path().to_owned());
+        #[cfg(windows)]
+        let sorted = lock_names.iter().map(|name| name.name().to_owned());
+        assert!(sorted.eq(resolved));
+
+        let lock = NamedLock::create(&names[0])?;
+        assert_eq!(lock.lock_name(), LockName::new(&names[0])?);
+        assert!(LockName::new("").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn lock_all() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let names = ["b", "a-b", "a", "a.b"]
+            .iter()
+            .map(|suffix| format!("{}-{}", uuid, suffix))
+            .collect::<Vec<_>>();
+        let names = names.iter().map(String::as_str).collect::<Vec<_>>();
+
+        // The guards come in the order of the lock names, which is the
+        // order they are locked in.
+        let guards = NamedLock::lock_all(&[names[0], names[2], names[0]])?;
+        let mut lock_names =
+            [LockName::new(names[0])?, LockName::new(names[2])?];
+        lock_names.sort();
+        assert_eq!(guards.len(), 2);
+        #[cfg(unix)]
+        for (guard, name) in guards.iter().zip(&lock_names) {
+            assert_eq!(guard.path(), name.path());
+        }
+        drop(guards);
+
+        // Threads that take overlapping sets in any order do not deadlock.
+        let threads = (0..2)
+            .map(|i| {
+                let mut names = names
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect::<Vec<_>>();
+
+                if i == 1 {
+                    names.reverse();
+                }
+
+                thread::spawn(move || -> Result<()> {
+                    let names =
+                        names.iter().map(String::as_str).collect::<Vec<_>>();
+
+                    for _ in 0..50 {
+                        drop(NamedLock::lock_all(&names[..3])?);
+                        drop(NamedLock::lock_all(&names[1..])?);
+                    }
+
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap()?;
+        }
+
+        assert!(NamedLock::lock_all(&[])?.is_empty());
+        assert!(matches!(
+            NamedLock::lock_all(&[names[0], ""]),
+            Err(Error::EmptyName)
+        ));
+        assert!(NamedLock::try_lock_all(&names)?.len() == 4);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "parking_lot")]
+    fn unlock_fair() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create(&uuid)?;
+
+        for &unlock in &[false, true] {
+            let mut guard = lock.lock()?;
+            guard.set_unlock_fair(true);
+
+            let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+            let waiter = std::thread::spawn({
+                let lock = lock.clone();
+                move || {
+                    let _guard = lock.lock().unwrap();
+                    locked_tx.send(()).unwrap();
+                    sleep(Duration::from_millis(50));
+                }
+            });
+            sleep(Duration::from_millis(100));
+
+            // The lock is handed over to the waiting thread, so this thread
+            // can not overtake it, no matter how the threads are scheduled.
+            if unlock {
+                guard.unlock()?;
+            } else {
+                drop(guard);
+            }
+            assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+
+            locked_rx.recv().unwrap();
+            waiter.join().unwrap();
+        }
+
+        // Unfair unlocks still work.
+        let guard = lock.lock()?;
+        drop(guard);
+        let _guard = lock.try_lock()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn take_unlock_error() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create(&uuid)?;
+
+        drop(lock.lock()?);
+        assert!(lock.take_unlock_error().is_none());
+
+        // Dropping the guard on another thread fails to release the mutex.
+        #[cfg(windows)]
+        {
+            let guard = lock.lock()?;
+            std::thread::spawn(move || drop(guard)).join().unwrap();
+
+            let other = NamedLock::create(&uuid)?;
+            assert!(matches!(
+                other.take_unlock_error(),
+                Some(Error::WrongThread)
+            ));
+            assert!(lock.take_unlock_error().is_none());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn guard_with_data() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create(&uuid)?;
+
+        let mut guard = lock.lock()?.with_data(vec![1, 2]);
+        guard.push(3);
+        assert_eq!(*guard, [1, 2, 3]);
+        assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+
+        drop(guard);
+        let (guard, data) = lock.try_lock()?.with_data(42).into_parts();
+        assert_eq!(data, 42);
+        assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+
+        drop(guard);
+        lock.try_lock()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn wait_until_free() -> Result<()> {
+        let (proc_num, uuid) = proc_num_and_uuid();
+
+        match proc_num {
+            0 => {
+                let mut handle = call_proc("tests::wait_until_free", 1, &uuid);
+                sleep(Duration::from_millis(100));
+
+                let lock = NamedLock::create(&uuid).unwrap();
+                assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+
+                lock.wait_until_free().unwrap();
+                assert!(handle.wait().unwrap().success());
+
+                // Not held by this process either.
+                let _guard = lock.try_lock()?;
+                assert!(matches!(
+                    lock.wait_until_free(),
+                    Err(Error::WouldDeadlock)
+                ));
+            }
+            1 => {
+                let lock = NamedLock::create(&uuid)?;
+                let _guard = lock.lock()?;
+                sleep(Duration::from_millis(300));
+            }
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_command() -> Result<()> {
+        let (proc_num, uuid) = proc_num_and_uuid();
+        let lock = NamedLock::create(&uuid)?;
+
+        let command = |num: u32| {
+            let mut cmd = Command::new(env::current_exe().unwrap());
+            cmd.env("TEST_CROSS_PROCESS_LOCK_PROC_NUM", num.to_string())
+                .env("TEST_CROSS_PROCESS_LOCK_UUID", &uuid)
+                .arg("tests::run_command");
+            cmd
+        };
+
+        match proc_num {
+            0 => {
+                let status = lock.run_command(&mut command(1))?;
+                assert!(status.success());
+                drop(lock.try_lock()?);
+
+                #[cfg(unix)]
+                {
+                    use std::os::unix::io::AsRawFd;
+
+                    // The child gets the number of the inherited descriptor.
+                    let fd = lock.try_lock()?.file().as_raw_fd();
+                    let mut cmd = command(2);
+                    cmd.env("TEST_RUN_COMMAND_FD", fd.to_string());
+                    assert!(lock.run_command_inherited(&mut cmd)?.success());
+                    let _guard = lock.try_lock()?;
+                }
+
+                // The exit status is passed on.
+                let status = lock.run_command(&mut command(3))?;
+                assert!(!status.success());
+            }
+            1 => {
+                assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+            }
+            #[cfg(unix)]
+            2 => {
+                assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+
+                // Locking the inherited descriptor succeeds, since it is the
+                // open file that holds the lock.
+                let fd: i32 =
+                    env::var("TEST_RUN_COMMAND_FD").unwrap().parse().unwrap();
+                let res =
+                    unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) };
+                assert_eq!(res, 0);
+            }
+            3 => std::process::exit(3),
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn lock_cancellable() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create(&uuid)?;
+        let token = CancelToken::new();
+
+        let guard = lock.lock()?;
+        let waiter = std::thread::spawn({
+            let lock = lock.clone();
+            let token = token.clone();
+            move || lock.lock_cancellable(&token).map(|_| ())
+        });
+        sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+        token.cancel();
+        assert!(matches!(waiter.join().unwrap(), Err(Error::Cancelled)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        drop(guard);
+
+        // The token stays cancelled.
+        assert!(token.is_cancelled());
+        assert!(matches!(lock.lock_cancellable(&token), Err(Error::Cancelled)));
+
+        let _guard = lock.lock_cancellable(&CancelToken::new())?;
+        assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+
+        // Waiting for itself would only end when the token is cancelled.
+        assert!(matches!(
+            lock.lock_cancellable(&CancelToken::new()),
+            Err(Error::WouldDeadlock)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn lock_interruptible() -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        extern "C" fn handler(_: libc::c_int) {}
+
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handler as *const () as usize;
+            assert_eq!(
+                libc::sigaction(libc::SIGUSR2, &action, std::ptr::null_mut()),
+                0
+            );
+        }
+
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create(&uuid)?;
+        let interrupter = LockInterrupter::new(libc::SIGUSR2);
+
+        let wait = |lock: NamedLock, interrupter: &LockInterrupter| {
+            let waiter = std::thread::spawn({
+                let interrupter = interrupter.clone();
+                move || lock.lock_interruptible(&interrupter).map(|_| ())
+            });
+            sleep(Duration::from_millis(100));
+            interrupter.interrupt();
+            waiter.join().unwrap()
+        };
+
+        // Held by another file descriptor, so the waiter blocks in `flock`.
+        let other =
+            std::fs::File::open(NamedLock::resolved_name(&uuid)?).unwrap();
+        assert_eq!(unsafe { libc::flock(other.as_raw_fd(), libc::LOCK_EX) }, 0);
+
+        let res = wait(lock.clone(), &interrupter);
+        assert!(matches!(res, Err(Error::Interrupted)));
+        drop(other);
+
+        // Still interrupted until reset.
+        assert!(interrupter.is_interrupted());
+        assert!(matches!(
+            lock.lock_interruptible(&interrupter),
+            Err(Error::Interrupted)
+        ));
+        interrupter.reset();
+
+        // Held by this process, so the waiter blocks in the in-process layer.
+        let guard = lock.lock()?;
+        let res = wait(lock.clone(), &interrupter);
+        assert!(matches!(res, Err(Error::Interrupted)));
+        drop(guard);
+
+        interrupter.reset();
+        let _guard = lock.lock_interruptible(&interrupter)?;
+        assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+        assert!(matches!(
+            lock.lock_interruptible(&interrupter),
+            Err(Error::WouldDeadlock)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn raw_windows_names() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let local = format!("Local\\{}", uuid);
+
+        let lock = NamedLock::create_raw_windows(&local)?;
+        let _guard = lock.try_lock()?;
+        assert!(matches!(
+            NamedLock::create_raw_windows(&local)?.try_lock(),
+            Err(Error::WouldBlock)
+        ));
+
+        // `Local\` and `Global\` are different namespaces.
+        NamedLock::create(&uuid)?.try_lock()?;
+
+        assert!(matches!(
+            NamedLock::create_raw_windows(""),
+            Err(Error::EmptyName)
+        ));
+        assert!(matches!(
+            NamedLock::create_raw_windows("Local\\a\0b"),
+            Err(Error::InvalidCharacter)
+        ));
+
+        Ok(())
+    }
+
+    // A service in session 0 and a program in an interactive session both
+    // open the same mutex. Only one session is available in tests, so this
+    // checks that the cross-session mutex behaves like any other lock.
+    #[test]
+    #[cfg(windows)]
+    fn cross_session() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+
+        let service = match NamedLock::create_cross_session(&uuid) {
+            Ok(lock) => lock,
+            // Not running as a service or with `SeCreateGlobalPrivilege`.
+            Err(Error::CreateFailed(e))
+                if e.kind() == std::io::ErrorKind::PermissionDenied =>
+            {
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+
+        let guard = service.try_lock()?;
+        let app = NamedLock::create_cross_session(&uuid)?;
+        assert!(matches!(app.try_lock(), Err(Error::WouldBlock)));
+
+        drop(guard);
+        app.try_lock()?;
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn unlock_from_wrong_thread() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create(&uuid)?;
+
+        let guard = std::thread::spawn(move || lock.lock()).join().unwrap()?;
+
+        assert!(matches!(guard.unlock(), Err(Error::WrongThread)));
+
+        Ok(())
+    }
+
+    // The contenders start to wait one after the other while the lock is
+    // held, so they have to get it in the same order.
+    #[test]
+    #[cfg(windows)]
+    fn ticket_lock_arrival_order() -> Result<()> {
+        const PROCESSES: u32 = 4;
+
+        let (proc_num, uuid) = proc_num_and_uuid();
+        let lock = NamedLockOptions::new().ticket_lock(true).create(&uuid)?;
+        let waiting = NamedCounter::create(&format!("{}-waiting", uuid))?;
+        let served = NamedCounter::create(&format!("{}-served", uuid))?;
+
+        if proc_num == 0 {
+            assert_eq!(lock.backend(), Backend::TicketLock);
+
+            let guard = lock.lock()?;
+            let mut handles = Vec::new();
+
+            for num in 1..=PROCESSES {
+                handles.push(call_proc(
+                    "tests::ticket_lock_arrival_order",
+                    num,
+                    &uuid,
+                ));
+
+                // Give the contender time to draw its ticket after it says
+                // that it waits.
+                while waiting.get()? < u64::from(num) {
+                    sleep(Duration::from_millis(10));
+                }
+                sleep(Duration::from_millis(100));
+            }
+
+            drop(guard);
+
+            for mut handle in handles {
+                assert!(handle.wait().unwrap().success());
+            }
+
+            assert_eq!(served.get()?, u64::from(PROCESSES));
+        } else {
+            waiting.increment()?;
+
+            let _guard = lock.lock()?;
+            assert_eq!(served.increment()?, u64::from(proc_num));
+            sleep(Duration::from_millis(20));
+        }
+
+        Ok(())
+    }
+
+    // The holder exits without unlocking, so its turn is skipped and the
+    // mutex that the skip takes is abandoned.
+    #[test]
+    #[cfg(windows)]
+    fn ticket_lock_abandoned() -> Result<()> {
+        let (proc_num, uuid) = proc_num_and_uuid();
+        let mut lock =
+            NamedLockOptions::new().ticket_lock(true).create(&uuid)?;
+
+        if proc_num == 0 {
+            lock.set_abandoned_policy(AbandonedPolicy::Error);
+
+            let status = call_proc("tests::ticket_lock_abandoned", 1, &uuid)
+                .wait()
+                .unwrap();
+            assert!(status.success());
+
+            assert!(matches!(lock.lock(), Err(Error::Abandoned)));
+            lock.try_lock()?;
+        } else {
+            std::mem::forget(lock.lock()?);
+            std::process::exit(0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_names() {
+        assert!(matches!(NamedLock::create(""), Err(Error::EmptyName)));
+
+        assert!(matches!(
+            NamedLock::create("abc/"),
+            Err(Error::InvalidCharacter)
+        ));
+
+        assert!(matches!(
+            NamedLock::create("abc\\"),
+            Err(Error::InvalidCharacter)
+        ));
+
+        assert!(matches!(
+            NamedLock::create("abc\0"),
+            Err(Error::InvalidCharacter)
+        ));
+    }
+
+    #[test]
+    fn per_user_names() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create_per_user(&uuid)?;
+        let same_user = NamedLock::create_per_user(&uuid)?;
+
+        let _guard = lock.try_lock()?;
+        assert!(matches!(same_user.try_lock(), Err(Error::WouldBlock)));
+
+        // Not the same lock as the one without a user.
+        let _unscoped = NamedLock::create(&uuid)?.try_lock()?;
+
+        // Other users get other names.
+        assert_eq!(per_user_name("foo", "1000"), "foo@user-1000");
+        assert_ne!(per_user_name("foo", "1000"), per_user_name("foo", "1001"));
+        assert_eq!(
+            per_user_name("foo", "S-1-5-21-1"),
+            per_user_name("foo", "S-1-5-21-1")
+        );
+
+        assert!(matches!(
+            NamedLock::create_per_user(""),
+            Err(Error::EmptyName)
+        ));
+        assert!(matches!(
+            NamedLock::create_per_user("a/b"),
+            Err(Error::InvalidCharacter)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn app_lock() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let app = format!("com.example.{}", uuid);
+        let other_app = format!("org.example.{}", uuid);
+
+        let lock = AppLock::new(&app, "database")?;
+        let _guard = lock.try_lock()?;
+
+        // Instances of the same app collide.
+        let same_app = AppLock::new(&app, "database")?;
+        assert!(matches!(same_app.try_lock(), Err(Error::WouldBlock)));
+
+        // Other apps and plain locks with the same name do not.
+        let _other = AppLock::new(&other_app, "database")?.try_lock()?;
+        let _plain =
+            NamedLock::create_namespaced(&uuid, "database")?.try_lock()?;
+
+        // Reserved characters are encoded, so the ID is a single namespace.
+        let nested = format!("{}/app", uuid);
+        let _nested = AppLock::new(&nested, "database")?.try_lock()?;
+        let _ns =
+            NamedLock::create_namespaced(&nested, "database")?.try_lock()?;
+
+        assert!(matches!(AppLock::new("", "database"), Err(Error::EmptyName)));
+        assert!(matches!(
+            AppLock::new("..", "database"),
+            Err(Error::InvalidCharacter)
+        ));
+        assert!(matches!(
+            AppLock::new(&app, "a/b"),
+            Err(Error::InvalidCharacter)
+        ));
+
+        let inner = same_app.into_inner();
+        assert!(matches!(inner.try_lock(), Err(Error::WouldBlock)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn versioned_names() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let v1 = NamedLock::create_versioned(&uuid, "v1")?;
+        let v1_again = NamedLock::create_versioned(&uuid, "v1")?;
+        let v2 = NamedLock::create_versioned(&uuid, "v2")?;
+        let reserved = NamedLock::create_versioned(&uuid, "v2/\\\0@%")?;
+
+        let _guard1 = v1.try_lock()?;
+        assert!(matches!(v1_again.try_lock(), Err(Error::WouldBlock)));
+
+        // Different generations must not collide.
+        let _guard2 = v2.try_lock()?;
+        let _guard3 = reserved.try_lock()?;
+
+        assert!(matches!(
+            NamedLock::create_versioned(&uuid, ""),
+            Err(Error::EmptyName)
+        ));
+
+        assert_eq!(encode_component("a@b%c/d"), "a%40b%25c%2Fd");
+
+        // Plain names can not look like versioned ones.
+        assert!(matches!(
+            NamedLock::create(&format!("{}@v2", uuid)),
+            Err(Error::InvalidCharacter)
+        ));
+        assert!(matches!(
+            NamedLock::create_versioned(&format!("{}@v1", uuid), "v2"),
+            Err(Error::InvalidCharacter)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn acquire_modes() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock1 = NamedLock::create(&uuid)?;
+        let lock2 = NamedLock::create(&uuid)?;
+
+        let acquired = lock1.acquire(AcquireMode::Blocking)?;
+        assert!(!acquired.contended());
+        assert!(!acquired.abandoned());
+
+        assert!(matches!(
+            lock2.acquire(AcquireMode::NonBlocking),
+            Err(Error::WouldBlock)
+        ));
+
+        let start = Instant::now();
+        assert!(matches!(
+            lock2.acquire(AcquireMode::Timeout(Duration::from_millis(100))),
+            Err(Error::WouldBlock)
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        let guard = acquired.into_guard();
+        let handle = std::thread::spawn(move || {
+            sleep(Duration::from_millis(100));
+            drop(guard);
+        });
+
+        let acquired =
+            lock2.acquire(AcquireMode::Timeout(Duration::from_secs(10)))?;
+        assert!(acquired.contended());
+        assert!(acquired.wait_duration() >= Duration::from_millis(50));
+        handle.join().unwrap();
+        drop(acquired);
+
+        let acquired = lock2.acquire(AcquireMode::NonBlocking)?;
+        assert!(!acquired.contended());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn with_path_named() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let dir = NamedLock::default_lock_dir();
+
+        let lock1 = NamedLock::create(&uuid)?;
+        let lock2 = NamedLock::with_path_named(&dir, &uuid)?;
+        assert!(Arc::ptr_eq(&lock1.raw, &lock2.raw));
+        assert!(dir.join(format!("{}.lock", uuid)).exists());
+
+        let _guard = lock1.try_lock()?;
         assert!(matches!(lock2.try_lock(), Err(Error::WouldBlock)));
 
-        // Unlock by dropping the `guard1`
-        drop(guard1);
-        let _guard2 = lock2.try_lock()?;
+        assert!(matches!(
+            NamedLock::with_path_named(&dir, "abc/"),
+            Err(Error::InvalidCharacter)
+        ));
 
         Ok(())
     }
 
     #[test]
-    fn invalid_names() {
-        assert!(matches!(NamedLock::create(""), Err(Error::EmptyName)));
+    #[cfg(target_os = "linux")]
+    fn with_dir_fd() -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let dir_path = NamedLock::default_lock_dir().join(&uuid);
+        std::fs::create_dir(&dir_path).unwrap();
+        let dir = std::fs::File::open(&dir_path).unwrap();
+
+        let lock1 = NamedLock::with_dir_fd(dir.as_raw_fd(), "foo")?;
+        assert!(dir_path.join("foo.lock").is_file());
+
+        let lock2 = NamedLock::with_path_named(&dir_path, "foo")?;
+        assert!(Arc::ptr_eq(&lock1.raw, &lock2.raw));
+
+        let _guard = lock1.try_lock()?;
+        assert!(matches!(lock2.try_lock(), Err(Error::WouldBlock)));
 
         assert!(matches!(
-            NamedLock::create("abc/"),
+            NamedLock::with_dir_fd(dir.as_raw_fd(), "abc/"),
             Err(Error::InvalidCharacter)
         ));
 
+        let file = std::fs::File::open(dir_path.join("foo.lock")).unwrap();
         assert!(matches!(
-            NamedLock::create("abc\\"),
+            NamedLock::with_dir_fd(file.as_raw_fd(), "bar"),
+            Err(Error::CreateFailed(_))
+        ));
+
+        std::fs::remove_dir_all(&dir_path).unwrap();
+
+        Ok(())
+    }
+
+    #[test]
+    fn exists() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+
+        assert!(!NamedLock::exists(&uuid)?);
+
+        let lock = NamedLock::create(&uuid)?;
+        assert!(NamedLock::exists(&uuid)?);
+
+        #[cfg(unix)]
+        {
+            drop(lock);
+            assert!(NamedLock::exists(&uuid)?);
+            std::fs::remove_file(NamedLock::resolved_name(&uuid)?).unwrap();
+            assert!(!NamedLock::exists(&uuid)?);
+        }
+        #[cfg(windows)]
+        {
+            drop(lock);
+            assert!(!NamedLock::exists(&uuid)?);
+        }
+
+        assert!(matches!(NamedLock::exists(""), Err(Error::EmptyName)));
+
+        Ok(())
+    }
+
+    // `fd-lock` locks the whole file with `flock` on UNIX, so its guards
+    // and the guards of a lock on the same path exclude each other.
+    #[test]
+    #[cfg(unix)]
+    fn fd_lock_interop() -> Result<()> {
+        use std::io::ErrorKind;
+
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let path = env::temp_dir().join(format!("{}.lock", uuid));
+        let lock = NamedLock::with_path(&path)?;
+
+        let file = std::fs::File::open(&path).unwrap();
+        let mut fd_lock = fd_lock::RwLock::new(file);
+
+        {
+            let _read = fd_lock.try_read().unwrap();
+            assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+        }
+
+        {
+            let _write = fd_lock.try_write().unwrap();
+            assert!(matches!(lock.try_lock(), Err(Error::WouldBlock)));
+        }
+
+        let guard = lock.try_lock()?;
+        assert!(matches!(
+            fd_lock.try_read(),
+            Err(e) if e.kind() == ErrorKind::WouldBlock
+        ));
+        assert!(matches!(
+            fd_lock.try_write(),
+            Err(e) if e.kind() == ErrorKind::WouldBlock
+        ));
+
+        drop(guard);
+        drop(fd_lock.try_write().unwrap());
+
+        Ok(())
+    }
+
+    #[test]
+    fn for_path() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let dir = env::temp_dir().join(&uuid);
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("a"), "").unwrap();
+
+        let lock = NamedLock::for_path(dir.join("a"))?;
+        let same = NamedLock::for_path(dir.join(".").join("a"))?;
+        assert!(Arc::ptr_eq(&lock.raw, &same.raw));
+
+        let _guard = lock.try_lock()?;
+        assert!(matches!(same.try_lock(), Err(Error::WouldBlock)));
+
+        let other = NamedLock::for_path(dir.join("b"))?;
+        assert!(!Arc::ptr_eq(&lock.raw, &other.raw));
+        other.try_lock()?;
+
+        // The name only depends on the path, not on whether it exists.
+        let name = path_lock_name(&dir.join("b"))?;
+        std::fs::write(dir.join("b"), "").unwrap();
+        assert_eq!(path_lock_name(&dir.join("b"))?, name);
+        assert!(name.starts_with("path-"));
+        assert_eq!(name.len(), 37);
+
+        assert!(matches!(
+            NamedLock::for_path(dir.join("missing").join("c")),
+            Err(Error::Io(_))
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        Ok(())
+    }
+
+    #[test]
+    fn fnv1a_128() {
+        assert_eq!(super::fnv1a_128(*b""), 0x6c62272e07bb014262b821756295c58d);
+        assert_eq!(super::fnv1a_128(*b"a"), 0xd228cb696f1a8caf78912b704e4a8964);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn scan_stale() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let dir = env::temp_dir().join(&uuid);
+        std::fs::create_dir(&dir).unwrap();
+
+        let held = NamedLock::with_path_named(&dir, "held")?;
+        let opened = NamedLock::with_path_named(&dir, "opened")?;
+        let _ = NamedLock::with_path_named(&dir, "closed")?;
+        std::fs::write(dir.join("other.txt"), "").unwrap();
+        std::fs::create_dir(dir.join("dir.lock")).unwrap();
+
+        let _guard = held.lock()?;
+        assert_eq!(
+            NamedLock::scan_stale(&dir)?,
+            [dir.join("closed.lock"), dir.join("opened.lock")]
+        );
+
+        // Scanning does not disturb the locks.
+        assert!(matches!(held.try_lock(), Err(Error::WouldBlock)));
+        let _guard = opened.try_lock()?;
+        assert_eq!(NamedLock::scan_stale(&dir)?, [dir.join("closed.lock")]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(NamedLock::scan_stale(&dir), Err(Error::Io(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn lock_kinds() -> Result<()> {
+        let machine = resolved_name_of_kind("foo", LockKind::Machine)?;
+        assert_eq!(machine, NamedLock::resolved_name("foo")?);
+
+        #[cfg(unix)]
+        {
+            let vars = |dir: Option<&'static str>| {
+                move |var: &OsStr| {
+                    assert_eq!(var, "XDG_RUNTIME_DIR");
+                    dir.map(OsString::from)
+                }
+            };
+
+            assert_eq!(
+                session_lock_dir(vars(Some("/run/user/1000")))?,
+                Path::new("/run/user/1000")
+            );
+            assert!(matches!(
+                session_lock_dir(vars(None)),
+                Err(Error::CreateFailed(_))
+            ));
+            assert!(matches!(
+                session_lock_dir(vars(Some(""))),
+                Err(Error::CreateFailed(_))
+            ));
+
+            match env::var_os("XDG_RUNTIME_DIR") {
+                Some(dir) if !dir.is_empty() => {
+                    let session =
+                        resolved_name_of_kind("foo", LockKind::Session)?;
+                    assert_ne!(session, machine);
+                    assert_eq!(session, Path::new(&dir).join("foo.lock"));
+                }
+                _ => assert!(matches!(
+                    NamedLockOptions::new()
+                        .kind(LockKind::Session)
+                        .create("foo"),
+                    Err(Error::CreateFailed(_))
+                )),
+            }
+        }
+
+        #[cfg(windows)]
+        {
+            let session = resolved_name_of_kind("foo", LockKind::Session)?;
+            assert_ne!(session, machine);
+            assert_eq!(session, "Local\\foo");
+
+            let uuid = Uuid::new_v4().as_hyphenated().to_string();
+            let session = NamedLockOptions::new()
+                .kind(LockKind::Session)
+                .create(&uuid)?;
+            let _guard = session.try_lock()?;
+            NamedLock::create(&uuid)?.try_lock()?;
+        }
+
+        assert!(matches!(
+            resolved_name_of_kind("a/b", LockKind::Session),
             Err(Error::InvalidCharacter)
         ));
 
+        Ok(())
+    }
+
+    #[test]
+    fn resolved_names() -> Result<()> {
+        let foo = NamedLock::resolved_name("foo")?;
+        assert_eq!(foo, NamedLock::resolved_name("foo")?);
+        assert_ne!(foo, NamedLock::resolved_name("bar")?);
+        assert_ne!(foo, NamedLock::resolved_name("foo.lock")?);
+        assert_ne!(foo, NamedLock::resolved_name("Foo")?);
+
+        #[cfg(unix)]
+        assert_eq!(foo, NamedLock::default_lock_dir().join("foo.lock"));
+
+        #[cfg(windows)]
+        assert_eq!(foo, "Global\\foo");
+
         assert!(matches!(
-            NamedLock::create("abc\0"),
+            NamedLock::resolved_name("foo/bar"),
+            Err(Error::InvalidCharacter)
+        ));
+
+        // Resolution does not create anything.
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let _resolved = NamedLock::resolved_name(&uuid)?;
+        #[cfg(unix)]
+        assert!(!_resolved.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn plan() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+
+        let plan = NamedLock::plan(&uuid)?;
+        assert!(!plan.is_opened());
+        #[cfg(unix)]
+        {
+            assert_eq!(plan.path(), NamedLock::resolved_name(&uuid)?);
+            assert!(!plan.exists());
+            assert!(plan.would_create());
+            assert!(!plan.path().exists());
+        }
+
+        let lock = NamedLock::create(&uuid)?;
+        let plan = NamedLock::plan(&uuid)?;
+        assert!(plan.is_opened());
+        #[cfg(unix)]
+        {
+            assert!(plan.exists());
+            assert!(!plan.would_create());
+        }
+
+        drop(lock);
+        let plan = NamedLock::plan(&uuid)?;
+        assert!(!plan.is_opened());
+        #[cfg(unix)]
+        assert!(plan.exists());
+
+        assert!(matches!(NamedLock::plan(""), Err(Error::EmptyName)));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn read_write_options() -> Result<()> {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLockOptions::new().read(true).create(&uuid)?;
+        let guard = lock.try_lock()?;
+
+        let mut file = guard.file();
+        file.write_all(b"content").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut content = String::new();
+        file.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "content");
+
+        // Locking is not affected by the open mode.
+        let lock2 = NamedLock::create(&uuid)?;
+        assert!(matches!(lock2.try_lock(), Err(Error::WouldBlock)));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn inheritable_fd() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+        use std::os::unix::io::AsRawFd;
+
+        // In the child, check that the fd refers to the same lock file.
+        if let Ok(fd) = env::var("TEST_INHERITABLE_FD") {
+            let fd = fd.parse::<i32>().unwrap();
+            let ino = env::var("TEST_INHERITABLE_INO").unwrap();
+
+            let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+            let inherited = unsafe { libc::fstat(fd, &mut stat) } == 0
+                && stat.st_ino.to_string() == ino;
+
+            std::process::exit(if inherited {
+                0
+            } else {
+                3
+            });
+        }
+
+        let is_inherited = |inheritable: bool| {
+            let uuid = Uuid::new_v4().as_hyphenated().to_string();
+            let lock = NamedLockOptions::new()
+                .inheritable(inheritable)
+                .create(&uuid)?;
+            let guard = lock.lock()?;
+            let file = guard.file();
+
+            let status = Command::new(env::current_exe().expect("no exe"))
+                .env("TEST_INHERITABLE_FD", file.as_raw_fd().to_string())
+                .env(
+                    "TEST_INHERITABLE_INO",
+                    file.metadata().unwrap().ino().to_string(),
+                )
+                .arg("tests::inheritable_fd")
+                .status()
+                .unwrap();
+
+            assert!(status.success() || status.code() == Some(3));
+            Ok(status.success())
+        };
+
+        assert!(!is_inherited(false)?);
+        assert!(is_inherited(true)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn guard_name() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let guard = NamedLock::create(&uuid)?.lock()?;
+
+        #[cfg(unix)]
+        assert_eq!(guard.path(), NamedLock::resolved_name(&uuid)?);
+        #[cfg(windows)]
+        assert_eq!(guard.name(), NamedLock::resolved_name(&uuid)?);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn guard_file() -> Result<()> {
+        use std::io::Write;
+
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let lock = NamedLock::create(&uuid)?;
+
+        {
+            let guard = lock.lock()?;
+            let mut file = guard.file();
+            file.set_len(0).unwrap();
+            file.write_all(b"protected").unwrap();
+        }
+
+        let content =
+            std::fs::read_to_string(NamedLock::resolved_name(&uuid)?).unwrap();
+        assert_eq!(content, "protected");
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn directory_at_lock_path() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let path = NamedLock::resolved_name(&uuid)?;
+        std::fs::create_dir(&path).unwrap();
+
+        let res = NamedLock::create(&uuid);
+        std::fs::remove_dir(&path).unwrap();
+        assert!(matches!(res, Err(Error::NotAFile)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn namespaced() -> Result<()> {
+        let uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let nested = format!("{}/cache", uuid);
+
+        let lock1 = NamedLock::create_namespaced(&nested, "foo")?;
+        let lock2 = NamedLock::create_namespaced(&nested, "foo")?;
+        let other = NamedLock::create_namespaced(&uuid, "foo")?;
+
+        #[cfg(unix)]
+        assert!(NamedLock::default_lock_dir()
+            .join(&uuid)
+            .join("cache")
+            .join("foo.lock")
+            .is_file());
+
+        let _guard1 = lock1.try_lock()?;
+        assert!(matches!(lock2.try_lock(), Err(Error::WouldBlock)));
+        let _guard2 = other.try_lock()?;
+
+        for namespace in ["..", "a/../b", "./a", "a/."] {
+            assert!(matches!(
+                NamedLock::create_namespaced(namespace, "foo"),
+                Err(Error::InvalidCharacter)
+            ));
+        }
+
+        assert!(matches!(
+            NamedLock::create_namespaced("a//b", "foo"),
+            Err(Error::EmptyName)
+        ));
+        assert!(matches!(
+            NamedLock::create_namespaced("a\\b", "foo"),
+            Err(Error::InvalidCharacter)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reserved_chars() {
+        for c in RESERVED_CHARS {
+            assert!(matches!(
+                NamedLock::create(&format!("abc{}", c)),
+                Err(Error::InvalidCharacter)
+            ));
+        }
+
+        assert!(matches!(
+            NamedLock::create("abc@"),
             Err(Error::InvalidCharacter)
         ));
+
+        for c in ['.', '-', '_', ':', '%', ' ', 'λ'] {
+            assert!(!RESERVED_CHARS.contains(&c));
+            assert!(validate_name(&format!("abc{}", c)).is_ok());
+        }
     }
 
     #[test]
     fn check_traits() {
-        assert_impl_all!(NamedLock: Debug, Send, Sync);
+        assert_impl_all!(NamedLock: Clone, Debug, Send, Sync);
         assert_impl_all!(NamedLockGuard: Debug, Send, Sync);
+        assert_impl_all!(LockStatus: Clone, Debug, Display, Send, Sync);
+        assert_impl_all!(LazyNamedLock: Clone, Debug, Send, Sync);
+        assert_impl_all!(LockHandle: Clone, Debug, Send, Sync);
+        #[cfg(unix)]
+        assert_impl_all!(SignalCleanup: Debug, Send, Sync);
+        #[cfg(unix)]
+        assert_impl_all!(RenewingGuard: Debug, Send, Sync);
+        assert_impl_all!(Acquired: Debug, Send, Sync);
+        assert_impl_all!(WeakNamedLock: Clone, Debug, Send, Sync);
+        assert_impl_all!(GuardWith<u32>: Debug, Send, Sync);
+        assert_impl_all!(RetryPolicy: Clone, Debug, Send, Sync);
+        assert_impl_all!(CancelToken: Clone, Debug, Send, Sync);
+        assert_impl_all!(LockName: Clone, Debug, Ord, std::hash::Hash, Send, Sync);
+        assert_impl_all!(Backend: Clone, Copy, Debug, Send, Sync);
+        assert_impl_all!(AppLock: Clone, Debug, Send, Sync);
+        assert_impl_all!(SingleInstance: Debug, Send, Sync);
+        assert_impl_all!(AbandonedPolicy: Clone, Debug, Send, Sync);
+        assert_impl_all!(RawNamedMutex<()>: Debug, Send, Sync);
+        #[cfg(unix)]
+        assert_impl_all!(NamedCounter: Clone, Debug, Send, Sync);
+        #[cfg(unix)]
+        assert_impl_all!(LockInterrupter: Clone, Debug, Send, Sync);
+        #[cfg(target_os = "linux")]
+        assert_impl_all!(AbstractSocketLock: Clone, Debug, Send, Sync);
+        #[cfg(target_os = "linux")]
+        assert_impl_all!(AbstractSocketLockGuard: Debug, Send, Sync);
+        #[cfg(unix)]
+        assert_impl_all!(NamedLockGroup: Clone, Debug, Send, Sync);
+        #[cfg(unix)]
+        assert_impl_all!(SubLock: Clone, Debug, Send, Sync);
+        #[cfg(unix)]
+        assert_impl_all!(SubLockGuard: Debug, Send, Sync);
     }
 }
@@ -0,0 +1,104 @@
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(unix)]
+use std::path::Path;
+
+use crate::error::*;
+use crate::{NameType, NamedLock};
+
+/// Resolved name of a lock, which orders locks the same way in every
+/// process.
+///
+/// Names are compared byte by byte on the path of the lock file (on UNIX)
+/// or on the mutex name (on Windows), so every process that resolves the
+/// names the same way sorts them in the same order. [NamedLock::lock_all]
+/// acquires locks in this order, and callers that lock several names
+/// themselves can sort them with it as well.
+///
+/// ```rust
+/// use named_lock::LockName;
+/// use named_lock::Result;
+///
+/// fn main() -> Result<()> {
+///     let mut names = vec![LockName::new("foo")?, LockName::new("bar")?];
+///     names.sort();
+///
+///     assert_eq!(names[0], LockName::new("bar")?);
+///
+///     Ok(())
+/// }
+/// ```
+///
+/// [NamedLock::lock_all]: crate::NamedLock::lock_all
+#[derive(Debug, Clone)]
+pub struct LockName {
+    pub(crate) name: NameType,
+}
+
+impl LockName {
+    /// Resolve `name` like [NamedLock::create] does.
+    ///
+    /// Nothing is created. See [NamedLock::resolved_name].
+    ///
+    /// [NamedLock::create]: crate::NamedLock::create
+    /// [NamedLock::resolved_name]: crate::NamedLock::resolved_name
+    pub fn new(name: &str) -> Result<LockName> {
+        Ok(LockName {
+            name: NamedLock::resolved_name(name)?,
+        })
+    }
+
+    /// Path of the lock file.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn path(&self) -> &Path {
+        &self.name
+    }
+
+    /// Name of the mutex.
+    #[cfg(windows)]
+    #[cfg_attr(docsrs, doc(cfg(windows)))]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // `Path` compares by components, which does not agree with comparing
+    // the bytes, so the bytes are used everywhere.
+    #[cfg(unix)]
+    fn as_bytes(&self) -> &[u8] {
+        self.name.as_os_str().as_bytes()
+    }
+
+    #[cfg(windows)]
+    fn as_bytes(&self) -> &[u8] {
+        self.name.as_bytes()
+    }
+}
+
+impl PartialEq for LockName {
+    fn eq(&self, other: &LockName) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for LockName {}
+
+impl PartialOrd for LockName {
+    fn partial_cmp(&self, other: &LockName) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for LockName {
+    fn cmp(&self, other: &LockName) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl Hash for LockName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state);
+    }
+}
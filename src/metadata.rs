@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Process ID of the holder.
+pub(crate) const PID: &str = "pid";
+// Start time of the holder, in clock ticks since boot (Linux only). Tells
+// the holder apart from a later process that reuses its ID.
+pub(crate) const START: &str = "start";
+// Set by `lock_and_init` once the initialization completed.
+pub(crate) const INITIALIZED: &str = "initialized";
+// Protocol version of the last holder that used `lock_with_version`.
+pub(crate) const VERSION: &str = "version";
+// When the lease of the holder started, in milliseconds since the UNIX
+// epoch, and how long it lasts in milliseconds.
+pub(crate) const ACQUIRED: &str = "acquired";
+pub(crate) const TTL: &str = "ttl";
+
+// Some features store metadata in the lock file, one `key=value` per line.
+//
+// The metadata is always written while holding the lock, but it can be
+// read by anyone at any time, so readers must be prepared for partial or
+// stale content.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Metadata {
+    entries: BTreeMap<String, String>,
+}
+
+impl Metadata {
+    pub(crate) fn read(path: &Path) -> io::Result<Metadata> {
+        match fs::read(path) {
+            Ok(content) => {
+                Ok(Metadata::parse(&String::from_utf8_lossy(&content)))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Ok(Metadata::default())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn parse(content: &str) -> Metadata {
+        let entries = content
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(k, v)| (k.trim().to_owned(), v.trim().to_owned()))
+            .collect();
+
+        Metadata {
+            entries,
+        }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+
+    pub(crate) fn set<T>(&mut self, key: &str, value: T)
+    where
+        T: ToString,
+    {
+        self.entries.insert(key.to_owned(), value.to_string());
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) {
+        self.entries.remove(key);
+    }
+
+    pub(crate) fn set_lease(&mut self, now: SystemTime, ttl: Duration) {
+        self.set(ACQUIRED, millis_since_epoch(now));
+        self.set(TTL, millis(ttl));
+    }
+
+    pub(crate) fn remove_lease(&mut self) {
+        self.remove(ACQUIRED);
+        self.remove(TTL);
+    }
+
+    // A lease without valid timestamps never expires.
+    pub(crate) fn is_lease_expired(&self, now: SystemTime) -> bool {
+        let get = |key| self.get(key).and_then(|v| v.parse::<u64>().ok());
+
+        match (get(ACQUIRED), get(TTL)) {
+            (Some(acquired), Some(ttl)) => {
+                millis_since_epoch(now) >= acquired.saturating_add(ttl)
+            }
+            _ => false,
+        }
+    }
+
+    pub(crate) fn write(&self, file: &File) -> io::Result<()> {
+        let content = self
+            .entries
+            .iter()
+            .map(|(k, v)| format!("{}={}\n", k, v))
+            .collect::<String>();
+
+        // Write before truncating, so readers never see an empty file
+        // when a key is only updated.
+        file.write_all_at(content.as_bytes(), 0)?;
+        file.set_len(content.len() as u64)
+    }
+}
+
+// Returns the start time of process `pid` from `/proc/<pid>/stat`, or
+// `None` if it is not running or the start time is unknown.
+#[cfg(target_os = "linux")]
+pub(crate) fn process_start_time(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+
+    // The command name is in parentheses and may contain anything, so the
+    // fields are counted from the last `)`. The start time is field 22,
+    // and the state right after the name is field 3.
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(22 - 3)?.parse().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn process_start_time(_pid: u32) -> Option<u64> {
+    None
+}
+
+// Returns `true` if process `pid` exists, even if it belongs to another
+// user.
+pub(crate) fn process_exists(pid: u32) -> bool {
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) if pid > 0 => pid,
+        _ => return false,
+    };
+
+    let res = unsafe { libc::kill(pid, 0) };
+    res == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+// A clock before the UNIX epoch counts as the epoch.
+fn millis_since_epoch(time: SystemTime) -> u64 {
+    millis(time.duration_since(UNIX_EPOCH).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let mut meta = Metadata::parse("pid=12\n\ngarbage\n name = foo=bar \n");
+        assert_eq!(meta.get("pid"), Some("12"));
+        assert_eq!(meta.get("name"), Some("foo=bar"));
+        assert_eq!(meta.get("garbage"), None);
+
+        meta.set("pid", 13);
+        meta.remove("name");
+        assert_eq!(meta, Metadata::parse("pid=13"));
+    }
+
+    #[test]
+    fn lease() {
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let mut meta = Metadata::default();
+        assert!(!meta.is_lease_expired(at(100)));
+
+        meta.set_lease(at(100), Duration::from_secs(10));
+        assert_eq!(meta.get(ACQUIRED), Some("100000"));
+        assert_eq!(meta.get(TTL), Some("10000"));
+        assert!(!meta.is_lease_expired(at(100)));
+        assert!(!meta.is_lease_expired(at(109)));
+        assert!(meta.is_lease_expired(at(110)));
+
+        meta.set(TTL, "garbage");
+        assert!(!meta.is_lease_expired(at(200)));
+
+        meta.set_lease(at(100), Duration::MAX);
+        assert!(!meta.is_lease_expired(at(u64::MAX / 1000)));
+
+        meta.remove_lease();
+        assert_eq!(meta, Metadata::default());
+    }
+
+    #[test]
+    fn process() {
+        let pid = std::process::id();
+        assert!(process_exists(pid));
+        assert!(process_exists(1));
+        assert!(!process_exists(0));
+        assert!(!process_exists(u32::MAX));
+
+        #[cfg(target_os = "linux")]
+        {
+            let start = process_start_time(pid);
+            assert!(start.is_some());
+            assert_eq!(process_start_time(pid), start);
+            assert!(process_start_time(1) <= start);
+        }
+    }
+}
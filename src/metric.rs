@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+use crate::error::*;
+use crate::LockState;
+
+// Records the outcome of an acquisition with the `metrics` facade:
+// `named_lock.acquired` and `named_lock.wait_seconds` on success, and
+// `named_lock.would_block` if the lock was held. Other errors are not
+// recorded. `wait` is only called on success, so it costs nothing without
+// the feature.
+#[cfg(feature = "metrics")]
+pub(crate) fn record<T, F>(state: &LockState, res: &Result<T>, wait: F)
+where
+    F: FnOnce() -> Duration,
+{
+    match res {
+        Ok(_) => acquired(state, wait()),
+        Err(Error::WouldBlock) => would_block(state),
+        Err(_) => {}
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+#[inline(always)]
+pub(crate) fn record<T, F>(_state: &LockState, _res: &Result<T>, _wait: F)
+where
+    F: FnOnce() -> Duration,
+{
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn acquired(state: &LockState, wait: Duration) {
+    let name = label(state);
+
+    metrics::counter!("named_lock.acquired", "name" => name.clone())
+        .increment(1);
+    metrics::histogram!("named_lock.wait_seconds", "name" => name)
+        .record(wait.as_secs_f64());
+}
+
+#[cfg(not(feature = "metrics"))]
+#[inline(always)]
+pub(crate) fn acquired(_state: &LockState, _wait: Duration) {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn would_block(state: &LockState) {
+    metrics::counter!("named_lock.would_block", "name" => label(state))
+        .increment(1);
+}
+
+#[cfg(not(feature = "metrics"))]
+#[inline(always)]
+pub(crate) fn would_block(_state: &LockState) {}
+
+// The resolved name, i.e. the path of the lock file on UNIX and the name of
+// the mutex on Windows.
+#[cfg(all(feature = "metrics", unix))]
+fn label(state: &LockState) -> String {
+    state.name.lock().to_string_lossy().into_owned()
+}
+
+#[cfg(all(feature = "metrics", windows))]
+fn label(state: &LockState) -> String {
+    state.name.lock().clone()
+}
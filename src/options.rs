@@ -0,0 +1,328 @@
+#[cfg(unix)]
+use std::path::Path;
+
+use crate::error::*;
+use crate::{resolved_name_of_kind, NameType, NamedLock, NamedLockGuard};
+
+/// Options and flags which can be used to configure how a named lock
+/// is opened.
+///
+/// This is a builder in the style of [`std::fs::OpenOptions`].
+/// [NamedLock::create] and [NamedLock::with_path] use the default options.
+///
+/// Options take effect when this process opens the underlying OS lock for
+/// the first time. If the lock is already open in this process, the
+/// existing handle is reused as is.
+///
+/// ```rust
+/// use named_lock::NamedLockOptions;
+/// use named_lock::Result;
+///
+/// fn main() -> Result<()> {
+///     let lock = NamedLockOptions::new().create("foobar")?;
+///     let _guard = lock.lock()?;
+///
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct NamedLockOptions {
+    pub(crate) kind: LockKind,
+    #[cfg(unix)]
+    pub(crate) read: bool,
+    #[cfg(unix)]
+    pub(crate) inheritable: bool,
+    #[cfg(unix)]
+    pub(crate) record_holder: bool,
+    #[cfg(unix)]
+    pub(crate) ttl: Option<std::time::Duration>,
+    // Extension of the lock file, `None` for the default `lock`. An empty
+    // extension means no suffix.
+    #[cfg(unix)]
+    pub(crate) extension: Option<String>,
+    #[cfg(unix)]
+    pub(crate) existing_file: ExistingFilePolicy,
+    // Lock a directory itself instead of a lock file.
+    #[cfg(unix)]
+    pub(crate) directory: bool,
+    // Create the mutex with a DACL that allows access from other sessions.
+    #[cfg(windows)]
+    pub(crate) cross_session: bool,
+    #[cfg(windows)]
+    pub(crate) ticket_lock: bool,
+    // Directory that the lock file is opened relative to with `openat`.
+    #[cfg(target_os = "linux")]
+    pub(crate) dir_fd: Option<std::os::unix::io::RawFd>,
+}
+
+impl NamedLockOptions {
+    /// Create a new set of options with the default configuration.
+    pub fn new() -> NamedLockOptions {
+        NamedLockOptions::default()
+    }
+
+    /// Set the scope in which the lock is exclusive.
+    ///
+    /// The default is [LockKind::Machine]. See [LockKind] for how each kind
+    /// maps to the platform.
+    pub fn kind(&mut self, kind: LockKind) -> &mut NamedLockOptions {
+        self.kind = kind;
+        self
+    }
+
+    /// Open the lock file for reading too, not only for writing.
+    ///
+    /// This allows the content of the lock file to be read back through
+    /// the same handle while holding the lock. It has no effect on the
+    /// locking itself.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn read(&mut self, read: bool) -> &mut NamedLockOptions {
+        self.read = read;
+        self
+    }
+
+    /// Allow the lock file descriptor to be inherited by child processes.
+    ///
+    /// By default the lock file is opened with `O_CLOEXEC`, so it is closed
+    /// on `exec`. With this option the descriptor survives `exec` and since
+    /// [`flock`] locks belong to the open file, a child that inherits it
+    /// keeps holding the lock even after this process unlocks or exits.
+    ///
+    /// This is advanced usage: every program that you spawn inherits the
+    /// descriptor, which leaks the lock file into processes that may not
+    /// expect it and may hold the lock longer than intended.
+    ///
+    /// [`flock`]: https://linux.die.net/man/2/flock
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn inheritable(&mut self, inheritable: bool) -> &mut NamedLockOptions {
+        self.inheritable = inheritable;
+        self
+    }
+
+    /// Record the process ID of the holder in the lock file.
+    ///
+    /// The ID is written when the lock is acquired and removed when it is
+    /// released, so other processes can find out who holds the lock with
+    /// [NamedLock::try_lock_or_holder]. On Linux the start time of the
+    /// holder is recorded as well, so that [NamedLock::is_stale] can tell
+    /// the holder apart from a later process with the same ID. This
+    /// overwrites any other content of the lock file.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn record_holder(
+        &mut self,
+        record_holder: bool,
+    ) -> &mut NamedLockOptions {
+        self.record_holder = record_holder;
+        self
+    }
+
+    /// Record an advisory lease of `ttl` in the lock file.
+    ///
+    /// When the lock is acquired, the time of acquisition and `ttl` are
+    /// written to the lock file, and they are removed when it is released.
+    /// Other processes can then find out with [NamedLock::is_expired] if
+    /// the holder has kept the lock for longer than it promised, e.g.
+    /// because it is stuck.
+    ///
+    /// The lease is only information: an expired lease does not release
+    /// the lock and the lock can not be taken away from a holder that is
+    /// alive. What to do about a stuck holder, like killing it, is up to the
+    /// caller. The lease uses the system clock, so the clocks of the
+    /// machines that share the lock must agree.
+    ///
+    /// This overwrites any other content of the lock file, except what
+    /// [NamedLockOptions::record_holder] writes.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn ttl(&mut self, ttl: std::time::Duration) -> &mut NamedLockOptions {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Set the extension of the lock file that [NamedLockOptions::create]
+    /// uses.
+    ///
+    /// The default is `lock`, so `foobar` is locked with `foobar.lock`.
+    /// `Some("pid")` uses `foobar.pid` instead, and `None` or an empty
+    /// extension uses `foobar` without any suffix. A leading `.` is
+    /// optional.
+    ///
+    /// The extension must not contain any of [RESERVED_CHARS], otherwise
+    /// creating the lock fails with `Error::InvalidCharacter`. It has no
+    /// effect on [NamedLockOptions::with_path].
+    ///
+    /// [RESERVED_CHARS]: crate::RESERVED_CHARS
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn extension(
+        &mut self,
+        extension: Option<&str>,
+    ) -> &mut NamedLockOptions {
+        let extension = extension.unwrap_or("");
+        let extension = extension.strip_prefix('.').unwrap_or(extension);
+
+        self.extension = Some(extension.to_owned());
+        self
+    }
+
+    /// Set what happens if the lock file already exists.
+    ///
+    /// The default is [ExistingFilePolicy::Reuse]. See
+    /// [ExistingFilePolicy] for the other policies.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn existing_file(
+        &mut self,
+        policy: ExistingFilePolicy,
+    ) -> &mut NamedLockOptions {
+        self.existing_file = policy;
+        self
+    }
+
+    /// Serve the processes that wait for the lock roughly in the order in
+    /// which they started to wait.
+    ///
+    /// Windows mutexes give no guarantee in which order waiters get them,
+    /// so under contention a process can starve. With this option every
+    /// waiter draws a ticket from a counter in named shared memory and only
+    /// waits for the mutex once it is its turn. The turn is passed on when
+    /// the lock is released, and a named event wakes up the waiters.
+    /// [NamedLock::backend] returns [Backend::TicketLock].
+    ///
+    /// # Notes
+    ///
+    /// * This is slower than the plain mutex. Every acquisition and release
+    ///   touches the shared memory as well, and a process that has to wait
+    ///   is woken up by the event instead of the mutex, which adds a
+    ///   context switch. Handing the lock over takes tens of microseconds
+    ///   more, and up to 10 ms if a wakeup is missed.
+    /// * A waiter that gives up, e.g. after an [AcquireMode::Timeout], or
+    ///   exits while it waits holds up the ones behind it for about 50 ms,
+    ///   until its turn is skipped. Methods that wait in a loop of timeouts,
+    ///   like [NamedLock::lock_with_progress], lose their place in the
+    ///   queue every time.
+    /// * Every process has to open the lock with this option. A process
+    ///   with the plain mutex still excludes the others, but does not
+    ///   queue.
+    ///
+    /// [NamedLock::backend]: crate::NamedLock::backend
+    /// [AcquireMode::Timeout]: crate::AcquireMode::Timeout
+    /// [NamedLock::lock_with_progress]: crate::NamedLock::lock_with_progress
+    /// [Backend::TicketLock]: crate::Backend::TicketLock
+    #[cfg(windows)]
+    #[cfg_attr(docsrs, doc(cfg(windows)))]
+    pub fn ticket_lock(&mut self, ticket_lock: bool) -> &mut NamedLockOptions {
+        self.ticket_lock = ticket_lock;
+        self
+    }
+
+    /// Create/open a named lock with these options.
+    ///
+    /// See [NamedLock::create] for details.
+    pub fn create(&self, name: &str) -> Result<NamedLock> {
+        NamedLock::_create(self.resolved_name(name)?, self)
+    }
+
+    /// Create/open a named lock with these options and lock it without
+    /// blocking.
+    ///
+    /// This returns the lock together with the guard, or `Error::WouldBlock`
+    /// if the lock is held.
+    ///
+    /// # Notes
+    ///
+    /// * On Windows, if the mutex does not exist yet, it is created already
+    ///   owned by the current thread (`bInitialOwner`), so no other process
+    ///   can lock it between the creation and the locking.
+    /// * On UNIX there is no atomic create-and-lock with [`flock`], so this
+    ///   is the same as [NamedLockOptions::create] followed by
+    ///   [NamedLock::try_lock].
+    ///
+    /// [`flock`]: https://linux.die.net/man/2/flock
+    /// [NamedLock::try_lock]: crate::NamedLock::try_lock
+    pub fn create_locked(
+        &self,
+        name: &str,
+    ) -> Result<(NamedLock, NamedLockGuard)> {
+        NamedLock::_create_locked(self.resolved_name(name)?, self)
+    }
+
+    fn resolved_name(&self, name: &str) -> Result<NameType> {
+        let resolved = resolved_name_of_kind(name, self.kind)?;
+
+        #[cfg(unix)]
+        if let Some(extension) = &self.extension {
+            if extension.contains(crate::RESERVED_CHARS) {
+                return Err(Error::InvalidCharacter);
+            }
+
+            // Only the `.lock` suffix is replaced, since the name itself
+            // was kept as is when it was resolved.
+            return Ok(resolved.with_extension(extension));
+        }
+
+        Ok(resolved)
+    }
+
+    /// Create/open a named lock on specified path with these options.
+    ///
+    /// See [NamedLock::with_path] for details.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn with_path<P>(&self, path: P) -> Result<NamedLock>
+    where
+        P: AsRef<Path>,
+    {
+        NamedLock::_create(path.as_ref().to_owned(), self)
+    }
+}
+
+/// What to do when the lock file already exists.
+///
+/// Used by [NamedLockOptions::existing_file]. Like every option, it only
+/// applies when this process opens the lock file for the first time.
+///
+/// [NamedLockOptions::existing_file]: crate::NamedLockOptions::existing_file
+#[cfg(unix)]
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExistingFilePolicy {
+    /// Open the existing file as is.
+    #[default]
+    Reuse,
+    /// Fail with `Error::AlreadyExists`, so only a new file is used.
+    Error,
+    /// Open the existing file and truncate it.
+    ///
+    /// This also removes content that another process wrote while it holds
+    /// the lock, e.g. the holder that [NamedLockOptions::record_holder]
+    /// records.
+    ///
+    /// [NamedLockOptions::record_holder]: crate::NamedLockOptions::record_holder
+    Truncate,
+}
+
+/// Scope in which a named lock is exclusive.
+///
+/// | Kind      | UNIX                                   | Windows           |
+/// |-----------|----------------------------------------|-------------------|
+/// | `Machine` | [NamedLock::default_lock_dir]          | `Global\` prefix  |
+/// | `Session` | `$XDG_RUNTIME_DIR`                     | `Local\` prefix   |
+///
+/// On UNIX, `$XDG_RUNTIME_DIR` belongs to the user and lasts for as long as
+/// the user is logged in, so session locks are exclusive per user rather
+/// than per login session. If it is not set, creating a session lock fails
+/// with `Error::CreateFailed`.
+///
+/// [NamedLock::default_lock_dir]: crate::NamedLock::default_lock_dir
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LockKind {
+    /// Exclusive between all processes of the machine.
+    #[default]
+    Machine,
+    /// Exclusive between the processes of the current session.
+    Session,
+}
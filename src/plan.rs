@@ -0,0 +1,102 @@
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+use crate::NameType;
+
+/// Description of what [NamedLock::create] would do for a name.
+///
+/// This is returned by [NamedLock::plan]. It is a snapshot, so it may be
+/// stale by the time you look at it.
+///
+/// [NamedLock::create]: crate::NamedLock::create
+/// [NamedLock::plan]: crate::NamedLock::plan
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockPlan {
+    pub(crate) name: NameType,
+    pub(crate) opened: bool,
+    #[cfg(unix)]
+    pub(crate) exists: bool,
+    #[cfg(unix)]
+    pub(crate) dir_source: TempDirSource,
+}
+
+impl LockPlan {
+    /// Path of the lock file.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn path(&self) -> &Path {
+        &self.name
+    }
+
+    /// Name of the mutex.
+    #[cfg(windows)]
+    #[cfg_attr(docsrs, doc(cfg(windows)))]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns `true` if this process already has the lock open, in which
+    /// case `create` reuses it instead of opening it again.
+    pub fn is_opened(&self) -> bool {
+        self.opened
+    }
+
+    /// Returns `true` if the lock file exists.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn exists(&self) -> bool {
+        self.exists
+    }
+
+    /// Where the directory of the lock file comes from.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn dir_source(&self) -> &TempDirSource {
+        &self.dir_source
+    }
+
+    /// Returns `true` if `create` would need to create the lock file.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn would_create(&self) -> bool {
+        !self.exists
+    }
+}
+
+/// Source of the directory that [NamedLock::create] uses.
+///
+/// See [NamedLock::default_lock_dir] for how the directory is resolved.
+///
+/// [NamedLock::create]: crate::NamedLock::create
+/// [NamedLock::default_lock_dir]: crate::NamedLock::default_lock_dir
+#[cfg(unix)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TempDirSource {
+    /// The override variable set with [NamedLock::set_lock_dir_var].
+    ///
+    /// [NamedLock::set_lock_dir_var]: crate::NamedLock::set_lock_dir_var
+    Override(PathBuf),
+    /// `TMPDIR` environment variable.
+    TmpDirEnv(PathBuf),
+    /// `/tmp`, because no environment variable was set, or they were set
+    /// to an empty string.
+    FallbackTmp,
+}
+
+#[cfg(unix)]
+impl TempDirSource {
+    /// The resolved directory.
+    pub fn path(&self) -> &Path {
+        match self {
+            TempDirSource::Override(dir) | TempDirSource::TmpDirEnv(dir) => dir,
+            TempDirSource::FallbackTmp => Path::new("/tmp"),
+        }
+    }
+
+    pub(crate) fn into_path(self) -> PathBuf {
+        match self {
+            TempDirSource::Override(dir) | TempDirSource::TmpDirEnv(dir) => dir,
+            TempDirSource::FallbackTmp => PathBuf::from("/tmp"),
+        }
+    }
+}
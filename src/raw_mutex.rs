@@ -0,0 +1,132 @@
+use std::fmt;
+use std::marker::PhantomData;
+
+use lock_api::GuardNoSend;
+use once_cell::sync::OnceCell;
+
+use crate::error::*;
+use crate::sync::Mutex;
+use crate::{NamedLock, NamedLockGuard};
+
+/// Name of a [RawNamedMutex] that is known at compile time.
+///
+/// ```rust
+/// use named_lock::StaticName;
+///
+/// struct Config;
+///
+/// impl StaticName for Config {
+///     const NAME: &'static str = "myapp-config";
+/// }
+/// ```
+pub trait StaticName {
+    /// The name, which follows the same rules as in [NamedLock::create].
+    const NAME: &'static str;
+}
+
+/// Cross-process raw mutex for [`lock_api`].
+///
+/// This implements [`lock_api::RawMutex`] on top of [NamedLock], so it can
+/// be used to build a [`lock_api::Mutex`] that protects a value both
+/// between threads and between processes. [NamedMutex] is a shortcut for
+/// such a mutex.
+///
+/// ```rust
+/// use named_lock::{NamedMutex, StaticName};
+///
+/// struct Cache;
+///
+/// impl StaticName for Cache {
+///     const NAME: &'static str = "foobar-cache";
+/// }
+///
+/// static CACHE: NamedMutex<Cache, Vec<u32>> = NamedMutex::new(Vec::new());
+///
+/// CACHE.lock().push(1);
+/// ```
+///
+/// # Notes
+///
+/// * `RawMutex` requires a constant `INIT` value, which can not carry a
+///   name. The name is given by the type parameter instead, and the named
+///   lock is created when the mutex is locked for the first time.
+/// * The methods of `RawMutex` can not fail, so any error while locking
+///   (e.g. an invalid name, or a lock file that can not be created) panics.
+///   Errors while unlocking are ignored.
+/// * The value itself lives in the memory of each process, only the
+///   locking is shared. To share data between processes, keep it somewhere
+///   that the processes can access, like a file, and use the mutex to
+///   protect it.
+///
+/// [`lock_api`]: https://docs.rs/lock_api
+/// [`lock_api::RawMutex`]: https://docs.rs/lock_api/0.4/lock_api/trait.RawMutex.html
+/// [`lock_api::Mutex`]: https://docs.rs/lock_api/0.4/lock_api/struct.Mutex.html
+pub struct RawNamedMutex<N> {
+    lock: OnceCell<NamedLock>,
+    guard: Mutex<Option<NamedLockGuard>>,
+    _name: PhantomData<fn() -> N>,
+}
+
+/// [`lock_api::Mutex`] that is locked with a [RawNamedMutex].
+///
+/// [`lock_api::Mutex`]: https://docs.rs/lock_api/0.4/lock_api/struct.Mutex.html
+pub type NamedMutex<N, T> = lock_api::Mutex<RawNamedMutex<N>, T>;
+
+impl<N: StaticName> RawNamedMutex<N> {
+    fn named_lock(&self) -> &NamedLock {
+        self.lock
+            .get_or_try_init(|| NamedLock::create(N::NAME))
+            .unwrap_or_else(|e| fail(e))
+    }
+
+    fn locked(&self, guard: NamedLockGuard) {
+        *self.guard.lock() = Some(guard);
+    }
+}
+
+unsafe impl<N: StaticName> lock_api::RawMutex for RawNamedMutex<N> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = RawNamedMutex {
+        lock: OnceCell::new(),
+        guard: Mutex::new(None),
+        _name: PhantomData,
+    };
+
+    // On Windows the mutex must be released by the thread that locked it.
+    type GuardMarker = GuardNoSend;
+
+    fn lock(&self) {
+        match self.named_lock().lock() {
+            Ok(guard) => self.locked(guard),
+            Err(e) => fail(e),
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        match self.named_lock().try_lock() {
+            Ok(guard) => {
+                self.locked(guard);
+                true
+            }
+            Err(Error::WouldBlock) => false,
+            Err(e) => fail(e),
+        }
+    }
+
+    unsafe fn unlock(&self) {
+        // This is called when a `MutexGuard` is dropped, so errors are
+        // ignored like in `NamedLockGuard::drop`.
+        drop(self.guard.lock().take());
+    }
+}
+
+impl<N> fmt::Debug for RawNamedMutex<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawNamedMutex").field("lock", &self.lock).finish()
+    }
+}
+
+#[cold]
+fn fail(e: Error) -> ! {
+    panic!("named mutex failed: {}", e)
+}
@@ -0,0 +1,105 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::clock;
+use crate::error::*;
+use crate::metadata::Metadata;
+use crate::NamedLockGuard;
+
+// Shortest interval between renewals, so a zero `ttl` does not spin.
+const MIN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// [NamedLockGuard] that keeps renewing its lease in the background.
+///
+/// Created by [NamedLock::lock_with_renewal].
+///
+/// # Notes
+///
+/// * The renewal thread is spawned when the lock is acquired and writes a
+///   lease of `ttl` that starts at the time of renewal every `ttl / 2`.
+/// * A renewal that fails is tried again at the next interval. Watchers
+///   may see the lease as expired in the meantime.
+/// * Dropping the guard stops the renewal thread and joins it, then removes
+///   the lease and unlocks the named lock. Nothing is written to the lock
+///   file after the drop returns.
+///
+/// [NamedLock::lock_with_renewal]: crate::NamedLock::lock_with_renewal
+#[derive(Debug)]
+pub struct RenewingGuard {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+    file: File,
+    guard: NamedLockGuard,
+}
+
+impl RenewingGuard {
+    pub(crate) fn new(guard: NamedLockGuard, ttl: Duration) -> Result<Self> {
+        // Both the renewal thread and `drop` write through their own
+        // descriptor, closing them does not release the `flock` lock.
+        let file = guard.file().try_clone().map_err(Error::Io)?;
+        let thread_file = file.try_clone().map_err(Error::Io)?;
+        let path = guard.path().to_owned();
+
+        write_lease(&file, &path, Some(ttl)).map_err(Error::Io)?;
+
+        let (stop, stopped) = mpsc::channel::<()>();
+        let interval = (ttl / 2).max(MIN_INTERVAL);
+
+        let thread = thread::Builder::new()
+            .name("named-lock-renewal".to_owned())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) =
+                    stopped.recv_timeout(interval)
+                {
+                    let _ = write_lease(&thread_file, &path, Some(ttl));
+                }
+            })
+            .map_err(Error::Io)?;
+
+        Ok(RenewingGuard {
+            stop: Some(stop),
+            thread: Some(thread),
+            file,
+            guard,
+        })
+    }
+
+    /// Returns the guard.
+    pub fn guard(&self) -> &NamedLockGuard {
+        &self.guard
+    }
+}
+
+impl Drop for RenewingGuard {
+    fn drop(&mut self) {
+        // Dropping the sender disconnects the channel, which wakes the
+        // thread up right away.
+        drop(self.stop.take());
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        let _ = write_lease(&self.file, self.guard.path(), None);
+    }
+}
+
+// Writes a lease of `ttl` that starts now, or removes it for `None`.
+fn write_lease(
+    file: &File,
+    path: &Path,
+    ttl: Option<Duration>,
+) -> io::Result<()> {
+    let mut meta = Metadata::read(path)?;
+
+    match ttl {
+        Some(ttl) => meta.set_lease(clock::system_now(), ttl),
+        None => meta.remove_lease(),
+    }
+
+    meta.write(file)
+}
@@ -0,0 +1,203 @@
+use std::collections::hash_map::RandomState;
+use std::convert::TryFrom;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use crate::clock;
+use crate::error::*;
+
+/// Policy for retrying transient lock failures.
+///
+/// Used by [NamedLock::lock_with_retry]. Only `Error::LockFailed` and
+/// `Error::ResourceExhausted` are considered transient, every other error
+/// is returned immediately. The
+/// delay between attempts starts at [RetryPolicy::backoff] and doubles
+/// after every retry, up to [RetryPolicy::max_backoff].
+///
+/// ```rust
+/// use named_lock::{NamedLock, RetryPolicy};
+/// use named_lock::Result;
+/// use std::time::Duration;
+///
+/// fn main() -> Result<()> {
+///     let lock = NamedLock::create("foobar")?;
+///     let policy = RetryPolicy::new()
+///         .max_retries(5)
+///         .backoff(Duration::from_millis(1))
+///         .clone();
+///     let _guard = lock.lock_with_retry(&policy)?;
+///
+///     Ok(())
+/// }
+/// ```
+///
+/// [NamedLock::lock_with_retry]: crate::NamedLock::lock_with_retry
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Create a policy with 3 retries and a backoff of 10ms up to 1s.
+    pub fn new() -> RetryPolicy {
+        RetryPolicy::default()
+    }
+
+    /// Number of retries after the first attempt.
+    pub fn max_retries(&mut self, max_retries: u32) -> &mut RetryPolicy {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Delay before the first retry.
+    pub fn backoff(&mut self, backoff: Duration) -> &mut RetryPolicy {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Upper limit of the delay between retries.
+    pub fn max_backoff(&mut self, max_backoff: Duration) -> &mut RetryPolicy {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    pub(crate) fn run<T, F>(&self, mut attempt: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        let mut backoff = self.backoff.min(self.max_backoff);
+        let mut retries = 0;
+
+        loop {
+            match attempt() {
+                Err(Error::LockFailed(_) | Error::ResourceExhausted)
+                    if retries < self.max_retries => {}
+                res => return res,
+            }
+
+            clock::sleep(backoff);
+            backoff = backoff.saturating_mul(2).min(self.max_backoff);
+            retries += 1;
+        }
+    }
+}
+
+// Returns a random duration between zero and `max`.
+//
+// Every `RandomState` has new random keys, which is good enough to spread
+// out waiters and needs no extra dependency.
+pub(crate) fn jitter(max: Duration) -> Duration {
+    let max = u64::try_from(max.as_nanos()).unwrap_or(u64::MAX);
+
+    match max.checked_add(1) {
+        Some(0) => Duration::ZERO,
+        bound => {
+            let random = RandomState::new().build_hasher().finish();
+            Duration::from_nanos(bound.map_or(random, |bound| random % bound))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy::new()
+            .max_retries(max_retries)
+            .backoff(Duration::from_millis(1))
+            .clone()
+    }
+
+    // Fails with `error` the first `failures` times.
+    fn flaky(
+        failures: u32,
+        error: fn() -> Error,
+    ) -> impl FnMut() -> Result<u32> {
+        let mut attempts = 0;
+
+        move || {
+            attempts += 1;
+
+            if attempts <= failures {
+                Err(error())
+            } else {
+                Ok(attempts)
+            }
+        }
+    }
+
+    #[test]
+    fn transient_failure() {
+        assert_eq!(
+            policy(3).run(flaky(2, || Error::LockFailed(None))).unwrap(),
+            3
+        );
+        assert_eq!(
+            policy(3).run(flaky(2, || Error::ResourceExhausted)).unwrap(),
+            3
+        );
+        assert!(matches!(
+            policy(1).run(flaky(2, || Error::LockFailed(None))),
+            Err(Error::LockFailed(_))
+        ));
+        assert!(matches!(
+            policy(0).run(flaky(1, || Error::LockFailed(None))),
+            Err(Error::LockFailed(_))
+        ));
+    }
+
+    #[test]
+    fn fatal_failure() {
+        assert!(matches!(
+            policy(3).run(flaky(1, || Error::WrongThread)),
+            Err(Error::WrongThread)
+        ));
+        assert!(matches!(
+            policy(3).run(flaky(1, || Error::WouldBlock)),
+            Err(Error::WouldBlock)
+        ));
+    }
+
+    #[test]
+    fn backoff() {
+        let clock = clock::MockClock::install();
+        let policy = RetryPolicy::new()
+            .max_retries(4)
+            .backoff(Duration::from_secs(1))
+            .max_backoff(Duration::from_secs(3))
+            .clone();
+
+        assert_eq!(
+            policy.run(flaky(4, || Error::LockFailed(None))).unwrap(),
+            5
+        );
+        assert_eq!(clock.elapsed(), Duration::from_secs(1 + 2 + 3 + 3));
+        clock.uninstall();
+    }
+
+    #[test]
+    fn jitter() {
+        assert_eq!(super::jitter(Duration::ZERO), Duration::ZERO);
+
+        let max = Duration::from_millis(10);
+        let values: Vec<Duration> =
+            (0..100).map(|_| super::jitter(max)).collect();
+        assert!(values.iter().all(|&v| v <= max));
+        assert!(values.iter().any(|&v| v != values[0]));
+
+        assert!(super::jitter(Duration::MAX) <= Duration::MAX);
+    }
+}
@@ -0,0 +1,169 @@
+use std::ffi::CString;
+use std::io;
+use std::os::raw::{c_char, c_int};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use once_cell::sync::OnceCell;
+
+use crate::error::*;
+
+// Signals that terminate a process by default and are sent to ask it to
+// exit, e.g. by `kill`, a service manager or Ctrl-C.
+const SIGNALS: [c_int; 3] = [libc::SIGTERM, libc::SIGINT, libc::SIGHUP];
+
+// Maximum number of lock files that can be registered at the same time.
+const MAX_PATHS: usize = 32;
+
+// The handler may only use async-signal-safe functions, so the paths are
+// prepared as C strings up front and it only has to call `unlink`.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_PATH: AtomicPtr<c_char> = AtomicPtr::new(ptr::null_mut());
+static PATHS: [AtomicPtr<c_char>; MAX_PATHS] = [NO_PATH; MAX_PATHS];
+
+// Number of handlers that are running, so that paths are freed only when
+// no handler can read them.
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+// Result of installing the handlers, with the OS error code of a failure,
+// so that every registration reports it and not only the first one.
+static INSTALL: OnceCell<std::result::Result<(), i32>> = OnceCell::new();
+
+// The handlers that were installed before ours, indexed like `SIGNALS`.
+// They are written once in `INSTALL`, before our handler can run.
+static mut PREVIOUS: [Option<libc::sigaction>; 3] = [None; 3];
+
+/// Registration of a lock file that is removed on termination signals.
+///
+/// Created by [NamedLock::on_signal_cleanup]. Dropping it unregisters the
+/// lock file again.
+///
+/// [NamedLock::on_signal_cleanup]: crate::NamedLock::on_signal_cleanup
+#[derive(Debug)]
+pub struct SignalCleanup {
+    slot: usize,
+}
+
+impl SignalCleanup {
+    pub(crate) fn register(path: &Path) -> Result<SignalCleanup> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| Error::InvalidCharacter)?
+            .into_raw();
+
+        let slot = PATHS.iter().position(|slot| {
+            slot.compare_exchange(
+                ptr::null_mut(),
+                path,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_ok()
+        });
+
+        let slot = match slot {
+            Some(slot) => slot,
+            None => {
+                drop(unsafe { CString::from_raw(path) });
+                return Err(Error::Io(io::Error::other(
+                    "too many lock files are registered for signal cleanup",
+                )));
+            }
+        };
+
+        let cleanup = SignalCleanup {
+            slot,
+        };
+        install()?;
+
+        Ok(cleanup)
+    }
+}
+
+impl Drop for SignalCleanup {
+    fn drop(&mut self) {
+        let path = PATHS[self.slot].swap(ptr::null_mut(), Ordering::SeqCst);
+
+        // A handler that runs on another thread may still read the path.
+        while RUNNING.load(Ordering::SeqCst) > 0 {
+            std::thread::yield_now();
+        }
+
+        drop(unsafe { CString::from_raw(path) });
+    }
+}
+
+fn install() -> Result<()> {
+    let res = *INSTALL.get_or_init(|| {
+        for (i, &signal) in SIGNALS.iter().enumerate() {
+            let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+            action.sa_sigaction = handler as *const () as usize;
+            action.sa_flags = libc::SA_RESTART;
+
+            let mut previous: libc::sigaction = unsafe { std::mem::zeroed() };
+
+            if unsafe { libc::sigaction(signal, &action, &mut previous) } < 0 {
+                let err = io::Error::last_os_error();
+                return Err(err.raw_os_error().unwrap_or(libc::EINVAL));
+            }
+
+            // The process does not exit on an ignored signal (e.g. `SIGHUP`
+            // under `nohup`), so its lock files must not be removed either.
+            if previous.sa_sigaction == libc::SIG_IGN {
+                unsafe { libc::sigaction(signal, &previous, ptr::null_mut()) };
+                continue;
+            }
+
+            unsafe { PREVIOUS[i] = Some(previous) };
+        }
+
+        Ok(())
+    });
+
+    res.map_err(|code| Error::Io(io::Error::from_raw_os_error(code)))
+}
+
+extern "C" fn handler(signal: c_int) {
+    RUNNING.fetch_add(1, Ordering::SeqCst);
+
+    for slot in &PATHS {
+        let path = slot.load(Ordering::SeqCst);
+
+        if !path.is_null() {
+            unsafe { libc::unlink(path) };
+        }
+    }
+
+    RUNNING.fetch_sub(1, Ordering::SeqCst);
+
+    let i = match SIGNALS.iter().position(|&s| s == signal) {
+        Some(i) => i,
+        None => return,
+    };
+    let previous = unsafe { PREVIOUS[i] };
+
+    match previous {
+        Some(previous) if previous.sa_sigaction != libc::SIG_DFL => {
+            // Our handler was installed without `SA_SIGINFO`, so a
+            // previous handler that wants it gets no info.
+            if previous.sa_flags & libc::SA_SIGINFO != 0 {
+                let previous: extern "C" fn(
+                    c_int,
+                    *mut libc::siginfo_t,
+                    *mut libc::c_void,
+                ) = unsafe { std::mem::transmute(previous.sa_sigaction) };
+                previous(signal, ptr::null_mut(), ptr::null_mut());
+            } else {
+                let previous: extern "C" fn(c_int) =
+                    unsafe { std::mem::transmute(previous.sa_sigaction) };
+                previous(signal);
+            }
+        }
+        // Terminate like the signal would have without our handler.
+        _ => unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        },
+    }
+}
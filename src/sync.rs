@@ -0,0 +1,145 @@
+// The mutex of the in-process layer.
+//
+// `parking_lot` is used by default. Without the `parking_lot` feature a
+// `RawMutex` that is built on `std::sync` is used instead, so the crate does
+// not depend on `parking_lot` and its feature flags at all.
+
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) use self::std_mutex::RawStdMutex as RawMutex;
+#[cfg(feature = "parking_lot")]
+pub(crate) use parking_lot::RawMutex;
+
+pub(crate) type Mutex<T> = lock_api::Mutex<RawMutex, T>;
+
+#[cfg(any(test, not(feature = "parking_lot")))]
+mod std_mutex {
+    use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+    use std::time::{Duration, Instant};
+
+    use lock_api::GuardSend;
+
+    #[derive(Debug)]
+    pub(crate) struct RawStdMutex {
+        locked: Mutex<bool>,
+        unlocked: Condvar,
+    }
+
+    impl RawStdMutex {
+        // Nothing panics while the state is locked, so poisoning can be
+        // ignored.
+        fn state(&self) -> MutexGuard<'_, bool> {
+            self.locked.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+
+    unsafe impl lock_api::RawMutex for RawStdMutex {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const INIT: Self = RawStdMutex {
+            locked: Mutex::new(false),
+            unlocked: Condvar::new(),
+        };
+
+        type GuardMarker = GuardSend;
+
+        fn lock(&self) {
+            let mut locked = self.state();
+
+            while *locked {
+                locked = self
+                    .unlocked
+                    .wait(locked)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+
+            *locked = true;
+        }
+
+        fn try_lock(&self) -> bool {
+            let mut locked = self.state();
+            !std::mem::replace(&mut *locked, true)
+        }
+
+        unsafe fn unlock(&self) {
+            *self.state() = false;
+            self.unlocked.notify_one();
+        }
+
+        fn is_locked(&self) -> bool {
+            *self.state()
+        }
+    }
+
+    // A `Condvar` can not hand the mutex over to a particular thread, so
+    // a fair unlock is a normal unlock.
+    unsafe impl lock_api::RawMutexFair for RawStdMutex {
+        unsafe fn unlock_fair(&self) {
+            lock_api::RawMutex::unlock(self);
+        }
+    }
+
+    unsafe impl lock_api::RawMutexTimed for RawStdMutex {
+        type Duration = Duration;
+        type Instant = Instant;
+
+        fn try_lock_for(&self, timeout: Duration) -> bool {
+            match Instant::now().checked_add(timeout) {
+                Some(deadline) => self.try_lock_until(deadline),
+                None => {
+                    lock_api::RawMutex::lock(self);
+                    true
+                }
+            }
+        }
+
+        fn try_lock_until(&self, deadline: Instant) -> bool {
+            let mut locked = self.state();
+
+            while *locked {
+                let now = Instant::now();
+
+                if now >= deadline {
+                    return false;
+                }
+
+                locked = self
+                    .unlocked
+                    .wait_timeout(locked, deadline - now)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0;
+            }
+
+            *locked = true;
+            true
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::sync::Arc;
+        use std::thread;
+
+        type StdMutex<T> = lock_api::Mutex<RawStdMutex, T>;
+
+        #[test]
+        fn lock_unlock() {
+            let mutex = Arc::new(StdMutex::new(0));
+
+            let guard = mutex.lock();
+            assert!(mutex.is_locked());
+            assert!(mutex.try_lock().is_none());
+            assert!(mutex.try_lock_for(Duration::from_millis(10)).is_none());
+
+            let handle = {
+                let mutex = mutex.clone();
+                thread::spawn(move || *mutex.lock() += 1)
+            };
+            thread::sleep(Duration::from_millis(50));
+            drop(guard);
+            handle.join().unwrap();
+
+            assert_eq!(*mutex.try_lock().unwrap(), 1);
+            assert!(!mutex.is_locked());
+        }
+    }
+}
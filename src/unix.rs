@@ -1,46 +1,509 @@
 use std::fs::{File, OpenOptions};
 use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
 
 use libc::{LOCK_EX, LOCK_NB, LOCK_UN};
+use once_cell::sync::OnceCell;
 
+use crate::clock;
 use crate::error::*;
+use crate::fd_limit::{self, OpenFile};
+use crate::metadata::{self, Metadata};
+use crate::{Backend, ExistingFilePolicy, NamedLockOptions};
+
+// How often `try_lock_for` polls `flock`, which has no native timeout.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// How many times `open` tries again when the lock file is removed between
+// creating and opening it.
+const OPEN_RETRIES: u32 = 5;
 
 #[derive(Debug)]
 pub(crate) struct RawNamedLock {
-    lock_file: File,
+    // Closed when the lock is idle and the limit of open lock files is
+    // reached, and opened again when it is used.
+    lock_file: OnceCell<OpenFile>,
+    // Stamp of `fd_limit::tick` of the last use.
+    last_used: AtomicU64,
+    path: PathBuf,
+    // Kept to open the file again after `fork`.
+    options: NamedLockOptions,
+    // Process that opened the file.
+    pid: u32,
+    // `LOCK_UN` succeeds even if the lock is not held, so debug builds
+    // keep track of it to warn about unlocking without locking.
+    #[cfg(debug_assertions)]
+    held: AtomicBool,
 }
 
 impl RawNamedLock {
-    pub(crate) fn create(lock_path: &Path) -> Result<RawNamedLock> {
-        let lock_file = OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(lock_path)
-            .or_else(|_| OpenOptions::new().write(true).open(lock_path))
-            .map_err(Error::CreateFailed)?;
+    pub(crate) fn create(
+        lock_path: &Path,
+        options: &NamedLockOptions,
+    ) -> Result<RawNamedLock> {
+        let lock_file = open_lock_file(lock_path, options)?;
 
         Ok(RawNamedLock {
-            lock_file,
+            lock_file: OnceCell::with_value(lock_file),
+            last_used: AtomicU64::new(fd_limit::tick()),
+            path: lock_path.to_owned(),
+            options: options.clone(),
+            pid: std::process::id(),
+            #[cfg(debug_assertions)]
+            held: AtomicBool::new(false),
         })
     }
 
-    pub(crate) fn try_lock(&self) -> Result<()> {
-        unsafe { flock(self.lock_file.as_raw_fd(), LOCK_EX | LOCK_NB) }
+    // There is no atomic create-and-lock with `flock`, so the lock is never
+    // owned on creation and has to be locked as usual.
+    pub(crate) fn create_owned(
+        lock_path: &Path,
+        options: &NamedLockOptions,
+    ) -> Result<(RawNamedLock, bool)> {
+        Ok((RawNamedLock::create(lock_path, options)?, false))
+    }
+
+    pub(crate) fn backend(&self) -> Backend {
+        Backend::Flock
+    }
+
+    pub(crate) fn exists(lock_path: &Path) -> Result<bool> {
+        match std::fs::metadata(lock_path) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+
+    // Probes the lock file at `lock_path` by locking and unlocking it
+    // through a new descriptor. Returns `None` if the file does not exist.
+    pub(crate) fn probe(lock_path: &Path) -> io::Result<Option<bool>> {
+        let file = match File::open(lock_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        // Locks of other descriptors conflict even within this process.
+        match unsafe { flock(file.as_raw_fd(), LOCK_EX | LOCK_NB) } {
+            Ok(()) => Ok(Some(false)),
+            Err(Error::WouldBlock) => Ok(Some(true)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // A child of `fork` shares the open file description, and with it the
+    // `flock`, with its parent. Opening the file again gives the child its
+    // own.
+    pub(crate) fn reinit_after_fork(&mut self) -> Result<()> {
+        if self.pid != std::process::id() {
+            // The file was opened before, so it must be reused as is.
+            let policy = self.options.existing_file;
+            let mut options = self.options.clone();
+            options.existing_file = ExistingFilePolicy::Reuse;
+
+            *self = RawNamedLock::create(&self.path, &options)?;
+            self.options.existing_file = policy;
+        }
+
+        Ok(())
+    }
+
+    // Moves the lock file to `new_path`. Must be called while the lock is
+    // held, so that it is never unprotected.
+    pub(crate) fn relocate(&mut self, new_path: &Path) -> Result<()> {
+        match std::fs::rename(&self.path, new_path) {
+            Ok(()) => {}
+            // A file can not be moved to another file system, so a new one
+            // is locked there before the old one is released.
+            Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                let new = open_lock_file(new_path, &self.options)?;
+                unsafe { flock(new.file().as_raw_fd(), LOCK_EX) }?;
+
+                if self.has_metadata() {
+                    Metadata::read(&self.path)
+                        .and_then(|meta| meta.write(new.file()))
+                        .map_err(Error::Io)?;
+                }
+
+                let _ = std::fs::remove_file(&self.path);
+                self.lock_file = OnceCell::with_value(new);
+            }
+            Err(e) => return Err(Error::Io(e)),
+        }
+
+        self.path = new_path.to_owned();
+
+        // The new path is not relative to the directory descriptor anymore.
+        #[cfg(target_os = "linux")]
+        {
+            self.options.dir_fd = None;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Only valid while the lock is held, which keeps the file open.
+    pub(crate) fn file(&self) -> &File {
+        self.lock_file
+            .get()
+            .expect("lock file is open while the lock is held")
+            .file()
+    }
+
+    // Opens the lock file again if it was closed.
+    fn fd(&self) -> Result<RawFd> {
+        self.last_used.store(fd_limit::tick(), Ordering::Relaxed);
+
+        let lock_file = self.lock_file.get_or_try_init(|| {
+            // The file was opened before, so it must be reused as is.
+            let mut options = self.options.clone();
+            options.existing_file = ExistingFilePolicy::Reuse;
+
+            open_lock_file(&self.path, &options)
+        })?;
+
+        Ok(lock_file.file().as_raw_fd())
+    }
+
+    // Returns the stamp of the last use, or `None` if the file is closed.
+    pub(crate) fn last_used(&self) -> Option<u64> {
+        self.lock_file.get().map(|_| self.last_used.load(Ordering::Relaxed))
     }
 
-    pub(crate) fn lock(&self) -> Result<()> {
-        unsafe { flock(self.lock_file.as_raw_fd(), LOCK_EX) }
+    // Closes the lock file until it is used again. Must only be called
+    // while the lock is not held. Returns `false` if it was closed already.
+    pub(crate) fn close(&mut self) -> bool {
+        self.lock_file.take().is_some()
+    }
+
+    // The returned `bool` reports if the lock was abandoned by its previous
+    // owner, which never happens with `flock`.
+    pub(crate) fn try_lock(&self) -> Result<bool> {
+        unsafe { flock(self.fd()?, LOCK_EX | LOCK_NB) }?;
+        self.locked()?;
+        Ok(false)
+    }
+
+    pub(crate) fn try_lock_for(&self, timeout: Duration) -> Result<bool> {
+        let deadline = match clock::now().checked_add(timeout) {
+            Some(deadline) => deadline,
+            None => return self.lock(),
+        };
+
+        loop {
+            match self.try_lock() {
+                Err(Error::WouldBlock) => {}
+                res => return res,
+            }
+
+            let now = clock::now();
+
+            if now >= deadline {
+                return Err(Error::WouldBlock);
+            }
+
+            clock::sleep(POLL_INTERVAL.min(deadline - now));
+        }
+    }
+
+    pub(crate) fn lock(&self) -> Result<bool> {
+        unsafe { flock(self.fd()?, LOCK_EX) }?;
+        self.locked()?;
+        Ok(false)
+    }
+
+    // Like `lock`, but gives up with `Error::Interrupted` once `interrupted`
+    // is set. This relies on a signal interrupting `flock` with `EINTR`.
+    pub(crate) fn lock_interruptible(
+        &self,
+        interrupted: &AtomicBool,
+    ) -> Result<bool> {
+        unsafe {
+            flock_until(self.fd()?, LOCK_EX, || {
+                interrupted.load(Ordering::SeqCst)
+            })
+        }?;
+        self.locked()?;
+        Ok(false)
+    }
+
+    // Called after the lock is acquired. If this fails, the lock is
+    // released again.
+    fn locked(&self) -> Result<()> {
+        self.set_held(true);
+
+        if !self.has_metadata() {
+            return Ok(());
+        }
+
+        if let Err(e) = self.update_metadata(|meta| {
+            if self.options.record_holder {
+                let pid = std::process::id();
+                meta.set(metadata::PID, pid);
+
+                match metadata::process_start_time(pid) {
+                    Some(start) => meta.set(metadata::START, start),
+                    None => meta.remove(metadata::START),
+                }
+            }
+
+            if let Some(ttl) = self.options.ttl {
+                meta.set_lease(clock::system_now(), ttl);
+            }
+        }) {
+            let _ = self.unlock();
+            return Err(Error::Io(e));
+        }
+
+        Ok(())
+    }
+
+    fn has_metadata(&self) -> bool {
+        self.options.record_holder || self.options.ttl.is_some()
+    }
+
+    fn update_metadata<F>(&self, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut Metadata),
+    {
+        let mut meta = Metadata::read(&self.path)?;
+        f(&mut meta);
+        meta.write(self.file())
     }
 
     pub(crate) fn unlock(&self) -> Result<()> {
-        unsafe { flock(self.lock_file.as_raw_fd(), LOCK_UN) }
+        #[cfg(debug_assertions)]
+        if !self.held.load(Ordering::Relaxed) {
+            warn_unmatched_unlock(&self.path);
+        }
+
+        if self.has_metadata() {
+            let _ = self.update_metadata(|meta| {
+                meta.remove(metadata::PID);
+                meta.remove(metadata::START);
+                meta.remove_lease();
+            });
+        }
+
+        unsafe { flock(self.file().as_raw_fd(), LOCK_UN) }?;
+        self.set_held(false);
+        Ok(())
+    }
+
+    #[inline]
+    fn set_held(&self, _held: bool) {
+        #[cfg(debug_assertions)]
+        self.held.store(_held, Ordering::Relaxed);
     }
 }
 
+// Only a warning, since unlocking a lock that is not held does no harm to
+// the lock itself, and unlocking never panics.
+#[cfg(debug_assertions)]
+fn warn_unmatched_unlock(path: &Path) {
+    #[cfg(test)]
+    tests::UNMATCHED_UNLOCKS.fetch_add(1, Ordering::Relaxed);
+
+    eprintln!(
+        "warning: named lock {} unlocked without being locked",
+        path.display()
+    );
+}
+
+fn open_lock_file(
+    lock_path: &Path,
+    options: &NamedLockOptions,
+) -> Result<OpenFile> {
+    OpenFile::open(|| {
+        #[cfg(target_os = "linux")]
+        let res = match options.dir_fd {
+            Some(dir_fd) => open_at(dir_fd, lock_path, options),
+            None => open(lock_path, options),
+        };
+        #[cfg(not(target_os = "linux"))]
+        let res = open(lock_path, options);
+
+        let lock_file = res.map_err(|e| match e.raw_os_error() {
+            Some(libc::EISDIR) => Error::NotAFile,
+            // Only `ExistingFilePolicy::Error` fails on existing files.
+            Some(libc::EEXIST) => Error::AlreadyExists,
+            Some(libc::EMFILE) | Some(libc::ENFILE) => Error::TooManyOpenFiles,
+            Some(libc::ENAMETOOLONG) => Error::NameTooLong,
+            _ => Error::CreateFailed(e),
+        })?;
+
+        // `std` always opens files with `O_CLOEXEC`, so it has to be
+        // cleared afterwards.
+        if options.inheritable {
+            set_cloexec(lock_file.as_raw_fd(), false)
+                .map_err(Error::CreateFailed)?;
+        }
+
+        Ok(lock_file)
+    })
+}
+
+fn open(lock_path: &Path, options: &NamedLockOptions) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    // A directory can only be opened for reading, which is enough for
+    // `flock`.
+    if options.directory {
+        return OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY)
+            .open(lock_path);
+    }
+
+    let mut open_options = OpenOptions::new();
+    open_options.read(options.read).write(true);
+
+    match options.existing_file {
+        ExistingFilePolicy::Reuse => {}
+        ExistingFilePolicy::Error => {
+            return open_options.create_new(true).open(lock_path);
+        }
+        ExistingFilePolicy::Truncate => {
+            return open_options.create(true).truncate(true).open(lock_path);
+        }
+    }
+
+    let mut retries = 0;
+
+    loop {
+        let res = open_options
+            .clone()
+            .create_new(true)
+            .open(lock_path)
+            .or_else(|_| open_options.open(lock_path));
+
+        // Another process removed the file after `create_new` saw it, so
+        // it can be created again.
+        match res {
+            Err(e)
+                if e.kind() == io::ErrorKind::NotFound
+                    && retries < OPEN_RETRIES =>
+            {
+                retries += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+// Opens the file name of `lock_path` relative to `dir_fd`.
+#[cfg(target_os = "linux")]
+fn open_at(
+    dir_fd: RawFd,
+    lock_path: &Path,
+    options: &NamedLockOptions,
+) -> io::Result<File> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::FromRawFd;
+
+    let file_name = lock_path
+        .file_name()
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let file_name = CString::new(file_name.as_bytes())?;
+
+    let access = if options.read {
+        libc::O_RDWR
+    } else {
+        libc::O_WRONLY
+    };
+    let existing = match options.existing_file {
+        ExistingFilePolicy::Reuse => 0,
+        ExistingFilePolicy::Error => libc::O_EXCL,
+        ExistingFilePolicy::Truncate => libc::O_TRUNC,
+    };
+    let fd = unsafe {
+        libc::openat(
+            dir_fd,
+            file_name.as_ptr(),
+            access | existing | libc::O_CREAT | libc::O_CLOEXEC,
+            0o666 as libc::c_uint,
+        )
+    };
+
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+// Returns the longest file name that can be created in `dir`, in bytes.
+pub(crate) fn max_file_name_len(dir: &Path) -> usize {
+    use std::convert::TryFrom;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    // `NAME_MAX` of Linux and most other systems.
+    const DEFAULT_NAME_MAX: usize = 255;
+
+    let name_max = CString::new(dir.as_os_str().as_bytes())
+        .ok()
+        .map(|dir| unsafe { libc::pathconf(dir.as_ptr(), libc::_PC_NAME_MAX) })
+        .and_then(|len| usize::try_from(len).ok())
+        .filter(|&len| len > 0)
+        .unwrap_or(DEFAULT_NAME_MAX);
+
+    // The whole path, with a separator and the terminating NUL, must fit
+    // in `PATH_MAX` as well.
+    let path_max = (libc::PATH_MAX as usize)
+        .saturating_sub(dir.as_os_str().as_bytes().len() + 2);
+
+    name_max.min(path_max)
+}
+
+// Only calls `fcntl`, so it can be used between `fork` and `exec`.
+pub(crate) fn set_cloexec(fd: RawFd, cloexec: bool) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let flags = if cloexec {
+        flags | libc::FD_CLOEXEC
+    } else {
+        flags & !libc::FD_CLOEXEC
+    };
+
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
 unsafe fn flock(fd: RawFd, operation: i32) -> Result<()> {
+    flock_until(fd, operation, || false)
+}
+
+// `flock` that retries on `EINTR` until `interrupted` returns `true`.
+unsafe fn flock_until<F>(
+    fd: RawFd,
+    operation: i32,
+    interrupted: F,
+) -> Result<()>
+where
+    F: Fn() -> bool,
+{
     loop {
+        if interrupted() {
+            return Err(Error::Interrupted);
+        }
+
         let rc = libc::flock(fd, operation);
 
         if rc < 0 {
@@ -48,13 +511,9 @@ unsafe fn flock(fd: RawFd, operation: i32) -> Result<()> {
 
             if err.kind() == io::ErrorKind::Interrupted {
                 continue;
-            } else if err.kind() == io::ErrorKind::WouldBlock {
-                return Err(Error::WouldBlock);
-            } else if (operation & LOCK_EX) == LOCK_EX {
-                return Err(Error::LockFailed);
-            } else if (operation & LOCK_UN) == LOCK_UN {
-                return Err(Error::UnlockFailed);
             }
+
+            return Err(flock_error(err, operation));
         }
 
         break;
@@ -62,3 +521,114 @@ unsafe fn flock(fd: RawFd, operation: i32) -> Result<()> {
 
     Ok(())
 }
+
+// Maps a failure of `flock` other than `EINTR`.
+fn flock_error(err: io::Error, operation: i32) -> Error {
+    if err.kind() == io::ErrorKind::WouldBlock {
+        Error::WouldBlock
+    } else if err.raw_os_error() == Some(libc::ENOLCK) {
+        Error::ResourceExhausted
+    } else if (operation & LOCK_UN) == LOCK_UN {
+        Error::UnlockFailed(Some(err))
+    } else {
+        Error::LockFailed(Some(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    // Counts the warnings of `warn_unmatched_unlock`.
+    #[cfg(debug_assertions)]
+    pub(super) static UNMATCHED_UNLOCKS: AtomicU64 = AtomicU64::new(0);
+
+    fn raw_lock() -> RawNamedLock {
+        let path = std::env::temp_dir()
+            .join(format!("{}.lock", Uuid::new_v4().as_hyphenated()));
+        RawNamedLock::create(&path, &NamedLockOptions::new()).unwrap()
+    }
+
+    #[test]
+    fn lock_unlock() {
+        let raw = raw_lock();
+        raw.lock().unwrap();
+        raw.unlock().unwrap();
+        raw.try_lock().unwrap();
+        raw.unlock().unwrap();
+    }
+
+    #[test]
+    fn open_while_removed() {
+        let path = std::env::temp_dir()
+            .join(format!("{}.lock", Uuid::new_v4().as_hyphenated()));
+        let options = NamedLockOptions::new();
+        let stop = std::sync::Arc::new(AtomicBool::new(false));
+
+        let remover = std::thread::spawn({
+            let path = path.clone();
+            let stop = stop.clone();
+            move || {
+                while !stop.load(Ordering::SeqCst) {
+                    let _ = std::fs::remove_file(&path);
+                }
+            }
+        });
+
+        let creators = (0..4)
+            .map(|_| {
+                let path = path.clone();
+                let options = options.clone();
+                std::thread::spawn(move || {
+                    (0..500).all(|_| open(&path, &options).is_ok())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for creator in creators {
+            assert!(creator.join().unwrap());
+        }
+
+        stop.store(true, Ordering::SeqCst);
+        remover.join().unwrap();
+    }
+
+    #[test]
+    fn flock_errors() {
+        let os_error = io::Error::from_raw_os_error;
+
+        assert!(matches!(
+            flock_error(os_error(libc::EWOULDBLOCK), LOCK_EX | LOCK_NB),
+            Error::WouldBlock
+        ));
+        assert!(matches!(
+            flock_error(os_error(libc::ENOLCK), LOCK_EX),
+            Error::ResourceExhausted
+        ));
+        assert!(matches!(
+            flock_error(os_error(libc::ENOLCK), LOCK_UN),
+            Error::ResourceExhausted
+        ));
+        assert!(matches!(
+            flock_error(os_error(libc::EBADF), LOCK_EX),
+            Error::LockFailed(Some(e)) if e.raw_os_error() == Some(libc::EBADF)
+        ));
+        assert!(matches!(
+            flock_error(os_error(libc::EBADF), LOCK_UN),
+            Error::UnlockFailed(Some(e)) if e.raw_os_error() == Some(libc::EBADF)
+        ));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn unlock_without_lock() {
+        let raw = raw_lock();
+        raw.lock().unwrap();
+        raw.unlock().unwrap();
+
+        let warnings = UNMATCHED_UNLOCKS.load(Ordering::Relaxed);
+        raw.unlock().unwrap();
+        assert!(UNMATCHED_UNLOCKS.load(Ordering::Relaxed) > warnings);
+    }
+}
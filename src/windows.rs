@@ -1,60 +1,581 @@
 use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
 
-use windows::core::HSTRING;
+use windows::core::{w, HSTRING, PWSTR};
 use windows::Win32::Foundation::{
-    CloseHandle, HANDLE, WAIT_ABANDONED, WAIT_OBJECT_0, WAIT_TIMEOUT,
+    CloseHandle, LocalFree, ERROR_ACCESS_DENIED, ERROR_ALREADY_EXISTS,
+    ERROR_FILE_NOT_FOUND, HANDLE, HLOCAL, INVALID_HANDLE_VALUE, WAIT_ABANDONED,
+    WAIT_OBJECT_0, WAIT_TIMEOUT,
 };
+use windows::Win32::Security::Authorization::{
+    ConvertSidToStringSidW,
+    ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+};
+use windows::Win32::Security::{
+    GetTokenInformation, TokenUser, PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES,
+    TOKEN_QUERY, TOKEN_USER,
+};
+use windows::Win32::System::Memory::{
+    CreateFileMappingW, MapViewOfFile, UnmapViewOfFile, FILE_MAP_ALL_ACCESS,
+    MEMORY_MAPPED_VIEW_ADDRESS, PAGE_READWRITE,
+};
+use windows::Win32::System::SystemInformation::GetTickCount64;
 use windows::Win32::System::Threading::{
-    CreateMutexW, ReleaseMutex, WaitForSingleObject, INFINITE,
+    CreateEventW, CreateMutexW, GetCurrentProcess, OpenMutexW,
+    OpenProcessToken, ReleaseMutex, ResetEvent, SetEvent, WaitForSingleObject,
+    INFINITE, SYNCHRONIZATION_SYNCHRONIZE,
 };
 
 use crate::error::*;
+use crate::sync::Mutex;
+use crate::{Backend, NamedLockOptions, NAME_SEPARATOR};
 
 #[derive(Debug)]
 pub(crate) struct RawNamedLock {
     handle: HANDLE,
+    name: String,
+    // Windows mutexes are owned by the thread that acquired them and only
+    // that thread can release them.
+    owner: Mutex<Option<ThreadId>>,
+    // Queue in front of the mutex, see `NamedLockOptions::ticket_lock`.
+    tickets: Option<TicketQueue>,
 }
 
+// Longest name of a kernel object, in UTF-16 code units.
+pub(crate) const MAX_NAME_LEN: usize = 260;
+
 unsafe impl Sync for RawNamedLock {}
 unsafe impl Send for RawNamedLock {}
 
 impl RawNamedLock {
-    pub(crate) fn create(name: &str) -> Result<RawNamedLock> {
+    pub(crate) fn create(
+        name: &str,
+        options: &NamedLockOptions,
+    ) -> Result<RawNamedLock> {
+        check_name_len(name)?;
+
+        let handle = if options.cross_session {
+            create_cross_session(name)?
+        } else {
+            unsafe {
+                CreateMutexW(None, false, &HSTRING::from(name))
+                    .map_err(|e| Error::CreateFailed(io::Error::from(e)))?
+            }
+        };
+
+        // Built first, so that the mutex is closed if the queue fails.
+        let mut raw = RawNamedLock {
+            handle,
+            name: name.to_owned(),
+            owner: Mutex::new(None),
+            tickets: None,
+        };
+
+        if options.ticket_lock {
+            raw.tickets = Some(TicketQueue::create(name)?);
+        }
+
+        Ok(raw)
+    }
+
+    // Creates the mutex owned by the current thread. The returned `bool`
+    // is `false` if the mutex already existed, in which case it is not
+    // owned and has to be locked as usual.
+    pub(crate) fn create_owned(
+        name: &str,
+        options: &NamedLockOptions,
+    ) -> Result<(RawNamedLock, bool)> {
+        // The queue decides who gets the mutex, so a ticket lock is never
+        // created owned.
+        if options.ticket_lock {
+            return Ok((RawNamedLock::create(name, options)?, false));
+        }
+
+        check_name_len(name)?;
+
+        // Bound before the call, so nothing is dropped between the call
+        // and reading the last error.
+        let wide_name = HSTRING::from(name);
         let handle = unsafe {
-            CreateMutexW(None, false, &HSTRING::from(name))
+            CreateMutexW(None, true, &wide_name)
                 .map_err(|e| Error::CreateFailed(io::Error::from(e)))?
         };
+        let owned = io::Error::last_os_error().raw_os_error()
+            != Some(ERROR_ALREADY_EXISTS.0 as i32);
 
-        Ok(RawNamedLock {
+        let raw = RawNamedLock {
             handle,
-        })
-    }
+            name: name.to_owned(),
+            owner: Mutex::new(owned.then(|| thread::current().id())),
+            tickets: None,
+        };
 
-    pub(crate) fn try_lock(&self) -> Result<()> {
-        let rc = unsafe { WaitForSingleObject(self.handle, 0) };
+        Ok((raw, owned))
+    }
 
-        if rc == WAIT_OBJECT_0 || rc == WAIT_ABANDONED {
-            Ok(())
-        } else if rc == WAIT_TIMEOUT {
-            Err(Error::WouldBlock)
+    pub(crate) fn backend(&self) -> Backend {
+        if self.tickets.is_some() {
+            Backend::TicketLock
         } else {
-            Err(Error::LockFailed)
+            Backend::NamedMutex
         }
     }
 
-    pub(crate) fn lock(&self) -> Result<()> {
-        let rc = unsafe { WaitForSingleObject(self.handle, INFINITE) };
+    pub(crate) fn exists(name: &str) -> Result<bool> {
+        let res = unsafe {
+            OpenMutexW(SYNCHRONIZATION_SYNCHRONIZE, false, &HSTRING::from(name))
+        };
 
-        if rc == WAIT_OBJECT_0 || rc == WAIT_ABANDONED {
-            Ok(())
-        } else {
-            Err(Error::LockFailed)
+        match res {
+            Ok(handle) => {
+                unsafe {
+                    let _ = CloseHandle(handle);
+                }
+                Ok(true)
+            }
+            Err(e) if e.code() == ERROR_FILE_NOT_FOUND.to_hresult() => {
+                Ok(false)
+            }
+            // The mutex exists, but its DACL does not allow this process to
+            // open it, e.g. if it belongs to a service.
+            Err(e) if e.code() == ERROR_ACCESS_DENIED.to_hresult() => Ok(true),
+            Err(e) => Err(Error::Io(io::Error::from(e))),
         }
     }
 
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    // The returned `bool` reports if the lock was abandoned by its previous
+    // owner, i.e. the owning thread exited without releasing it.
+    pub(crate) fn try_lock(&self) -> Result<bool> {
+        self.wait(0)
+    }
+
+    pub(crate) fn try_lock_for(&self, timeout: Duration) -> Result<bool> {
+        // `INFINITE` is `u32::MAX`, so anything longer is clamped just below it.
+        let millis = timeout.as_millis().min(u128::from(INFINITE - 1)) as u32;
+        self.wait(millis)
+    }
+
+    pub(crate) fn lock(&self) -> Result<bool> {
+        self.wait(INFINITE)
+    }
+
+    fn wait(&self, millis: u32) -> Result<bool> {
+        let abandoned = match &self.tickets {
+            Some(tickets) => tickets.acquire(self.handle, millis)?,
+            None => wait_mutex(self.handle, millis)?,
+        };
+
+        *self.owner.lock() = Some(thread::current().id());
+
+        Ok(abandoned)
+    }
+
     pub(crate) fn unlock(&self) -> Result<()> {
-        unsafe { ReleaseMutex(self.handle).map_err(|_| Error::UnlockFailed) }
+        let mut owner = self.owner.lock();
+
+        if *owner != Some(thread::current().id()) {
+            return Err(Error::WrongThread);
+        }
+
+        unsafe {
+            ReleaseMutex(self.handle)
+                .map_err(|e| Error::UnlockFailed(Some(io::Error::from(e))))?
+        };
+        *owner = None;
+
+        if let Some(tickets) = &self.tickets {
+            tickets.release();
+        }
+
+        Ok(())
+    }
+}
+
+fn wait_mutex(mutex: HANDLE, millis: u32) -> Result<bool> {
+    let rc = unsafe { WaitForSingleObject(mutex, millis) };
+
+    if rc == WAIT_OBJECT_0 || rc == WAIT_ABANDONED {
+        Ok(rc == WAIT_ABANDONED)
+    } else if rc == WAIT_TIMEOUT {
+        Err(Error::WouldBlock)
+    } else {
+        // `WAIT_FAILED`, the cause is in `GetLastError`.
+        Err(Error::LockFailed(Some(io::Error::last_os_error())))
+    }
+}
+
+fn release_mutex(mutex: HANDLE) -> Result<()> {
+    unsafe {
+        ReleaseMutex(mutex)
+            .map_err(|e| Error::UnlockFailed(Some(io::Error::from(e))))
+    }
+}
+
+// How long a waiter of a ticket lock waits for the event before it looks at
+// the turn again. A wakeup can be missed, so this bounds the delay.
+const TICKET_POLL_MILLIS: u32 = 10;
+
+// How long a turn may last without the mutex being held before it is
+// skipped, because its waiter has given up or exited.
+const TICKET_SKIP_MILLIS: u64 = 50;
+
+// Indexes of the values of a ticket lock in its shared memory.
+const NEXT: usize = 0;
+const SERVING: usize = 1;
+const SINCE: usize = 2;
+const ABANDONED: usize = 3;
+
+// Ticket lock in front of the mutex. Every waiter draws a ticket from `NEXT`
+// and only waits for the mutex once `SERVING` has reached its ticket, so the
+// mutex is taken in the order of arrival. `SINCE` is the `GetTickCount64` of
+// the start of the current turn, and the event is set whenever the turn
+// moves on. `ABANDONED` is `1` if a waiter found the mutex abandoned but had
+// to release it again, so that the next holder is told.
+//
+// The mutex still provides the mutual exclusion, the queue only decides who
+// waits for it. Every step keeps the exclusion even if the queue is off,
+// e.g. if a turn is skipped while its waiter is about to take the mutex.
+#[derive(Debug)]
+struct TicketQueue {
+    values: SharedValues,
+    event: HANDLE,
+    // Ticket of the current holder in this process. Only one thread of the
+    // process waits at a time, since the lock is shared behind a mutex.
+    held: AtomicU64,
+}
+
+impl TicketQueue {
+    fn create(name: &str) -> Result<TicketQueue> {
+        // Plain names can not contain the separator, so these never collide
+        // with the mutex of another lock.
+        let values = SharedValues::create(
+            &format!("{}{}ticket", name, NAME_SEPARATOR),
+            4,
+        )?;
+
+        let event_name = format!("{}{}ticket-event", name, NAME_SEPARATOR);
+        check_name_len(&event_name)?;
+
+        // Manual reset, so that every waiter sees that the turn has moved.
+        let event = unsafe {
+            CreateEventW(None, true, false, &HSTRING::from(event_name))
+                .map_err(|e| Error::CreateFailed(io::Error::from(e)))?
+        };
+
+        Ok(TicketQueue {
+            values,
+            event,
+            held: AtomicU64::new(0),
+        })
+    }
+
+    fn value(&self, index: usize) -> &AtomicU64 {
+        self.values.get(index)
+    }
+
+    // Acquires the mutex in the order of the tickets, like `wait_mutex`.
+    fn acquire(&self, mutex: HANDLE, millis: u32) -> Result<bool> {
+        if millis == 0 {
+            return self.try_acquire(mutex);
+        }
+
+        let deadline = (millis != INFINITE)
+            .then(|| Instant::now() + Duration::from_millis(u64::from(millis)));
+        let mut ticket = self.draw();
+
+        loop {
+            let serving = self.value(SERVING).load(Ordering::SeqCst);
+
+            if serving == ticket {
+                let res = wait_mutex(mutex, millis_until(deadline));
+                let abandoned = match res {
+                    Ok(abandoned) => abandoned,
+                    Err(e) => {
+                        self.advance(ticket);
+                        return Err(e);
+                    }
+                };
+
+                if self.value(SERVING).load(Ordering::SeqCst) == ticket {
+                    self.held.store(ticket, Ordering::SeqCst);
+                    return Ok(self.take_abandoned(abandoned));
+                }
+
+                // The turn was skipped before the mutex was taken.
+                self.keep_abandoned(abandoned);
+                release_mutex(mutex)?;
+                ticket = self.draw();
+                continue;
+            }
+
+            if serving > ticket {
+                ticket = self.draw();
+                continue;
+            }
+
+            if self.is_stalled() && self.skip(mutex, serving)? {
+                continue;
+            }
+
+            let left = millis_until(deadline);
+
+            if left == 0 {
+                // The ticket is skipped by the other waiters later.
+                return Err(Error::WouldBlock);
+            }
+
+            let rc = unsafe {
+                WaitForSingleObject(self.event, left.min(TICKET_POLL_MILLIS))
+            };
+
+            if rc == WAIT_OBJECT_0 {
+                // Set for a turn that is over already. This misses a turn
+                // that moves on right now, which costs one poll.
+                if self.value(SERVING).load(Ordering::SeqCst) == serving {
+                    unsafe {
+                        let _ = ResetEvent(self.event);
+                    }
+                }
+            } else if rc != WAIT_TIMEOUT {
+                return Err(Error::LockFailed(
+                    Some(io::Error::last_os_error()),
+                ));
+            }
+        }
+    }
+
+    // Acquires the mutex only if nobody holds it or waits for it.
+    fn try_acquire(&self, mutex: HANDLE) -> Result<bool> {
+        let ticket = loop {
+            let serving = self.value(SERVING).load(Ordering::SeqCst);
+
+            if self
+                .value(NEXT)
+                .compare_exchange(
+                    serving,
+                    serving + 1,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                )
+                .is_ok()
+            {
+                break serving;
+            }
+
+            // Tickets that were left behind are skipped, the lock is held or
+            // waited for otherwise.
+            if !(self.is_stalled() && self.skip(mutex, serving)?) {
+                return Err(Error::WouldBlock);
+            }
+        };
+
+        self.value(SINCE).store(tick_count(), Ordering::SeqCst);
+
+        match wait_mutex(mutex, 0) {
+            Ok(abandoned) => {
+                self.held.store(ticket, Ordering::SeqCst);
+                Ok(self.take_abandoned(abandoned))
+            }
+            Err(e) => {
+                self.advance(ticket);
+                Err(e)
+            }
+        }
+    }
+
+    fn draw(&self) -> u64 {
+        let ticket = self.value(NEXT).fetch_add(1, Ordering::SeqCst);
+
+        // The turn of an idle lock starts right away.
+        if self.value(SERVING).load(Ordering::SeqCst) == ticket {
+            self.value(SINCE).store(tick_count(), Ordering::SeqCst);
+        }
+
+        ticket
+    }
+
+    fn release(&self) {
+        self.advance(self.held.load(Ordering::SeqCst));
+    }
+
+    // Moves the turn on from `ticket`, unless it has moved already.
+    fn advance(&self, ticket: u64) {
+        let moved = self
+            .value(SERVING)
+            .compare_exchange(
+                ticket,
+                ticket + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_ok();
+
+        if moved {
+            self.value(SINCE).store(tick_count(), Ordering::SeqCst);
+
+            unsafe {
+                let _ = SetEvent(self.event);
+            }
+        }
+    }
+
+    fn is_stalled(&self) -> bool {
+        let since = self.value(SINCE).load(Ordering::SeqCst);
+        tick_count().saturating_sub(since) >= TICKET_SKIP_MILLIS
+    }
+
+    // Skips the turn `serving` if the mutex is free. Returns `false` if the
+    // mutex is held, i.e. the turn is taken.
+    fn skip(&self, mutex: HANDLE, serving: u64) -> Result<bool> {
+        match wait_mutex(mutex, 0) {
+            Ok(abandoned) => self.keep_abandoned(abandoned),
+            Err(Error::WouldBlock) => return Ok(false),
+            Err(e) => return Err(e),
+        }
+
+        // Never past the last ticket, which nobody would take.
+        if serving < self.value(NEXT).load(Ordering::SeqCst) {
+            self.advance(serving);
+        }
+
+        release_mutex(mutex)?;
+
+        Ok(true)
+    }
+
+    // Records that the mutex was abandoned before it is released again
+    // without being held.
+    fn keep_abandoned(&self, abandoned: bool) {
+        if abandoned {
+            self.value(ABANDONED).store(1, Ordering::SeqCst);
+        }
+    }
+
+    // Returns if the mutex that was just acquired was abandoned, either now
+    // or before a waiter released it again.
+    fn take_abandoned(&self, abandoned: bool) -> bool {
+        let kept = self.value(ABANDONED).swap(0, Ordering::SeqCst) != 0;
+        abandoned || kept
+    }
+}
+
+impl Drop for TicketQueue {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseHandle(self.event);
+        }
+    }
+}
+
+fn tick_count() -> u64 {
+    unsafe { GetTickCount64() }
+}
+
+fn millis_until(deadline: Option<Instant>) -> u32 {
+    match deadline {
+        Some(deadline) => deadline
+            .saturating_duration_since(Instant::now())
+            .as_millis()
+            .min(u128::from(INFINITE - 1)) as u32,
+        None => INFINITE,
+    }
+}
+
+// Access rights that everyone gets on a cross-session mutex: waiting on it
+// (`SYNCHRONIZE`) and releasing it (`MUTEX_MODIFY_STATE`). Only the creator
+// can change the DACL.
+const CROSS_SESSION_SDDL: windows::core::PCWSTR = w!("D:(A;;0x100001;;;WD)");
+
+fn check_name_len(name: &str) -> Result<()> {
+    if name.encode_utf16().count() > MAX_NAME_LEN {
+        return Err(Error::NameTooLong);
+    }
+
+    Ok(())
+}
+
+fn create_cross_session(name: &str) -> Result<HANDLE> {
+    let mut descriptor = PSECURITY_DESCRIPTOR::default();
+
+    unsafe {
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            CROSS_SESSION_SDDL,
+            SDDL_REVISION_1,
+            &mut descriptor,
+            None,
+        )
+        .map_err(|e| Error::CreateFailed(io::Error::from(e)))?;
+    }
+
+    let attributes = SECURITY_ATTRIBUTES {
+        nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+        lpSecurityDescriptor: descriptor.0,
+        bInheritHandle: false.into(),
+    };
+
+    let res = unsafe {
+        CreateMutexW(Some(&attributes), false, &HSTRING::from(name))
+            .map_err(|e| Error::CreateFailed(io::Error::from(e)))
+    };
+
+    unsafe {
+        let _ = LocalFree(HLOCAL(descriptor.0 as _));
     }
+
+    res
+}
+
+// Returns the SID of the user of the process token, e.g. `S-1-5-21-...`.
+pub(crate) fn current_user_sid() -> io::Result<String> {
+    let mut token = HANDLE::default();
+    unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token)? };
+
+    let res = token_user_sid(token);
+
+    unsafe {
+        let _ = CloseHandle(token);
+    }
+
+    res
+}
+
+fn token_user_sid(token: HANDLE) -> io::Result<String> {
+    // The first call fails with `ERROR_INSUFFICIENT_BUFFER` and reports the
+    // size that is needed.
+    let mut len = 0;
+    let _ = unsafe { GetTokenInformation(token, TokenUser, None, 0, &mut len) };
+
+    // `u64` elements keep the buffer aligned for `TOKEN_USER`.
+    let mut buf = vec![0u64; (len as usize).div_ceil(8)];
+
+    unsafe {
+        GetTokenInformation(
+            token,
+            TokenUser,
+            Some(buf.as_mut_ptr().cast()),
+            len,
+            &mut len,
+        )?;
+    }
+
+    let user = unsafe { &*buf.as_ptr().cast::<TOKEN_USER>() };
+    let mut sid = PWSTR::null();
+    unsafe { ConvertSidToStringSidW(user.User.Sid, &mut sid)? };
+
+    let res = unsafe { sid.to_string() }
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+
+    unsafe {
+        let _ = LocalFree(HLOCAL(sid.0 as _));
+    }
+
+    res
 }
 
 impl Drop for RawNamedLock {
@@ -64,3 +585,96 @@ impl Drop for RawNamedLock {
         }
     }
 }
+
+// `u64`s in named shared memory, which hold the value of a `NamedCounter`
+// or the state of a ticket lock. The memory is zeroed when it is created and
+// exists for as long as a process has it open.
+#[derive(Debug)]
+pub(crate) struct SharedValues {
+    mapping: HANDLE,
+    view: MEMORY_MAPPED_VIEW_ADDRESS,
+    len: usize,
+}
+
+unsafe impl Sync for SharedValues {}
+unsafe impl Send for SharedValues {}
+
+impl SharedValues {
+    pub(crate) fn create(name: &str, len: usize) -> Result<SharedValues> {
+        check_name_len(name)?;
+
+        let size = len * std::mem::size_of::<u64>();
+        let mapping = unsafe {
+            CreateFileMappingW(
+                INVALID_HANDLE_VALUE,
+                None,
+                PAGE_READWRITE,
+                0,
+                size as u32,
+                &HSTRING::from(name),
+            )
+            .map_err(|e| Error::CreateFailed(io::Error::from(e)))?
+        };
+
+        let view =
+            unsafe { MapViewOfFile(mapping, FILE_MAP_ALL_ACCESS, 0, 0, size) };
+
+        if view.Value.is_null() {
+            let err = io::Error::last_os_error();
+
+            unsafe {
+                let _ = CloseHandle(mapping);
+            }
+
+            return Err(Error::CreateFailed(err));
+        }
+
+        Ok(SharedValues {
+            mapping,
+            view,
+            len,
+        })
+    }
+
+    // Views are aligned to the allocation granularity, so the values are
+    // always aligned.
+    pub(crate) fn get(&self, index: usize) -> &AtomicU64 {
+        assert!(index < self.len);
+        unsafe { &*self.view.Value.cast::<AtomicU64>().add(index) }
+    }
+}
+
+impl Drop for SharedValues {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = UnmapViewOfFile(self.view);
+            let _ = CloseHandle(self.mapping);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use windows::Win32::Foundation::ERROR_INVALID_HANDLE;
+
+    #[test]
+    fn wait_failed() {
+        let raw = RawNamedLock {
+            handle: HANDLE::default(),
+            name: String::new(),
+            owner: Mutex::new(None),
+            tickets: None,
+        };
+
+        match raw.try_lock() {
+            Err(Error::LockFailed(Some(e))) => {
+                assert_eq!(
+                    e.raw_os_error(),
+                    Some(ERROR_INVALID_HANDLE.0 as i32)
+                );
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}